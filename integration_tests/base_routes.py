@@ -1,4 +1,7 @@
+import asyncio
+import json
 import os
+import threading
 
 import pathlib
 from collections import defaultdict
@@ -7,6 +10,36 @@ from typing import Optional
 from robyn import WS, Robyn, Request, Response, jsonify, serve_file, serve_html
 from robyn.authentication import AuthenticationHandler, BearerGetter, Identity
 from robyn.templating import JinjaTemplate
+from robyn.ws import (
+    AcceptConnection,
+    CloseConnection,
+    WebSocketRequest,
+    ack_status,
+    active_connection_count,
+    broadcast,
+    broadcast_sample,
+    broadcast_to_room,
+    broadcast_to_room_except,
+    broadcast_where,
+    broadcast_with_ack,
+    connection_metrics,
+    get_tags,
+    join_room,
+    kick,
+    leave_room,
+    list_connections,
+    pause,
+    queue_depth,
+    reconnect_token,
+    remove_tag,
+    resume,
+    room_member_count,
+    send_ping,
+    send_pong,
+    send_to,
+    set_tag,
+    set_verbose,
+)
 
 from integration_tests.views import SyncView, AsyncView
 from integration_tests.subroutes import sub_router
@@ -18,6 +51,210 @@ websocket = WS(app, "/web_socket")
 # while the original "raw" web_socket is used with benchmark tests
 websocket_json = WS(app, "/web_socket_json")
 
+# Used to check that a connection survives errors raised inside the handlers
+websocket_error = WS(app, "/web_socket_error", max_payload_size=1024)
+# the close reasons that the close handler received, by id
+error_close_reasons = {}
+
+# Only accepts clients that speak one of its subprotocols
+websocket_subprotocol = WS(
+    app,
+    "/web_socket_subprotocol",
+    subprotocols=["chat.v2", "chat.v1"],
+    subprotocol_required=True,
+)
+
+# Only accepts the handshakes from its own site
+websocket_origin = WS(
+    app,
+    "/web_socket_origin",
+    allowed_origins=["https://example.com", "https://*.example.com"],
+)
+
+# Answers the messages that the client sends again without running the message handler
+websocket_dedup = WS(app, "/web_socket_dedup", dedup_window=60)
+
+# Drops the messages whose whole content was received in the last 60 seconds
+websocket_dedup_content = WS(
+    app, "/web_socket_dedup_content", dedup_window=60, dedup_key=None
+)
+
+# Holds the messages of a connection between the "pause" and "resume <id>" commands
+websocket_pause = WS(app, "/web_socket_pause", mailbox_capacity=4)
+# the messages handled for each connection
+pause_handled_messages = {}
+
+# Pushes a tick to every connection until it is closed
+websocket_background = WS(app, "/web_socket_background")
+# the ids of the connections whose background task was cancelled
+background_cancelled = set()
+
+# The clients ack the messages of broadcast_with_ack in their "ack" field
+websocket_ack = WS(app, "/web_socket_ack", ack_field="ack")
+
+# Negotiates permessage-deflate, the answers below the default threshold of 1 KiB are sent
+# uncompressed
+websocket_compression = WS(app, "/web_socket_compression", compression=True)
+
+# Receives the text messages as parsed JSON
+websocket_json_mode = WS(app, "/web_socket_json_mode", json_mode=True)
+
+# Answers the invalid JSON messages with an envelope of its own
+websocket_json_envelope = WS(
+    app,
+    "/web_socket_json_envelope",
+    json_mode=True,
+    invalid_json_frame={"type": "error", "code": "{type}", "detail": "{message}"},
+)
+
+# Drops the invalid JSON messages without an answer
+websocket_json_silent = WS(
+    app, "/web_socket_json_silent", json_mode=True, invalid_json_reply=False
+)
+
+# The handlers receive a single WebSocketRequest
+websocket_request = WS(app, "/web_socket_request")
+# whether the connection was still open once a slow handler was done, by id
+request_open_after_wait = {}
+
+# Uses the handle of the connection in its connect handler
+websocket_connect_handle = WS(app, "/web_socket_connect_handle")
+refused_handles = []
+
+# Its close handler is async and reads the state of the connection
+websocket_async_close = WS(app, "/web_socket_async_close")
+async_close_calls = {}
+
+# The connect handler always raises
+websocket_connect_error = WS(app, "/web_socket_connect_error")
+
+# Closes the connections that are silent for a second
+websocket_idle = WS(app, "/web_socket_idle", idle_timeout=1)
+
+websocket_rate_limit = WS(
+    app,
+    "/web_socket_rate_limit",
+    rate_limit=1,
+    rate_limit_burst=2,
+    rate_limit_max_violations=2,
+)
+
+websocket_msgpack = WS(app, "/web_socket_msgpack", msgpack_mode=True)
+
+# Decodes and encodes the binary messages with CBOR
+websocket_cbor = WS(app, "/web_socket_cbor", cbor_mode=True)
+
+# The async handlers run on an event loop of their own
+websocket_dedicated_loop = WS(
+    app, "/web_socket_dedicated_loop", dedicated_event_loop=True
+)
+
+# The "ping" handler answers the pings itself
+websocket_manual_pong = WS(app, "/web_socket_manual_pong", auto_pong=False)
+
+# Answers the messages in the order they were received
+websocket_ordered = WS(app, "/web_socket_ordered", ordered=True)
+
+# The connect handler takes longer than the handshake timeout when asked to
+websocket_slow_connect = WS(app, "/web_socket_slow_connect", handshake_timeout=1)
+
+# Tells the client when a handler raises
+websocket_error_frame = WS(app, "/web_socket_error_frame", handler_error_frame=True)
+
+# Tells the handlers how deep the mailbox got
+websocket_high_water = WS(app, "/web_socket_high_water", high_water_mark=3)
+high_water_depths = {}
+high_water_drained = {}
+
+# Disconnects the clients that can't keep up with their frames
+websocket_slow_consumer = WS(
+    app, "/web_socket_slow_consumer", high_water_mark=10, slow_consumer_timeout=1
+)
+
+# Drops the frames sent to a connection that already has two waiting
+websocket_drop_newest = WS(
+    app, "/web_socket_drop_newest", mailbox_capacity=2, backpressure="drop_newest"
+)
+
+# Cancels the handlers that take longer than half a second
+websocket_handler_timeout = WS(app, "/web_socket_handler_timeout", handler_timeout=0.5)
+websocket_handler_timeout_close = WS(
+    app,
+    "/web_socket_handler_timeout_close",
+    handler_timeout=0.5,
+    handler_timeout_action="close",
+)
+cancelled_handlers = []
+
+# Sends its own heartbeats and expects the client to answer them
+websocket_app_heartbeat = WS(
+    app,
+    "/web_socket_app_heartbeat",
+    heartbeat_timeout=2,
+    app_heartbeat_interval=1,
+    app_heartbeat_pong={"type": "pong"},
+)
+
+# Sends its heartbeats less often than its heartbeat timeout
+websocket_slow_app_heartbeat = WS(
+    app,
+    "/web_socket_slow_app_heartbeat",
+    heartbeat_timeout=1,
+    app_heartbeat_interval=2,
+    app_heartbeat_pong={"type": "pong"},
+)
+
+# Pings less often than its heartbeat timeout
+websocket_slow_heartbeat = WS(
+    app, "/web_socket_slow_heartbeat", heartbeat_interval=2, heartbeat_timeout=1
+)
+
+# Each of them leaves out one of the handlers that routes usually have
+websocket_no_connect = WS(app, "/web_socket_no_connect")
+websocket_no_message = WS(app, "/web_socket_no_message")
+websocket_no_message_raw_text = WS(
+    app, "/web_socket_no_message_raw_text", raw_text=True
+)
+websocket_no_close = WS(app, "/web_socket_no_close")
+
+# The connections are tagged with the plan of the client
+websocket_tags = WS(app, "/web_socket_tags")
+
+# The connections use the ids of the application
+websocket_client_id = WS(
+    app, "/web_socket_client_id", id_header="X-Client-Id", unique_ids=True
+)
+
+# Keeps track of the upgrades that it refused
+websocket_upgrade_failed = WS(
+    app, "/web_socket_upgrade_failed", subprotocols=["chat"], subprotocol_required=True
+)
+upgrade_failures = []
+
+# Sends its queued frames before it closes
+websocket_drain = WS(app, "/web_socket_drain")
+
+# The handlers go through a handle on the connection
+websocket_handle = WS(app, "/web_socket_handle")
+
+# Dropped clients can come back with their state
+websocket_reconnect = WS(app, "/web_socket_reconnect", reconnect_ttl=5)
+
+# A single handler receives every event
+websocket_unified = WS(app, "/web_socket_unified")
+
+# The messages go through a chain of handlers
+websocket_chain = WS(app, "/web_socket_chain")
+
+# Receives the text messages as bytes
+websocket_raw_text = WS(app, "/web_socket_raw_text", raw_text=True)
+
+# The room is a path param
+websocket_room = WS(app, "/web_socket_room/:room")
+
+# The message handler is an async generator
+websocket_stream = WS(app, "/web_socket_stream")
+
 current_file_path = pathlib.Path(__file__).parent.resolve()
 jinja_template = JinjaTemplate(os.path.join(current_file_path, "templates"))
 
@@ -57,11 +294,21 @@ async def message(websocket_id: str, msg: str) -> str:
     return resp
 
 
+@websocket.on("binary")
+def binary(websocket_id: str, msg: bytes) -> str:
+    return f"Received {len(msg)} bytes"
+
+
 @websocket.on("close")
 def close():
     return "GoodBye world, from ws"
 
 
+@websocket_json.on("binary")
+async def jsonws_binary(websocket_id: str, msg: bytes) -> bytes:
+    return msg[::-1]
+
+
 @websocket_json.on("close")
 def jsonws_close():
     return "GoodBye world, from ws"
@@ -77,6 +324,952 @@ def jsonws_connect():
     return "Hello world, from ws"
 
 
+@websocket_error.on("message")
+async def error_ws_message(
+    websocket_id: str,
+    msg: str,
+    query_params: dict,
+    headers: dict,
+    state: dict,
+    remote_ip: Optional[str],
+    cookies: dict,
+) -> str:
+    if msg == "raise":
+        raise Exception("Raised inside an async web socket handler")
+    if msg == "id":
+        return websocket_id
+    if msg == "query":
+        return jsonify(query_params)
+    if msg == "headers":
+        return jsonify(headers)
+    if msg == "count":
+        state["count"] = state.get("count", 0) + 1
+        return str(state["count"])
+    if msg.startswith("send_to "):
+        send_to(msg.split(" ", 1)[1], f"Message from {websocket_id}")
+        return None
+    if msg.startswith("join "):
+        join_room(websocket_id, msg.split(" ", 1)[1])
+        return None
+    if msg.startswith("leave "):
+        leave_room(websocket_id, msg.split(" ", 1)[1])
+        return None
+    if msg.startswith("kick "):
+        return str(kick(msg.split(" ", 1)[1], 4003, "Kicked"))
+    if msg.startswith("kick_code "):
+        target, code = msg.split(" ", 2)[1:]
+        try:
+            return str(kick(target, int(code)))
+        except ValueError as e:
+            return type(e).__name__
+    if msg.startswith("kick_reason "):
+        target, length = msg.split(" ", 2)[1:]
+        try:
+            return str(kick(target, 1000, "x" * int(length)))
+        except ValueError as e:
+            return type(e).__name__
+    if msg.startswith("members "):
+        return str(room_member_count(msg.split(" ", 1)[1]))
+    if msg.startswith("room "):
+        room, text = msg.split(" ", 2)[1:]
+        return jsonify(broadcast_to_room(room, text))
+    if msg.startswith("relay "):
+        room, text = msg.split(" ", 2)[1:]
+        return jsonify(broadcast_to_room_except(room, websocket_id, text))
+    if msg.startswith("sample "):
+        room, sample, text = msg.split(" ", 3)[1:]
+        try:
+            return jsonify(
+                broadcast_sample(room, float(sample) if "." in sample else int(sample), text)
+            )
+        except ValueError as e:
+            return type(e).__name__
+    if msg == "close":
+        return CloseConnection(1000, "Closed by the handler")
+    if msg.startswith("close_with "):
+        _, code, reason = msg.split(" ", 2)
+        return CloseConnection(int(code), reason)
+    if msg.startswith("close_reason "):
+        return jsonify(error_close_reasons.get(msg.split(" ", 1)[1]))
+    if msg == "id":
+        return websocket_id
+    if msg == "ip":
+        return remote_ip
+    if msg == "cookies":
+        return jsonify(cookies)
+    if msg == "connections":
+        return str(active_connection_count())
+    if msg == "metrics":
+        return jsonify(connection_metrics(websocket_id))
+    if msg == "queue":
+        return str(queue_depth(websocket_id))
+    if msg.startswith("verbose "):
+        set_verbose(websocket_id, msg.split(" ", 1)[1] == "on")
+        return msg
+    if msg.startswith("ping "):
+        try:
+            send_ping(websocket_id, msg.split(" ", 1)[1].encode())
+        except ValueError as e:
+            return str(e)
+        return None
+    if msg == "many":
+        return ["one", b"two", "three"]
+    if msg == "dict":
+        return {"type": "update", "values": [1, 2]}
+    if msg == "broadcast":
+        broadcast("Broadcast from ws")
+        return None
+    if msg == "broadcast bytes":
+        broadcast(b"Broadcast from ws")
+        return None
+    return msg
+
+
+@websocket_error.on("binary")
+def error_ws_binary(websocket_id: str, msg: bytes) -> bytes:
+    if msg == b"raise":
+        raise Exception("Raised inside a sync web socket handler")
+    if msg == b"dict":
+        return {"type": "binary"}
+    if msg == b"object":
+        # can't be serialized to JSON
+        return object()
+    return msg
+
+
+@websocket_error.on("pong")
+def error_ws_pong(websocket_id: str, msg: bytes) -> str:
+    return f"Pong {msg.decode()}"
+
+
+@websocket_error.on("error")
+def error_ws_error(websocket_id: str, msg: str) -> str:
+    return f"Protocol error: {msg}"
+
+
+@websocket_error.on("close")
+def error_ws_close(websocket_id: str, msg: str):
+    error_close_reasons[websocket_id] = json.loads(msg)
+    return "GoodBye world, from ws"
+
+
+@websocket_error.on("connect")
+def error_ws_connect(websocket_id: str, msg: str, query_params: dict):
+    if query_params.get("reject"):
+        return False
+    if query_params.get("close"):
+        return CloseConnection(4001, "Refused by the connect handler")
+    return "Hello world, from ws"
+
+
+@websocket_subprotocol.on("message")
+def subprotocol_ws_message(ws: WebSocketRequest) -> str:
+    if ws.message == "subprotocol":
+        return ws.subprotocol
+    return ws.message
+
+
+@websocket_subprotocol.on("close")
+def subprotocol_ws_close():
+    return "GoodBye world, from ws"
+
+
+@websocket_subprotocol.on("connect")
+def subprotocol_ws_connect(websocket_id: str, msg: str, query_params: dict):
+    # overrides the subprotocol picked from the ones of the route
+    if "subprotocol" in query_params:
+        return AcceptConnection(query_params["subprotocol"], "Hello world, from ws")
+    return "Hello world, from ws"
+
+
+@websocket_dedup.on("message")
+def dedup_ws_message(msg: str) -> str:
+    return f"handled {msg}"
+
+
+@websocket_dedup.on("duplicate")
+def dedup_ws_duplicate(websocket_id: str, msg: str) -> str:
+    return f"duplicate {msg}"
+
+
+@websocket_dedup_content.on("message")
+def dedup_content_ws_message(msg: str) -> str:
+    return f"handled {msg}"
+
+
+@websocket_pause.on("message")
+def pause_ws_message(websocket_id: str, msg: str) -> str:
+    if msg == "pause":
+        pause(websocket_id)
+        return websocket_id
+    if msg.startswith("resume "):
+        id = msg.split(" ", 1)[1]
+        handled = list(pause_handled_messages.get(id, []))
+        return json.dumps({"resumed": resume(id), "handled": handled})
+    pause_handled_messages.setdefault(websocket_id, []).append(msg)
+    return msg
+
+
+async def background_ticks(websocket_id: str):
+    try:
+        tick = 0
+        while True:
+            send_to(websocket_id, f"tick {tick}")
+            tick += 1
+            await asyncio.sleep(0.05)
+    except asyncio.CancelledError:
+        background_cancelled.add(websocket_id)
+        raise
+
+
+@websocket_background.on("connect")
+def background_ws_connect(websocket_id: str) -> AcceptConnection:
+    return AcceptConnection(
+        message=websocket_id, background=background_ticks(websocket_id)
+    )
+
+
+@websocket_background.on("message")
+def background_ws_message(msg: str) -> str:
+    # "cancelled <id>" tells whether the task of the connection was cancelled
+    return str(msg.split(" ", 1)[1] in background_cancelled)
+
+
+@websocket_compression.on("message")
+def compression_ws_message(websocket_id: str, msg: str) -> str:
+    if msg == "metrics":
+        return jsonify(connection_metrics(websocket_id))
+    return msg
+
+
+@websocket_ack.on("connect")
+def ack_ws_connect(websocket_id: str, msg: str, query_params: dict) -> str:
+    if "room" in query_params:
+        join_room(websocket_id, query_params["room"])
+    return websocket_id
+
+
+@websocket_ack.on("message")
+def ack_ws_message(websocket_id: str, msg: str) -> str:
+    if msg == "id":
+        return websocket_id
+    if msg.startswith("broadcast "):
+        room, timeout = msg.split(" ", 2)[1:]
+        return broadcast_with_ack({"text": "alert"}, float(timeout), room)
+    if msg.startswith("status "):
+        return jsonify(ack_status(msg.split(" ", 1)[1]))
+    return msg
+
+
+@websocket_origin.on("connect")
+def origin_ws_connect():
+    return "Hello world, from ws"
+
+
+@websocket_origin.on("message")
+def origin_ws_message(msg: str) -> str:
+    return msg
+
+
+@websocket_json_mode.on("message")
+def json_mode_ws_message(websocket_id: str, msg) -> str:
+    return jsonify({"type": type(msg).__name__, "msg": msg})
+
+
+@websocket_json_envelope.on("message")
+def json_envelope_ws_message(msg) -> str:
+    return jsonify(msg)
+
+
+@websocket_json_silent.on("message")
+def json_silent_ws_message(msg) -> str:
+    return jsonify(msg)
+
+
+@websocket_json_mode.on("close")
+def json_mode_ws_close():
+    return "GoodBye world, from ws"
+
+
+@websocket_json_mode.on("connect")
+def json_mode_ws_connect():
+    return "Hello world, from ws"
+
+
+@websocket_request.on("message")
+async def request_ws_message(ws: WebSocketRequest) -> str:
+    if ws.message == "wait":
+        await asyncio.sleep(0.5)
+        request_open_after_wait[ws.id] = ws.is_open()
+        return "done"
+    if ws.message.startswith("was_open "):
+        return jsonify(request_open_after_wait.get(ws.message.split(" ", 1)[1]))
+    ws.state["messages"] = ws.state.get("messages", 0) + 1
+    return jsonify(
+        {
+            "id": ws.id,
+            "message": ws.message,
+            "query_params": ws.query_params,
+            "messages": ws.state["messages"],
+            "open": ws.is_open(),
+            "connected_at": ws.connected_at,
+            "route": ws.route,
+            "queue_depth": ws.queue_depth,
+            "extensions": ws.extensions,
+        }
+    )
+
+
+@websocket_request.on("close")
+def request_ws_close(ws: WebSocketRequest):
+    return "GoodBye world, from ws"
+
+
+@websocket_async_close.on("message")
+def async_close_ws_message(ws: WebSocketRequest):
+    if ws.message == "id":
+        return ws.id
+    if ws.message == "close":
+        return CloseConnection(4003, "Closed by the handler")
+    if ws.message.startswith("name "):
+        ws.state["name"] = ws.message.split(" ", 1)[1]
+        return "ok"
+    if ws.message.startswith("closed "):
+        return jsonify(async_close_calls.get(ws.message.split(" ", 1)[1]))
+
+
+@websocket_async_close.on("close")
+async def async_close_ws_close(ws: WebSocketRequest):
+    await asyncio.sleep(0.2)
+    async_close_calls[ws.id] = {
+        "code": json.loads(ws.message)["code"],
+        "name": ws.state.get("name"),
+    }
+
+
+@websocket_request.on("connect")
+def request_ws_connect(ws: WebSocketRequest):
+    return ws.id
+
+
+@websocket_connect_handle.on("connect")
+def connect_handle_ws_connect(ws: WebSocketRequest):
+    connection = ws.connection()
+    if ws.query_params.get("refuse"):
+        refused_handles.append(connection)
+        return False
+    connection.send("Sent from the connect handler")
+    return str(connection.is_alive())
+
+
+@websocket_connect_handle.on("message")
+def connect_handle_ws_message(ws: WebSocketRequest):
+    if ws.message == "refused":
+        handle = refused_handles[-1]
+        return jsonify({"alive": handle.is_alive(), "sent": handle.send("hello")})
+    return ws.message
+
+
+@websocket_connect_error.on("message")
+def connect_error_ws_message():
+    return "Unreachable"
+
+
+@websocket_connect_error.on("close")
+def connect_error_ws_close():
+    return "GoodBye world, from ws"
+
+
+@websocket_connect_error.on("connect")
+def connect_error_ws_connect():
+    raise Exception("Raised inside a web socket connect handler")
+
+
+@websocket_idle.on("message")
+def idle_ws_message(websocket_id: str, msg: str) -> str:
+    return msg
+
+
+@websocket_idle.on("before_close")
+async def idle_ws_before_close(websocket_id: str, msg: str) -> str:
+    await asyncio.sleep(0.01)
+    return f"Closing with {json.loads(msg)['code']}"
+
+
+@websocket_idle.on("close")
+def idle_ws_close():
+    return "GoodBye world, from ws"
+
+
+@websocket_idle.on("connect")
+def idle_ws_connect():
+    return "Hello world, from ws"
+
+
+@websocket_rate_limit.on("message")
+def rate_limit_ws_message(websocket_id: str, msg: str) -> str:
+    return msg
+
+
+@websocket_rate_limit.on("close")
+def rate_limit_ws_close():
+    return "GoodBye world, from ws"
+
+
+@websocket_rate_limit.on("connect")
+def rate_limit_ws_connect():
+    return "Hello world, from ws"
+
+
+@websocket_msgpack.on("message")
+def msgpack_ws_message(websocket_id: str, msg: str) -> str:
+    return msg
+
+
+@websocket_msgpack.on("binary")
+async def msgpack_ws_binary(websocket_id: str, msg: dict) -> dict:
+    return {"received": msg}
+
+
+@websocket_msgpack.on("error")
+def msgpack_ws_error(websocket_id: str, msg: str) -> str:
+    return msg
+
+
+@websocket_msgpack.on("close")
+def msgpack_ws_close():
+    return "GoodBye world, from ws"
+
+
+@websocket_msgpack.on("connect")
+def msgpack_ws_connect():
+    return "Hello world, from ws"
+
+
+@websocket_cbor.on("binary")
+def cbor_ws_binary(websocket_id: str, msg: dict) -> dict:
+    return {"received": msg}
+
+
+@websocket_cbor.on("error")
+def cbor_ws_error(websocket_id: str, msg: str) -> str:
+    return msg
+
+
+@websocket_cbor.on("close")
+def cbor_ws_close():
+    return "GoodBye world, from ws"
+
+
+@websocket_cbor.on("connect")
+def cbor_ws_connect():
+    return "Hello world, from ws"
+
+
+@websocket_dedicated_loop.on("message")
+async def dedicated_loop_ws_message(websocket_id: str, msg: str) -> str:
+    if threading.current_thread() is threading.main_thread():
+        return f"{msg} on the main thread"
+    return f"{msg} on a dedicated thread"
+
+
+@websocket_dedicated_loop.on("close")
+def dedicated_loop_ws_close():
+    return "GoodBye world, from ws"
+
+
+@websocket_dedicated_loop.on("connect")
+def dedicated_loop_ws_connect():
+    return "Hello world, from ws"
+
+
+@websocket_manual_pong.on("message")
+def manual_pong_ws_message(websocket_id: str, msg: str) -> str:
+    return msg
+
+
+@websocket_manual_pong.on("ping")
+def manual_pong_ws_ping(websocket_id: str, msg: bytes):
+    send_pong(websocket_id, b"manual " + msg)
+
+
+@websocket_manual_pong.on("close")
+def manual_pong_ws_close():
+    return "GoodBye world, from ws"
+
+
+@websocket_manual_pong.on("connect")
+def manual_pong_ws_connect():
+    return "Hello world, from ws"
+
+
+@websocket_ordered.on("message")
+async def ordered_ws_message(websocket_id: str, msg: str) -> str:
+    # the message is the number of seconds to wait before answering
+    await asyncio.sleep(float(msg))
+    return msg
+
+
+@websocket_ordered.on("close")
+def ordered_ws_close():
+    return "GoodBye world, from ws"
+
+
+@websocket_ordered.on("connect")
+def ordered_ws_connect():
+    return "Hello world, from ws"
+
+
+@websocket_drop_newest.on("message")
+def drop_newest_ws_message(websocket_id: str, msg: str) -> str:
+    # the mailbox can't drain while the handler runs
+    return jsonify([send_to(websocket_id, str(i)) for i in range(int(msg))])
+
+
+@websocket_drop_newest.on("close")
+def drop_newest_ws_close():
+    return "GoodBye world, from ws"
+
+
+@websocket_drop_newest.on("connect")
+def drop_newest_ws_connect():
+    return "Hello world, from ws"
+
+
+async def sleep_then_answer(msg: str) -> str:
+    if msg == "cancelled":
+        return str(len(cancelled_handlers))
+    try:
+        # the message is the number of seconds to wait before answering
+        await asyncio.sleep(float(msg))
+    except asyncio.CancelledError:
+        cancelled_handlers.append(msg)
+        raise
+    return msg
+
+
+@websocket_handler_timeout.on("message")
+async def handler_timeout_ws_message(websocket_id: str, msg: str) -> str:
+    return await sleep_then_answer(msg)
+
+
+@websocket_handler_timeout.on("close")
+def handler_timeout_ws_close():
+    return "GoodBye world, from ws"
+
+
+@websocket_handler_timeout.on("connect")
+def handler_timeout_ws_connect():
+    return "Hello world, from ws"
+
+
+@websocket_handler_timeout_close.on("message")
+async def handler_timeout_close_ws_message(websocket_id: str, msg: str) -> str:
+    return await sleep_then_answer(msg)
+
+
+@websocket_handler_timeout_close.on("close")
+def handler_timeout_close_ws_close():
+    return "GoodBye world, from ws"
+
+
+@websocket_handler_timeout_close.on("connect")
+def handler_timeout_close_ws_connect():
+    return "Hello world, from ws"
+
+
+@websocket_app_heartbeat.on("message")
+def app_heartbeat_ws_message(websocket_id: str, msg: str) -> str:
+    return msg
+
+
+@websocket_app_heartbeat.on("close")
+def app_heartbeat_ws_close():
+    return "GoodBye world, from ws"
+
+
+@websocket_app_heartbeat.on("connect")
+def app_heartbeat_ws_connect():
+    return "Hello world, from ws"
+
+
+@websocket_slow_app_heartbeat.on("message")
+def slow_app_heartbeat_ws_message(websocket_id: str, msg: str) -> str:
+    return msg
+
+
+@websocket_slow_app_heartbeat.on("connect")
+def slow_app_heartbeat_ws_connect():
+    return "Hello world, from ws"
+
+
+@websocket_slow_heartbeat.on("message")
+def slow_heartbeat_ws_message(websocket_id: str, msg: str) -> str:
+    return msg
+
+
+@websocket_slow_heartbeat.on("connect")
+def slow_heartbeat_ws_connect():
+    return "Hello world, from ws"
+
+
+@websocket_no_connect.on("message")
+def no_connect_ws_message(websocket_id: str, msg: str) -> str:
+    return msg
+
+
+@websocket_no_connect.on("close")
+def no_connect_ws_close():
+    return "GoodBye world, from ws"
+
+
+@websocket_no_message.on("connect")
+@websocket_no_message_raw_text.on("connect")
+def no_message_ws_connect():
+    return "Hello world, from ws"
+
+
+@websocket_no_message.on("close")
+@websocket_no_message_raw_text.on("close")
+def no_message_ws_close():
+    return "GoodBye world, from ws"
+
+
+@websocket_no_close.on("connect")
+def no_close_ws_connect():
+    return "Hello world, from ws"
+
+
+@websocket_no_close.on("message")
+def no_close_ws_message(websocket_id: str, msg: str) -> str:
+    return msg
+
+
+@websocket_tags.on("connect")
+def tags_ws_connect(websocket_id: str, msg: str, query_params: dict):
+    set_tag(websocket_id, "plan", query_params.get("plan", "free"))
+    set_tag(websocket_id, "region", "us")
+    return "Hello world, from ws"
+
+
+@websocket_tags.on("message")
+def tags_ws_message(websocket_id: str, msg: str):
+    if msg == "id":
+        return websocket_id
+    if msg.startswith("join "):
+        join_room(websocket_id, msg.split(" ", 1)[1])
+        return None
+    if msg == "tags":
+        return jsonify(get_tags(websocket_id))
+    if msg == "untag":
+        remove_tag(websocket_id, "plan")
+        return None
+    if msg.startswith("broadcast "):
+        plan, text = msg.split(" ", 2)[1:]
+        return jsonify(broadcast_where("plan", plan, text))
+    return msg
+
+
+@websocket_tags.on("close")
+def tags_ws_close():
+    return "GoodBye world, from ws"
+
+
+# the lifecycle events of the web socket connections, by id
+lifecycle_events = {}
+
+
+@app.web_socket_lifecycle_handler
+def web_socket_lifecycle(event: dict):
+    lifecycle_events.setdefault(event["id"], []).append(event)
+
+
+@app.get("/web_socket_lifecycle/:websocket_id")
+def web_socket_lifecycle_events(request: Request):
+    return jsonify(lifecycle_events.get(request.path_params["websocket_id"], []))
+
+
+@app.get("/web_socket_connections")
+def web_socket_connections(request: Request):
+    limit = request.queries.get("limit")
+    return jsonify(
+        list_connections(
+            int(limit) if limit else None,
+            int(request.queries.get("offset", "0")),
+            "details" in request.queries,
+        )
+    )
+
+
+@websocket_client_id.on("connect")
+def client_id_ws_connect(websocket_id: str, msg: str, query_params: dict):
+    if "user" in query_params:
+        return AcceptConnection(message="Hello world, from ws", id=query_params["user"])
+    if "header" in query_params:
+        # "name:value" is added to the handshake response
+        name, value = query_params["header"].split(":", 1)
+        return AcceptConnection(message="Hello world, from ws", headers={name: value})
+    return "Hello world, from ws"
+
+
+@websocket_client_id.on("message")
+def client_id_ws_message(websocket_id: str, msg: str):
+    if msg == "id":
+        return websocket_id
+    if msg.startswith("send_to "):
+        recipient, text = msg.split(" ", 2)[1:]
+        return str(send_to(recipient, text))
+    if msg.startswith("kick "):
+        return str(kick(msg.split(" ", 1)[1]))
+    return msg
+
+
+@websocket_client_id.on("close")
+def client_id_ws_close():
+    return "GoodBye world, from ws"
+
+
+@websocket_upgrade_failed.on("connect")
+def upgrade_failed_ws_connect(websocket_id: str, msg: str, query_params: dict):
+    return "reject" not in query_params
+
+
+@websocket_upgrade_failed.on("upgrade_failed")
+def upgrade_failed_ws_upgrade_failed(reason: str, request: dict):
+    upgrade_failures.append(
+        {
+            "reason": reason,
+            "status": request["status"],
+            "path": request["path"],
+            "query_params": request["query_params"],
+        }
+    )
+
+
+@app.get("/web_socket_upgrade_failures")
+def web_socket_upgrade_failures():
+    return jsonify(upgrade_failures)
+
+
+@websocket_drain.on("message")
+def drain_ws_message(websocket_id: str, msg: str):
+    # the frames wait in the mailbox while the handler returns the close
+    for i in range(int(msg)):
+        send_to(websocket_id, f"frame {i}")
+    return CloseConnection(1000, "Drained")
+
+
+@websocket_handle.on("connect")
+def handle_ws_connect(ws: WebSocketRequest):
+    return str(ws.connection())
+
+
+@websocket_handle.on("message")
+def handle_ws_message(ws: WebSocketRequest):
+    connection = ws.connection()
+    if ws.message == "send":
+        connection.send("Sent through the handle")
+    elif ws.message == "ping":
+        connection.ping(b"handle")
+    elif ws.message == "close":
+        connection.close(4000, "Closed through the handle")
+    else:
+        return str(connection.is_alive())
+
+
+@websocket_reconnect.on("connect")
+def reconnect_ws_connect(ws: WebSocketRequest):
+    return not ws.query_params.get("reject")
+
+
+@websocket_reconnect.on("message")
+def reconnect_ws_message(ws: WebSocketRequest) -> Optional[str]:
+    if ws.message == "token":
+        return reconnect_token(ws.id)
+    if ws.message == "join":
+        join_room(ws.id, "reconnect")
+        ws.state["joined"] = True
+        return None
+    return jsonify(
+        {
+            "state": ws.state,
+            "members": room_member_count("reconnect"),
+        }
+    )
+
+
+@websocket_unified.on("all")
+def unified_ws_event(event_type: str, websocket_id: str, msg) -> Optional[str]:
+    if event_type == "connect":
+        return "Hello world, from ws"
+    if event_type == "binary":
+        return f"binary of {len(msg)} bytes"
+    if event_type == "text":
+        return f"text {msg}"
+    return None
+
+
+@websocket_chain.on("message")
+def chain_ws_check(websocket_id: str, msg: str) -> Optional[str]:
+    # ends the chain
+    if msg == "forbidden":
+        return "Forbidden"
+    return None
+
+
+@websocket_chain.on("message")
+async def chain_ws_count(websocket_id, msg, query_params, headers, state):
+    state["messages"] = state.get("messages", 0) + 1
+
+
+@websocket_chain.on("message")
+def chain_ws_message(ws: WebSocketRequest) -> str:
+    return f"{ws.message} after {ws.state['messages']} messages"
+
+
+@websocket_chain.on("close")
+def chain_ws_close():
+    return "GoodBye world, from ws"
+
+
+@websocket_chain.on("connect")
+def chain_ws_connect():
+    return "Hello world, from ws"
+
+
+@websocket_high_water.on("message")
+def high_water_ws_message(websocket_id: str, msg: str) -> Optional[str]:
+    if msg == "depth":
+        return str(high_water_depths.get(websocket_id))
+    if msg == "drained":
+        return str(high_water_drained.get(websocket_id, 0))
+    # the mailbox can't drain while the handler runs
+    for i in range(int(msg)):
+        send_to(websocket_id, str(i))
+    return None
+
+
+@websocket_slow_consumer.on("message")
+def slow_consumer_ws_message(websocket_id: str, msg: str):
+    # enough frames to fill the socket buffers of a client that doesn't read
+    for _ in range(int(msg)):
+        send_to(websocket_id, "x" * 65_536)
+
+
+@websocket_high_water.on("backpressure")
+def high_water_ws_backpressure(websocket_id: str, depth: int):
+    high_water_depths[websocket_id] = depth
+
+
+@websocket_high_water.on("drained")
+def high_water_ws_drained(websocket_id: str):
+    high_water_drained[websocket_id] = high_water_drained.get(websocket_id, 0) + 1
+
+
+@websocket_high_water.on("close")
+def high_water_ws_close():
+    return "GoodBye world, from ws"
+
+
+@websocket_high_water.on("connect")
+def high_water_ws_connect():
+    return "Hello world, from ws"
+
+
+@websocket_slow_connect.on("message")
+def slow_connect_ws_message(
+    websocket_id: str, msg: str, query_params: dict, headers: dict, state: dict
+) -> str:
+    if msg == "ready":
+        return str(state.get("ready"))
+    return msg
+
+
+@websocket_slow_connect.on("close")
+def slow_connect_ws_close():
+    return "GoodBye world, from ws"
+
+
+@websocket_slow_connect.on("connect")
+async def slow_connect_ws_connect(
+    websocket_id: str, msg: str, query_params: dict, headers: dict, state: dict
+):
+    await asyncio.sleep(float(query_params.get("delay", "0")))
+    state["ready"] = True
+    return "Hello world, from ws"
+
+
+@websocket_error_frame.on("message")
+async def error_frame_ws_message(websocket_id: str, msg: str) -> str:
+    if msg == "raise":
+        raise ValueError("Raised inside an async web socket handler")
+    return msg
+
+
+@websocket_error_frame.on("binary")
+def error_frame_ws_binary(websocket_id: str, msg: bytes) -> bytes:
+    raise KeyError("binary")
+
+
+@websocket_error_frame.on("close")
+def error_frame_ws_close():
+    return "GoodBye world, from ws"
+
+
+@websocket_error_frame.on("connect")
+def error_frame_ws_connect():
+    return "Hello world, from ws"
+
+
+@websocket_raw_text.on("message")
+def raw_text_ws_message(websocket_id: str, msg: bytes) -> str:
+    return f"{type(msg).__name__} {msg.hex()}"
+
+
+@websocket_raw_text.on("close")
+def raw_text_ws_close():
+    return "GoodBye world, from ws"
+
+
+@websocket_raw_text.on("connect")
+def raw_text_ws_connect():
+    return "Hello world, from ws"
+
+
+@websocket_stream.on("message")
+async def stream_ws_message(websocket_id: str, msg: str):
+    for i in range(int(msg)):
+        await asyncio.sleep(0.01)
+        yield f"Frame {i}"
+
+
+@websocket_stream.on("close")
+def stream_ws_close():
+    return "GoodBye world, from ws"
+
+
+@websocket_stream.on("connect")
+def stream_ws_connect():
+    return "Hello world, from ws"
+
+
+@websocket_room.on("message")
+def room_ws_message(ws: WebSocketRequest) -> str:
+    if ws.message == "route":
+        return ws.route
+    return f"{ws.message} in {ws.path_params['room']}"
+
+
+@websocket_room.on("close")
+def room_ws_close():
+    return "GoodBye world, from ws"
+
+
+@websocket_room.on("connect")
+def room_ws_connect(ws: WebSocketRequest):
+    return jsonify(ws.path_params)
+
+
 # ===== Lifecycle handlers =====
 
 
@@ -116,6 +116,34 @@ def test_session():
     kill_process(process)
 
 
+@pytest.fixture(scope="session")
+def web_socket_limit_session():
+    domain = "127.0.0.1"
+    port = 8082
+    os.environ["ROBYN_URL"] = domain
+    os.environ["ROBYN_PORT"] = str(port)
+    os.environ["ROBYN_MAX_WEB_SOCKET_CONNECTIONS"] = "1"
+    process = start_server(domain, port)
+    # the limit only applies to this server
+    del os.environ["ROBYN_MAX_WEB_SOCKET_CONNECTIONS"]
+    del os.environ["ROBYN_PORT"]
+    yield
+    kill_process(process)
+
+
+@pytest.fixture
+def web_socket_shutdown_session():
+    # a server of its own, as the test stops it
+    domain = "127.0.0.1"
+    port = 8083
+    os.environ["ROBYN_URL"] = domain
+    os.environ["ROBYN_PORT"] = str(port)
+    process = start_server(domain, port)
+    del os.environ["ROBYN_PORT"]
+    yield process
+    kill_process(process)
+
+
 # create robyn.env before test and delete it after test
 @pytest.fixture
 def env_file():
@@ -1,8 +1,24 @@
 import json
+import platform
+import signal
+import socket
+import struct
+import time
+import uuid
+from concurrent.futures import ThreadPoolExecutor
 
-from websocket import create_connection
+from websocket import (
+    ABNF,
+    WebSocketBadStatusException,
+    WebSocketConnectionClosedException,
+    WebSocketTimeoutException,
+    create_connection,
+)
 import pytest
 
+from integration_tests.helpers.http_methods_helpers import get
+from robyn.ws import CloseConnection, WebSocketConfig
+
 BASE_URL = "ws://127.0.0.1:8080"
 
 
@@ -42,3 +58,1725 @@ def test_web_socket_json(session):
     resp = json.loads(ws.recv())
     assert resp["resp"] == "*chika* *chika* Slim Shady."
     assert resp["msg"] == msg
+
+
+def test_web_socket_binary(session):
+    ws = create_connection(f"{BASE_URL}/web_socket")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send_binary(b"\x00\x01\x02")
+    assert ws.recv() == "Received 3 bytes"
+
+
+def test_web_socket_binary_response(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_json")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send_binary(b"\x00\x01\x02")
+    assert ws.recv() == b"\x02\x01\x00"
+
+
+def test_web_socket_ping_does_not_call_connect(session):
+    ws = create_connection(f"{BASE_URL}/web_socket")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.ping("ping")
+    ws.send("My name is?")
+    assert ws.recv() == "Whaaat??"
+
+
+def test_web_socket_survives_async_handler_error(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send("raise")
+    ws.send("still alive")
+    assert ws.recv() == "still alive"
+
+
+def test_web_socket_survives_sync_handler_error(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send_binary(b"raise")
+    ws.send_binary(b"still alive")
+    assert ws.recv() == b"still alive"
+
+
+def test_web_socket_connect_handler_error(session):
+    with pytest.raises(WebSocketBadStatusException) as e:
+        create_connection(f"{BASE_URL}/web_socket_connect_error")
+    assert e.value.status_code == 500
+
+    # the worker is still serving the other connections
+    ws = create_connection(f"{BASE_URL}/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+
+
+def test_web_socket_pong_handler(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.pong("latency")
+    assert ws.recv() == "Pong latency"
+
+
+def test_web_socket_pong_is_not_answered(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.pong("unanswered")
+    # the frame that follows the pong is the answer of the handler, not a pong
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_TEXT
+    assert frame.data == b"Pong unanswered"
+
+
+def test_web_socket_automatic_pong(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.ping("auto")
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_PONG
+    assert frame.data == b"auto"
+
+
+def test_web_socket_manual_pong(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_manual_pong")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.ping("check")
+    # the only pong is the one sent by the handler
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_PONG
+    assert frame.data == b"manual check"
+    ws.send("after")
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_TEXT
+    assert frame.data == b"after"
+
+
+def test_web_socket_send_ping(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send("ping rtt-1")
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_PING
+    assert frame.data == b"rtt-1"
+    # websocket-client answers the ping, the pong reaches the "pong" handler
+    assert ws.recv() == "Pong rtt-1"
+
+
+def test_web_socket_send_ping_too_long(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send(f"ping {'x' * 126}")
+    assert ws.recv() == "The payload of a ping can't be longer than 125 bytes"
+
+
+def test_web_socket_ping_binary_payload(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_manual_pong")
+    assert ws.recv() == "Hello world, from ws"
+
+    # the payload reaches the handler as it was sent, even when it isn't text.
+    # the pong of the handler adds 7 bytes, so it stays within 125 bytes
+    payload = struct.pack("!d", time.time()) + b"\xff" * 110
+    ws.ping(payload)
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_PONG
+    assert frame.data == b"manual " + payload
+
+
+def test_web_socket_ping_too_long(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+
+    # websocket-client doesn't check the size of the control frames that it sends
+    ws.ping(b"x" * 126)
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_TEXT
+    assert frame.data.decode().startswith("Protocol error: ")
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_CLOSE
+    # 1002 (protocol error)
+    assert struct.unpack("!H", frame.data[:2])[0] == 1002
+
+
+def test_web_socket_query_params(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error?token=abc&room=42")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send("query")
+    assert json.loads(ws.recv()) == {"token": "abc", "room": "42"}
+
+
+def test_web_socket_headers(session):
+    ws = create_connection(
+        f"{BASE_URL}/web_socket_error", header={"Authorization": "Bearer abc"}
+    )
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send("headers")
+    assert json.loads(ws.recv())["authorization"] == "Bearer abc"
+
+
+def test_web_socket_connect_rejection(session):
+    with pytest.raises(WebSocketBadStatusException) as e:
+        create_connection(f"{BASE_URL}/web_socket_error?reject=true")
+    assert e.value.status_code == 403
+
+
+def test_web_socket_connect_close(session):
+    # the connection is upgraded and closed with the code of the connect handler
+    ws = create_connection(f"{BASE_URL}/web_socket_error?close=true")
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_CLOSE
+    assert struct.unpack("!H", frame.data[:2])[0] == 4001
+    assert frame.data[2:] == b"Refused by the connect handler"
+
+
+def test_web_socket_broadcast(session):
+    sender = create_connection(f"{BASE_URL}/web_socket_error")
+    receiver = create_connection(f"{BASE_URL}/web_socket_error")
+    assert sender.recv() == "Hello world, from ws"
+    assert receiver.recv() == "Hello world, from ws"
+
+    sender.send("broadcast")
+    assert sender.recv() == "Broadcast from ws"
+    assert receiver.recv() == "Broadcast from ws"
+
+
+def test_web_socket_binary_broadcast(session):
+    sender = create_connection(f"{BASE_URL}/web_socket_error")
+    receiver = create_connection(f"{BASE_URL}/web_socket_error")
+    assert sender.recv() == "Hello world, from ws"
+    assert receiver.recv() == "Hello world, from ws"
+
+    sender.send("broadcast bytes")
+    for ws in [sender, receiver]:
+        opcode, frame = ws.recv_data_frame()
+        assert opcode == ABNF.OPCODE_BINARY
+        assert frame.data == b"Broadcast from ws"
+
+
+def test_web_socket_broadcast_fan_out(session):
+    receivers = [create_connection(f"{BASE_URL}/web_socket_error") for _ in range(100)]
+    for receiver in receivers:
+        assert receiver.recv() == "Hello world, from ws"
+
+    # the message is converted once and shared by the 100 connections
+    receivers[0].send("broadcast")
+    for receiver in receivers:
+        assert receiver.recv() == "Broadcast from ws"
+
+
+def test_web_socket_connection_state(session):
+    first = create_connection(f"{BASE_URL}/web_socket_error")
+    second = create_connection(f"{BASE_URL}/web_socket_error")
+    assert first.recv() == "Hello world, from ws"
+    assert second.recv() == "Hello world, from ws"
+
+    first.send("count")
+    assert first.recv() == "1"
+    first.send("count")
+    assert first.recv() == "2"
+    # the state is not shared between connections
+    second.send("count")
+    assert second.recv() == "1"
+
+
+def test_web_socket_send_to(session):
+    sender = create_connection(f"{BASE_URL}/web_socket_error")
+    receiver = create_connection(f"{BASE_URL}/web_socket_error")
+    assert sender.recv() == "Hello world, from ws"
+    assert receiver.recv() == "Hello world, from ws"
+
+    sender.send("id")
+    sender_id = sender.recv()
+    receiver.send("id")
+    receiver_id = receiver.recv()
+
+    sender.send(f"send_to {receiver_id}")
+    assert receiver.recv() == f"Message from {sender_id}"
+
+
+def test_web_socket_rooms(session):
+    member = create_connection(f"{BASE_URL}/web_socket_error")
+    outsider = create_connection(f"{BASE_URL}/web_socket_error")
+    assert member.recv() == "Hello world, from ws"
+    assert outsider.recv() == "Hello world, from ws"
+
+    member.send("join lobby")
+    # the answer to "id" comes once the room was joined
+    member.send("id")
+    member_id = member.recv()
+    outsider.send("room lobby hello")
+    assert member.recv() == "hello"
+    assert json.loads(outsider.recv()) == {"delivered": 1, "failed": 0}
+
+    member.send("leave lobby")
+    member.send("id")
+    assert member.recv() == member_id
+    outsider.send("room lobby again")
+    assert json.loads(outsider.recv()) == {"delivered": 0, "failed": 0}
+    # the member left the room so the next message it receives is its id
+    member.send("id")
+    assert member.recv() == member_id
+
+
+def test_web_socket_room_except_sender(session):
+    sender = create_connection(f"{BASE_URL}/web_socket_error")
+    other = create_connection(f"{BASE_URL}/web_socket_error")
+    assert sender.recv() == "Hello world, from ws"
+    assert other.recv() == "Hello world, from ws"
+
+    sender.send("join relayed")
+    other.send("join relayed")
+    # the answer to "id" comes once the room was joined
+    other.send("id")
+    other_id = other.recv()
+    sender.send("relay relayed hello")
+    # the sender only receives the delivery, not its own message
+    assert json.loads(sender.recv()) == {"delivered": 1, "failed": 0}
+    assert other.recv() == "hello"
+    other.send("id")
+    assert other.recv() == other_id
+
+
+def test_web_socket_room_sample(session):
+    members = [create_connection(f"{BASE_URL}/web_socket_error") for _ in range(4)]
+    sender = create_connection(f"{BASE_URL}/web_socket_error")
+    for ws in [*members, sender]:
+        assert ws.recv() == "Hello world, from ws"
+    ids = []
+    for ws in members:
+        ws.send("join sampled")
+        # the answer to "id" comes once the room was joined
+        ws.send("id")
+        ids.append(ws.recv())
+
+    def received():
+        # the members that were not picked receive their id first
+        for ws in members:
+            ws.send("id")
+        answers = [ws.recv() for ws in members]
+        for ws, answer, id in zip(members, answers, ids):
+            if answer != id:
+                assert ws.recv() == id
+        return sum(answer != id for answer, id in zip(answers, ids))
+
+    sender.send("sample sampled 3 hello")
+    assert json.loads(sender.recv()) == {"delivered": 3, "failed": 0}
+    assert received() == 3
+    sender.send("sample sampled 0.25 hello")
+    assert json.loads(sender.recv()) == {"delivered": 1, "failed": 0}
+    assert received() == 1
+    # asking for more members than the room has sends to all of them
+    sender.send("sample sampled 10 hello")
+    assert json.loads(sender.recv()) == {"delivered": 4, "failed": 0}
+    assert received() == 4
+    sender.send("sample sampled 1.5 hello")
+    assert sender.recv() == "ValueError"
+    sender.send("sample sampled -1 hello")
+    assert sender.recv() == "ValueError"
+
+
+def test_web_socket_room_member_count(session):
+    first = create_connection(f"{BASE_URL}/web_socket_error")
+    second = create_connection(f"{BASE_URL}/web_socket_error")
+    assert first.recv() == "Hello world, from ws"
+    assert second.recv() == "Hello world, from ws"
+
+    first.send("members counted")
+    assert first.recv() == "0"
+    first.send("join counted")
+    second.send("join counted")
+    # the answer to "id" comes once the room was joined
+    second.send("id")
+    second.recv()
+    first.send("members counted")
+    assert first.recv() == "2"
+
+    second.close()
+    # wait for the server to forget the closed connection
+    time.sleep(0.5)
+    first.send("members counted")
+    assert first.recv() == "1"
+
+
+def test_web_socket_max_payload_size(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send("a" * 2048)
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_CLOSE
+    assert struct.unpack("!H", frame.data[:2])[0] == 1009
+
+
+def test_web_socket_fragmented_message(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send_frame(ABNF.create_frame("hel", ABNF.OPCODE_TEXT, fin=0))
+    ws.send_frame(ABNF.create_frame("l", ABNF.OPCODE_CONT, fin=0))
+    ws.send_frame(ABNF.create_frame("o", ABNF.OPCODE_CONT, fin=1))
+    assert ws.recv() == "hello"
+
+
+def test_web_socket_fragmented_message_too_big(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+
+    # every frame is below max_payload_size but the whole message is not
+    ws.send_frame(ABNF.create_frame("a" * 600, ABNF.OPCODE_TEXT, fin=0))
+    ws.send_frame(ABNF.create_frame("a" * 600, ABNF.OPCODE_CONT, fin=1))
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_CLOSE
+    assert struct.unpack("!H", frame.data[:2])[0] == 1009
+
+
+def test_web_socket_protocol_error(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send(b"\xff\xfe", opcode=ABNF.OPCODE_TEXT)
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_TEXT
+    assert frame.data.decode().startswith("Protocol error: ")
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_CLOSE
+    # 1007 (invalid frame payload data)
+    assert struct.unpack("!H", frame.data[:2])[0] == 1007
+
+
+def test_web_socket_none_response(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+
+    # joining a room returns None, so the next frame is the answer to "id"
+    ws.send("join quiet")
+    ws.send("id")
+    ws.send("leave quiet")
+    ws.send("echo")
+    websocket_id = ws.recv()
+    assert len(websocket_id) == 36
+    assert ws.recv() == "echo"
+
+
+def test_web_socket_connection_metrics(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send("hello")
+    assert ws.recv() == "hello"
+    ws.send("metrics")
+    metrics = json.loads(ws.recv())
+    assert metrics["frames_received"] == 2
+    assert metrics["bytes_received"] == len("hello") + len("metrics")
+    # the greeting and the echo
+    assert metrics["frames_sent"] == 2
+    assert metrics["bytes_sent"] == len("Hello world, from ws") + len("hello")
+    assert metrics["uptime"] > 0
+
+
+def test_web_socket_dict_response(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+
+    # async handler
+    ws.send("dict")
+    assert json.loads(ws.recv()) == {"type": "update", "values": [1, 2]}
+    # sync handler
+    ws.send_binary(b"dict")
+    assert json.loads(ws.recv()) == {"type": "binary"}
+
+    # a value that can't be serialized is logged and nothing is sent
+    ws.send_binary(b"object")
+    ws.send_binary(b"still alive")
+    assert ws.recv() == b"still alive"
+
+
+def test_web_socket_verbose(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+
+    # only the logs change
+    ws.send("verbose on")
+    assert ws.recv() == "verbose on"
+    ws.send("hello")
+    assert ws.recv() == "hello"
+    ws.send("verbose off")
+    assert ws.recv() == "verbose off"
+
+
+def test_web_socket_queue_depth(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send("queue")
+    assert ws.recv() == "0"
+
+
+def test_web_socket_list_response(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send("many")
+    assert ws.recv() == "one"
+    assert ws.recv() == b"two"
+    assert ws.recv() == "three"
+
+
+def test_web_socket_close_from_handler(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send("close")
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_CLOSE
+    assert struct.unpack("!H", frame.data[:2])[0] == 1000
+    assert frame.data[2:].decode() == "Closed by the handler"
+
+
+@pytest.mark.skipif(platform.system() == "Windows", reason="SIGTERM stops the server")
+def test_web_socket_going_away_on_shutdown(web_socket_shutdown_session):
+    ws = create_connection("ws://127.0.0.1:8083/web_socket_error", timeout=5)
+    assert ws.recv() == "Hello world, from ws"
+
+    # the clients are told to reconnect elsewhere instead of seeing the connection drop
+    web_socket_shutdown_session.send_signal(signal.SIGTERM)
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_CLOSE
+    assert struct.unpack("!H", frame.data[:2])[0] == 1001
+
+
+def test_web_socket_close_is_answered(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error", timeout=5)
+    assert ws.recv() == "Hello world, from ws"
+
+    # the server answers with the same code, then ends the connection
+    ws.send_close(4001, b"bye")
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_CLOSE
+    assert struct.unpack("!H", frame.data[:2])[0] == 4001
+    assert ws.sock.recv(1) == b""
+
+
+@pytest.mark.parametrize("closed_by", ["server", "client"])
+def test_web_socket_async_close_handler(session, closed_by):
+    ws = create_connection(f"{BASE_URL}/web_socket_async_close", timeout=5)
+    ws.send("id")
+    id = ws.recv()
+    ws.send("name alice")
+    assert ws.recv() == "ok"
+
+    if closed_by == "server":
+        ws.send("close")
+        code = 4003
+    else:
+        ws.send_close(4001, b"bye")
+        code = 4001
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_CLOSE
+    assert struct.unpack("!H", frame.data[:2])[0] == code
+    ws.close()
+    time.sleep(0.5)
+
+    # the handler ran to the end and could still read the state of the connection
+    other = create_connection(f"{BASE_URL}/web_socket_async_close")
+    other.send(f"closed {id}")
+    assert json.loads(other.recv()) == {"code": code, "name": "alice"}
+    other.close()
+
+
+def test_web_socket_close_code_reaches_close_handler(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+    ws.send("id")
+    id = ws.recv()
+
+    ws.send("close_with 4002 Session ended")
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_CLOSE
+    assert struct.unpack("!H", frame.data[:2])[0] == 4002
+    time.sleep(0.5)
+
+    other = create_connection(f"{BASE_URL}/web_socket_error")
+    assert other.recv() == "Hello world, from ws"
+    other.send(f"close_reason {id}")
+    assert json.loads(other.recv()) == {"code": 4002, "reason": "Session ended"}
+    other.close()
+
+
+@pytest.mark.parametrize("code", [1000, 1003, 1007, 1014, 3000, 4000, 4999])
+def test_web_socket_valid_close_codes(code):
+    assert CloseConnection(code).code == code
+
+
+@pytest.mark.parametrize(
+    "code", [0, 999, 1004, 1005, 1006, 1015, 1016, 2999, 5000, 65535]
+)
+def test_web_socket_invalid_close_codes(code):
+    with pytest.raises(ValueError):
+        CloseConnection(code)
+    close = CloseConnection(4000)
+    with pytest.raises(ValueError):
+        close.code = code
+    assert close.code == 4000
+
+
+def test_web_socket_close_reason_too_long():
+    # with the 2 bytes of the code, a close frame can't be longer than 125 bytes
+    assert CloseConnection(1000, "x" * 123).reason == "x" * 123
+    with pytest.raises(ValueError):
+        CloseConnection(1000, "x" * 124)
+    # the limit is in bytes, "é" takes 2 of them
+    with pytest.raises(ValueError):
+        CloseConnection(1000, "é" * 62)
+    close = CloseConnection(1000, "bye")
+    with pytest.raises(ValueError):
+        close.reason = "x" * 124
+    assert close.reason == "bye"
+
+
+def test_web_socket_kick_reason_too_long(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send("id")
+    websocket_id = ws.recv()
+    ws.send(f"kick_reason {websocket_id} 124")
+    assert ws.recv() == "ValueError"
+    ws.send("id")
+    assert ws.recv() == websocket_id
+
+
+@pytest.mark.parametrize(
+    "setting",
+    [
+        {"heartbeat_interval": 0},
+        {"idle_timeout": 0},
+        {"slow_consumer_timeout": 0, "high_water_mark": 3},
+        {"handshake_timeout": 0},
+        {"mailbox_capacity": 0},
+        {"max_payload_size": 0},
+    ],
+)
+def test_web_socket_zero_settings(setting):
+    # the periodic checks would run in a busy loop, or every connection would fail at once
+    with pytest.raises(ValueError):
+        WebSocketConfig(**setting)
+
+
+@pytest.mark.parametrize(
+    "setting",
+    [
+        {"compression_dictionary": b"keys"},
+        {"compression": True, "compression_dictionary": b""},
+        {"compression": True, "compression_dictionary": b"x" * 32769},
+    ],
+)
+def test_web_socket_invalid_compression_dictionary(setting):
+    # the dictionary needs the extension, and has to fit in the window of the compressor
+    with pytest.raises(ValueError):
+        WebSocketConfig(**setting)
+    WebSocketConfig(compression=True, compression_dictionary=b"x" * 32768)
+
+
+def test_web_socket_kick_invalid_code(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send("id")
+    websocket_id = ws.recv()
+    ws.send(f"kick_code {websocket_id} 1006")
+    assert ws.recv() == "ValueError"
+    ws.send("id")
+    assert ws.recv() == websocket_id
+
+
+def test_web_socket_kick(session):
+    victim = create_connection(f"{BASE_URL}/web_socket_error")
+    operator = create_connection(f"{BASE_URL}/web_socket_error")
+    assert victim.recv() == "Hello world, from ws"
+    assert operator.recv() == "Hello world, from ws"
+
+    victim.send("id")
+    victim_id = victim.recv()
+    operator.send(f"kick {victim_id}")
+    assert operator.recv() == "True"
+    opcode, frame = victim.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_CLOSE
+    assert struct.unpack("!H", frame.data[:2])[0] == 4003
+    assert frame.data[2:].decode() == "Kicked"
+
+    # wait for the server to forget the closed connection
+    time.sleep(0.5)
+    operator.send(f"kick {victim_id}")
+    assert operator.recv() == "False"
+
+
+def test_web_socket_remote_ip(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send("ip")
+    assert ws.recv() == "127.0.0.1"
+
+
+def test_web_socket_json_mode(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_json_mode")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send(json.dumps({"numbers": [1, 2, 3]}))
+    assert json.loads(ws.recv()) == {"type": "dict", "msg": {"numbers": [1, 2, 3]}}
+
+    ws.send("not json")
+    resp = json.loads(ws.recv())
+    assert resp["error"] == "invalid_json"
+    # the connection stays open after an invalid message
+    ws.send("[1]")
+    assert json.loads(ws.recv()) == {"type": "list", "msg": [1]}
+
+
+def test_web_socket_invalid_json_envelope(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_json_envelope")
+    ws.send("not json")
+    resp = json.loads(ws.recv())
+    assert resp["type"] == "error"
+    assert resp["code"] == "invalid_json"
+    assert resp["detail"].startswith("expected value")
+    ws.send('{"a": 1}')
+    assert json.loads(ws.recv()) == {"a": 1}
+    ws.close()
+
+
+def test_web_socket_invalid_json_without_reply(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_json_silent")
+    ws.send("not json")
+    ws.send('{"a": 1}')
+    # the invalid message is dropped without an answer
+    assert json.loads(ws.recv()) == {"a": 1}
+    ws.close()
+
+
+def test_web_socket_request_object(session):
+    before = time.time()
+    ws = create_connection(f"{BASE_URL}/web_socket_request?room=lobby")
+    websocket_id = ws.recv()
+
+    ws.send("hello")
+    response = json.loads(ws.recv())
+    # the clocks of the test and of the server are the same
+    assert before - 1 <= response.pop("connected_at") <= time.time()
+    assert response == {
+        "id": websocket_id,
+        "message": "hello",
+        "query_params": {"room": "lobby"},
+        "messages": 1,
+        "open": True,
+        "route": "/web_socket_request",
+        # the answers were sent before this message came in
+        "queue_depth": 0,
+        "extensions": [],
+    }
+
+
+@pytest.mark.parametrize("closed_by", ["client", "kick"])
+def test_web_socket_request_is_open_after_close(session, closed_by):
+    ws = create_connection(f"{BASE_URL}/web_socket_request")
+    websocket_id = ws.recv()
+
+    # the handler checks the connection once it closed in the meantime
+    ws.send("wait")
+    if closed_by == "client":
+        ws.close()
+    else:
+        other = create_connection(f"{BASE_URL}/web_socket_error")
+        assert other.recv() == "Hello world, from ws"
+        other.send(f"kick_code {websocket_id} 1000")
+        assert other.recv() == "True"
+        other.close()
+    time.sleep(1)
+
+    other = create_connection(f"{BASE_URL}/web_socket_request")
+    other.recv()
+    other.send(f"was_open {websocket_id}")
+    assert json.loads(other.recv()) is False
+    other.close()
+
+
+def test_web_socket_connection_handle_in_connect_handler(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_connect_handle")
+    # what the handle sent waits for the connection to open
+    assert ws.recv() == "True"
+    assert ws.recv() == "Sent from the connect handler"
+    ws.close()
+
+
+def test_web_socket_connection_handle_of_refused_connection(session):
+    with pytest.raises(WebSocketBadStatusException):
+        create_connection(f"{BASE_URL}/web_socket_connect_handle?refuse=1")
+
+    ws = create_connection(f"{BASE_URL}/web_socket_connect_handle")
+    assert ws.recv() == "True"
+    assert ws.recv() == "Sent from the connect handler"
+    ws.send("refused")
+    assert json.loads(ws.recv()) == {"alive": False, "sent": False}
+    ws.close()
+
+
+def test_web_socket_compression_negotiated(session):
+    ws = create_connection(
+        f"{BASE_URL}/web_socket_compression",
+        header={"Sec-WebSocket-Extensions": "permessage-deflate; client_max_window_bits"},
+    )
+    assert ws.getheaders()["sec-websocket-extensions"] == "permessage-deflate"
+    # below the threshold, so that the client doesn't need to inflate it
+    ws.send("hello")
+    assert ws.recv() == "hello"
+    ws.send("metrics")
+    metrics = json.loads(ws.recv())
+    assert metrics["uncompressed_frames_sent"] == 1
+    assert metrics["compressed_frames_sent"] == 0
+    ws.close()
+
+    ws = create_connection(f"{BASE_URL}/web_socket_compression")
+    assert "sec-websocket-extensions" not in ws.getheaders()
+    ws.send("x" * 2048)
+    assert ws.recv() == "x" * 2048
+    ws.close()
+
+
+def test_web_socket_request_extensions(session):
+    ws = create_connection(
+        f"{BASE_URL}/web_socket_request",
+        header={
+            "Sec-WebSocket-Extensions": 'x-custom; mode="a,b", bad name; x, '
+            "permessage-deflate; client_max_window_bits"
+        },
+    )
+    ws.recv()
+
+    ws.send("hello")
+    # the malformed extensions are left out
+    assert json.loads(ws.recv())["extensions"] == [
+        {"name": "x-custom", "params": {"mode": "a,b"}},
+        {"name": "permessage-deflate", "params": {"client_max_window_bits": None}},
+    ]
+
+
+def test_web_socket_idle_timeout(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_idle")
+    assert ws.recv() == "Hello world, from ws"
+
+    # talking keeps the connection open
+    for _ in range(3):
+        time.sleep(0.5)
+        ws.send("still here")
+        assert ws.recv() == "still here"
+
+    # the before_close handler runs before the close frame is sent
+    assert ws.recv() == "Closing with 1001"
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_CLOSE
+    assert struct.unpack("!H", frame.data[:2])[0] == 1001
+
+
+def test_web_socket_rate_limit(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_rate_limit")
+    assert ws.recv() == "Hello world, from ws"
+
+    # the burst goes through
+    ws.send("one")
+    ws.send("two")
+    assert ws.recv() == "one"
+    assert ws.recv() == "two"
+
+    # the next messages are dropped and the second violation closes the connection
+    ws.send("three")
+    ws.send("four")
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_CLOSE
+    assert struct.unpack("!H", frame.data[:2])[0] == 1008
+
+
+def test_web_socket_msgpack_mode(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_msgpack")
+    assert ws.recv() == "Hello world, from ws"
+
+    # {"type": "update", "values": [1, b"\x00"]}
+    message = b"\x82\xa4type\xa6update\xa6values\x92\x01\xc4\x01\x00"
+    ws.send_binary(message)
+    # {"received": message}
+    assert ws.recv() == b"\x81\xa8received" + message
+
+    # invalid msgpack goes to the error handler
+    ws.send_binary(b"\xc1")
+    assert ws.recv().startswith("Invalid msgpack")
+
+    # text messages are not decoded
+    ws.send("hello")
+    assert ws.recv() == "hello"
+
+
+def test_web_socket_cbor_mode(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_cbor")
+    assert ws.recv() == "Hello world, from ws"
+
+    # {"type": "update", "values": [1, b"\x00"]}
+    message = b"\xa2\x64type\x66update\x66values\x82\x01\x41\x00"
+    ws.send_binary(message)
+    # {"received": message}
+    assert ws.recv() == b"\xa1\x68received" + message
+
+    # an array with an indefinite length, [1, -1]
+    ws.send_binary(b"\x9f\x01\x20\xff")
+    assert ws.recv() == b"\xa1\x68received\x82\x01\x20"
+
+    # invalid CBOR goes to the error handler
+    ws.send_binary(b"\x1c")
+    assert ws.recv().startswith("Invalid CBOR")
+
+
+def test_web_socket_dedicated_event_loop(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_dedicated_loop")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send("hello")
+    assert ws.recv() == "hello on a dedicated thread"
+    ws.send("again")
+    assert ws.recv() == "again on a dedicated thread"
+
+
+def test_web_socket_ordered(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_ordered")
+    assert ws.recv() == "Hello world, from ws"
+
+    # the handler of the first message is the slowest one
+    ws.send("0.3")
+    ws.send("0.1")
+    ws.send("0")
+    assert [ws.recv() for _ in range(3)] == ["0.3", "0.1", "0"]
+
+
+def test_web_socket_dropped_frames_are_reported(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_drop_newest")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send("4")
+    # the answer of the handler doesn't wait in the mailbox, so it comes first
+    assert json.loads(ws.recv()) == [True, True, False, False]
+    assert [ws.recv() for _ in range(2)] == ["0", "1"]
+
+
+def test_web_socket_handler_timeout(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_handler_timeout")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send("cancelled")
+    cancelled = int(ws.recv())
+    # the handler is cancelled and the connection stays open
+    ws.send("2")
+    ws.send("0.1")
+    assert ws.recv() == "0.1"
+    time.sleep(1)
+    ws.send("cancelled")
+    assert ws.recv() == str(cancelled + 1)
+
+
+def test_web_socket_handler_timeout_closes(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_handler_timeout_close")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send("2")
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_CLOSE
+    assert struct.unpack("!H", frame.data[:2])[0] == 1011
+
+
+def test_web_socket_app_heartbeat(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_app_heartbeat", timeout=10)
+    assert ws.recv() == "Hello world, from ws"
+
+    for _ in range(3):
+        assert ws.recv() == '{"type":"ping"}'
+        # the answer is not passed to the message handler
+        ws.send('{"type": "pong"}')
+        ws.send("hello")
+        assert ws.recv() == "hello"
+
+
+def test_web_socket_app_heartbeat_missed(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_app_heartbeat", timeout=10)
+    assert ws.recv() == "Hello world, from ws"
+
+    # the client doesn't answer, so it is disconnected after the heartbeat timeout
+    frames = []
+    with pytest.raises(WebSocketConnectionClosedException):
+        while True:
+            frames.append(ws.recv())
+    assert set(frames) <= {'{"type":"ping"}', ""}
+
+
+def test_web_socket_app_heartbeat_interval_above_timeout(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_slow_app_heartbeat", timeout=5)
+    assert ws.recv() == "Hello world, from ws"
+
+    # the timeout runs from each heartbeat, not from the previous answer
+    for _ in range(2):
+        assert ws.recv() == '{"type":"ping"}'
+        ws.send('{"type": "pong"}')
+    ws.send("hello")
+    assert ws.recv() == "hello"
+    ws.close()
+
+
+def test_web_socket_heartbeat_interval_above_timeout(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_slow_heartbeat")
+    assert ws.recv() == "Hello world, from ws"
+
+    # the client answers the pings while it reads, the timeout runs from each ping
+    ws.settimeout(0.2)
+    deadline = time.time() + 5
+    while time.time() < deadline:
+        with pytest.raises(WebSocketTimeoutException):
+            ws.recv()
+    ws.settimeout(5)
+    ws.send("hello")
+    assert ws.recv() == "hello"
+    ws.close()
+
+
+def test_web_socket_without_connect_handler(session):
+    # the connection is accepted without a greeting
+    ws = create_connection(f"{BASE_URL}/web_socket_no_connect")
+    ws.send("hello")
+    assert ws.recv() == "hello"
+
+
+@pytest.mark.parametrize(
+    "route", ["/web_socket_no_message", "/web_socket_no_message_raw_text"]
+)
+def test_web_socket_without_message_handler(route, session):
+    ws = create_connection(f"{BASE_URL}{route}")
+    assert ws.recv() == "Hello world, from ws"
+
+    # the message is ignored and the connection stays open
+    ws.send("hello")
+    ws.ping("still open")
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_PONG
+    assert frame.data == b"still open"
+
+
+def test_web_socket_without_close_handler(session):
+    for _ in range(3):
+        ws = create_connection(f"{BASE_URL}/web_socket_no_close")
+        assert ws.recv() == "Hello world, from ws"
+        ws.close()
+
+    # the connections that are dropped without a close frame are ignored too
+    for _ in range(3):
+        ws = create_connection(f"{BASE_URL}/web_socket_no_close")
+        assert ws.recv() == "Hello world, from ws"
+        ws.shutdown()
+
+    ws = create_connection(f"{BASE_URL}/web_socket_no_close")
+    assert ws.recv() == "Hello world, from ws"
+    ws.send("hello")
+    assert ws.recv() == "hello"
+
+
+def test_web_socket_tags(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_tags?plan=pro")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send("tags")
+    assert json.loads(ws.recv()) == {"plan": "pro", "region": "us"}
+    ws.send("untag")
+    ws.send("tags")
+    assert json.loads(ws.recv()) == {"region": "us"}
+
+
+def test_web_socket_broadcast_where(session):
+    pro = create_connection(f"{BASE_URL}/web_socket_tags?plan=pro")
+    free = create_connection(f"{BASE_URL}/web_socket_tags?plan=free")
+    assert pro.recv() == "Hello world, from ws"
+    assert free.recv() == "Hello world, from ws"
+
+    free.send("broadcast pro Pro feature released")
+    assert pro.recv() == "Pro feature released"
+    # the message isn't sent to the sender, which is on another plan
+    assert json.loads(free.recv()) == {"delivered": 1, "failed": 0}
+
+    # the tags are dropped with the connection
+    pro.close()
+    time.sleep(0.5)
+    free.send("broadcast pro Not delivered")
+    assert json.loads(free.recv()) == {"delivered": 0, "failed": 0}
+
+
+def test_web_socket_list_connections(session):
+    first = create_connection(f"{BASE_URL}/web_socket_tags?plan=pro")
+    second = create_connection(f"{BASE_URL}/web_socket_tags")
+    ids = []
+    for ws in [first, second]:
+        assert ws.recv() == "Hello world, from ws"
+        ws.send("id")
+        ids.append(ws.recv())
+    first.send("join listed")
+    # the answer to "id" comes once the room was joined
+    first.send("id")
+    first.recv()
+
+    # the connections of the other tests can still be open
+    listed = get("/web_socket_connections").json()
+    # the oldest first
+    assert listed.index(ids[0]) < listed.index(ids[1])
+    pages = [
+        get(f"/web_socket_connections?limit=2&offset={offset}").json()
+        for offset in range(0, len(listed), 2)
+    ]
+    assert all(len(page) <= 2 for page in pages)
+    assert [id for page in pages for id in page] == listed
+
+    details = {
+        connection["id"]: connection
+        for connection in get("/web_socket_connections?details=true").json()
+    }
+    assert details[ids[0]] == {
+        "id": ids[0],
+        "tags": {"plan": "pro", "region": "us"},
+        "rooms": ["listed"],
+    }
+    assert details[ids[1]]["rooms"] == []
+
+    second.close()
+    time.sleep(0.5)
+    assert ids[1] not in get("/web_socket_connections").json()
+
+
+def test_web_socket_lifecycle_events(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+    ws.send("id")
+    id = ws.recv()
+    ws.send("raise")
+    ws.send("still alive")
+    assert ws.recv() == "still alive"
+    ws.close()
+    time.sleep(0.5)
+
+    events = get(f"/web_socket_lifecycle/{id}").json()
+    kinds = [event["event"] for event in events]
+    assert kinds == ["connected", "error", "disconnected"]
+    assert all(event["route"] == "/web_socket_error" for event in events)
+    assert events[0]["detail"] is None
+    assert events[1]["detail"] == "Raised inside an async web socket handler"
+    assert events[2]["detail"] == "1000"
+    timestamps = [event["timestamp"] for event in events]
+    assert timestamps == sorted(timestamps)
+    assert abs(timestamps[0] - time.time()) < 60
+
+
+def test_web_socket_id_header(session):
+    alice = create_connection(
+        f"{BASE_URL}/web_socket_client_id", header={"X-Client-Id": "alice"}
+    )
+    sender = create_connection(f"{BASE_URL}/web_socket_client_id")
+    assert alice.recv() == "Hello world, from ws"
+    assert sender.recv() == "Hello world, from ws"
+
+    alice.send("id")
+    assert alice.recv() == "alice"
+    # the connections without an id keep their random UUID
+    sender.send("id")
+    uuid.UUID(sender.recv())
+
+    sender.send("send_to alice Hi alice")
+    assert sender.recv() == "True"
+    assert alice.recv() == "Hi alice"
+
+
+def test_web_socket_duplicate_id(session):
+    carol = create_connection(
+        f"{BASE_URL}/web_socket_client_id", header={"X-Client-Id": "carol"}
+    )
+    assert carol.recv() == "Hello world, from ws"
+
+    with pytest.raises(WebSocketBadStatusException) as e:
+        create_connection(
+            f"{BASE_URL}/web_socket_client_id", header={"X-Client-Id": "carol"}
+        )
+    assert e.value.status_code == 409
+
+    # the id is released with the connection
+    carol.close()
+    time.sleep(0.5)
+    carol = create_connection(
+        f"{BASE_URL}/web_socket_client_id", header={"X-Client-Id": "carol"}
+    )
+    assert carol.recv() == "Hello world, from ws"
+
+
+def test_web_socket_id_from_connect_handler(session):
+    bob = create_connection(f"{BASE_URL}/web_socket_client_id?user=bob")
+    sender = create_connection(f"{BASE_URL}/web_socket_client_id")
+    assert bob.recv() == "Hello world, from ws"
+    assert sender.recv() == "Hello world, from ws"
+
+    bob.send("id")
+    assert bob.recv() == "bob"
+
+    sender.send("kick bob")
+    assert sender.recv() == "True"
+    opcode, frame = bob.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_CLOSE
+
+
+def test_web_socket_connect_handler_headers(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_client_id?header=X-Session-Id:abc")
+    assert ws.getheaders()["x-session-id"] == "abc"
+    assert ws.recv() == "Hello world, from ws"
+
+    # the headers of the upgrade can't be replaced
+    with pytest.raises(WebSocketBadStatusException) as e:
+        create_connection(f"{BASE_URL}/web_socket_client_id?header=Upgrade:h2c")
+    assert e.value.status_code == 500
+    with pytest.raises(WebSocketBadStatusException) as e:
+        create_connection(f"{BASE_URL}/web_socket_client_id?header=X-Bad%20Name:abc")
+    assert e.value.status_code == 500
+
+
+def test_web_socket_upgrade_failed(session):
+    with pytest.raises(WebSocketBadStatusException) as e:
+        create_connection(f"{BASE_URL}/web_socket_upgrade_failed")
+    assert e.value.status_code == 400
+
+
+@pytest.mark.parametrize(
+    "origin", ["https://example.com", "https://chat.example.com", "HTTPS://EXAMPLE.COM"]
+)
+def test_web_socket_allowed_origin(origin, session):
+    ws = create_connection(f"{BASE_URL}/web_socket_origin", origin=origin)
+    assert ws.recv() == "Hello world, from ws"
+    ws.close()
+
+
+@pytest.mark.parametrize(
+    "origin", ["https://evil.com", "https://example.com.evil.com", "http://example.com"]
+)
+def test_web_socket_refused_origin(origin, session):
+    with pytest.raises(WebSocketBadStatusException) as e:
+        create_connection(f"{BASE_URL}/web_socket_origin", origin=origin)
+    assert e.value.status_code == 403
+
+
+def test_web_socket_without_origin(session):
+    with pytest.raises(WebSocketBadStatusException) as e:
+        create_connection(f"{BASE_URL}/web_socket_origin", suppress_origin=True)
+    assert e.value.status_code == 403
+    with pytest.raises(WebSocketBadStatusException) as e:
+        create_connection(
+            f"{BASE_URL}/web_socket_upgrade_failed?reject=true", subprotocols=["chat"]
+        )
+    assert e.value.status_code == 403
+    # the accepted upgrades are not reported
+    ws = create_connection(
+        f"{BASE_URL}/web_socket_upgrade_failed", subprotocols=["chat"]
+    )
+    ws.close()
+
+    # the handler runs after the response was sent
+    time.sleep(0.5)
+    assert get("/web_socket_upgrade_failures").json() == [
+        {
+            "reason": "subprotocol_required",
+            "status": 400,
+            "path": "/web_socket_upgrade_failed",
+            "query_params": {},
+        },
+        {
+            "reason": "rejected",
+            "status": 403,
+            "path": "/web_socket_upgrade_failed",
+            "query_params": {"reject": "true"},
+        },
+    ]
+
+
+def test_web_socket_drain_before_close(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_drain")
+
+    ws.send("10")
+    assert [ws.recv() for _ in range(10)] == [f"frame {i}" for i in range(10)]
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_CLOSE
+    assert struct.unpack("!H", frame.data[:2])[0] == 1000
+    assert frame.data[2:].decode() == "Drained"
+
+
+def test_web_socket_connection_handle(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_handle")
+    # the connection isn't open during the handshake
+    assert ws.recv() == "None"
+
+    ws.send("alive")
+    assert ws.recv() == "True"
+    ws.send("send")
+    assert ws.recv() == "Sent through the handle"
+    ws.send("ping")
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_PING
+    assert frame.data == b"handle"
+    ws.send("close")
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_CLOSE
+    assert struct.unpack("!H", frame.data[:2])[0] == 4000
+    assert frame.data[2:].decode() == "Closed through the handle"
+
+
+def test_web_socket_reconnect_token(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_reconnect")
+    token = ws.getheaders()["x-reconnect-token"]
+    ws.send("token")
+    assert ws.recv() == token
+    ws.send("join")
+    ws.send("state")
+    assert json.loads(ws.recv()) == {"state": {"joined": True}, "members": 1}
+    # a dropped connection, without a close frame
+    ws.sock.close()
+    time.sleep(0.5)
+
+    # a rejected attempt doesn't use the token up
+    with pytest.raises(WebSocketBadStatusException):
+        create_connection(
+            f"{BASE_URL}/web_socket_reconnect?reconnect_token={token}&reject=1"
+        )
+
+    ws = create_connection(f"{BASE_URL}/web_socket_reconnect?reconnect_token={token}")
+    assert ws.getheaders()["x-reconnect-token"] != token
+    ws.send("state")
+    assert json.loads(ws.recv()) == {"state": {"joined": True}, "members": 1}
+    ws.close()
+    time.sleep(0.5)
+
+    # a token only works once, and a forged one is ignored
+    for token in [token, f"{token[:-1]}0"]:
+        ws = create_connection(
+            f"{BASE_URL}/web_socket_reconnect?reconnect_token={token}"
+        )
+        ws.send("state")
+        assert json.loads(ws.recv()) == {"state": {}, "members": 0}
+        ws.close()
+
+
+def test_web_socket_dedup(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_dedup")
+    first = json.dumps({"id": "m1", "text": "hi"})
+    second = json.dumps({"id": 2, "text": "hi"})
+    ws.send(first)
+    assert ws.recv() == f"handled {first}"
+    ws.send(first)
+    assert ws.recv() == f"duplicate {first}"
+    ws.send(second)
+    assert ws.recv() == f"handled {second}"
+
+    # the messages without an id are always handled
+    ws.send("no id")
+    assert ws.recv() == "handled no id"
+    ws.send("no id")
+    assert ws.recv() == "handled no id"
+    ws.close()
+
+    # the ids are tracked per connection
+    ws = create_connection(f"{BASE_URL}/web_socket_dedup")
+    ws.send(first)
+    assert ws.recv() == f"handled {first}"
+    ws.close()
+
+
+def test_web_socket_dedup_content(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_dedup_content")
+    ws.send("hello")
+    assert ws.recv() == "handled hello"
+    # dropped without a duplicate handler
+    ws.send("hello")
+    ws.send("world")
+    assert ws.recv() == "handled world"
+    ws.close()
+
+
+def test_web_socket_pause(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_pause")
+    ws.send("pause")
+    id = ws.recv()
+    ws.send("first")
+    ws.send("second")
+    # the ping is answered while the message handler is paused
+    ws.ping("alive")
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_PONG
+    assert frame.data == b"alive"
+    time.sleep(0.2)
+
+    other = create_connection(f"{BASE_URL}/web_socket_pause")
+    other.send(f"resume {id}")
+    assert json.loads(other.recv()) == {"resumed": True, "handled": []}
+    # the held messages are handled in order
+    assert ws.recv() == "first"
+    assert ws.recv() == "second"
+    ws.send("third")
+    assert ws.recv() == "third"
+
+    other.send(f"resume {uuid.uuid4()}")
+    assert json.loads(other.recv()) == {"resumed": False, "handled": []}
+    ws.close()
+    other.close()
+
+
+def test_web_socket_pause_too_many_messages(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_pause")
+    ws.send("pause")
+    ws.recv()
+    for i in range(5):
+        ws.send(str(i))
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_CLOSE
+    assert struct.unpack("!H", frame.data[:2])[0] == 1013
+
+
+def test_web_socket_background_task(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_background")
+    id = ws.recv()
+    assert [ws.recv() for _ in range(3)] == ["tick 0", "tick 1", "tick 2"]
+    ws.close()
+    time.sleep(0.2)
+
+    other = create_connection(f"{BASE_URL}/web_socket_background")
+    other_id = other.recv()
+    other.send(f"cancelled {id}")
+    # the ticks of the new connection can come before the answer
+    assert "True" in [other.recv() for _ in range(10)]
+    other.send(f"cancelled {other_id}")
+    assert "False" in [other.recv() for _ in range(10)]
+    other.close()
+
+
+def test_web_socket_broadcast_with_ack(session):
+    first = create_connection(f"{BASE_URL}/web_socket_ack?room=acked")
+    second = create_connection(f"{BASE_URL}/web_socket_ack?room=acked")
+    # not in the room, so it isn't sent the message
+    sender = create_connection(f"{BASE_URL}/web_socket_ack")
+    first_id, second_id = first.recv(), second.recv()
+    sender.recv()
+
+    sender.send("broadcast acked 5")
+    ack_id = sender.recv()
+    assert json.loads(first.recv()) == {"text": "alert", "ack": ack_id}
+    assert json.loads(second.recv()) == {"text": "alert", "ack": ack_id}
+    first.send(json.dumps({"ack": ack_id}))
+    # the ack doesn't reach the handler, the next answer is the one to "id"
+    first.send("id")
+    assert first.recv() == first_id
+    sender.send(f"status {ack_id}")
+    assert json.loads(sender.recv()) == {
+        "acked": [first_id],
+        "pending": [second_id],
+        "expired": False,
+    }
+
+    sender.send("broadcast acked 0.2")
+    ack_id = sender.recv()
+    first.recv()
+    second.recv()
+    time.sleep(0.5)
+    # a late ack is passed to the handler like any message
+    late_ack = json.dumps({"ack": ack_id})
+    first.send(late_ack)
+    assert first.recv() == late_ack
+    sender.send(f"status {ack_id}")
+    status = json.loads(sender.recv())
+    assert status["acked"] == []
+    assert sorted(status["pending"]) == sorted([first_id, second_id])
+    assert status["expired"]
+
+    sender.send("status unknown")
+    assert sender.recv() == "null"
+
+
+def test_web_socket_unified_handler(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_unified")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send("hello")
+    assert ws.recv() == "text hello"
+    ws.send_binary(b"abc")
+    assert ws.recv() == "binary of 3 bytes"
+
+
+def test_web_socket_handler_chain(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_chain")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send("hello")
+    assert ws.recv() == "hello after 1 messages"
+    # the first handler answers, the others don't run
+    ws.send("forbidden")
+    assert ws.recv() == "Forbidden"
+    ws.send("again")
+    assert ws.recv() == "again after 2 messages"
+
+
+def test_web_socket_high_water_mark(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_high_water")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send("2")
+    assert [ws.recv() for _ in range(2)] == ["0", "1"]
+    ws.send("depth")
+    assert ws.recv() == "None"
+
+    ws.send("5")
+    assert [ws.recv() for _ in range(5)] == ["0", "1", "2", "3", "4"]
+    ws.send("depth")
+    assert ws.recv() == "3"
+
+
+def test_web_socket_drained(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_high_water")
+    assert ws.recv() == "Hello world, from ws"
+
+    # the mailbox never reached the mark
+    ws.send("2")
+    assert [ws.recv() for _ in range(2)] == ["0", "1"]
+    ws.send("drained")
+    assert ws.recv() == "0"
+
+    # called once per time the mailbox backed up
+    for times in [1, 2]:
+        ws.send("5")
+        assert [ws.recv() for _ in range(5)] == ["0", "1", "2", "3", "4"]
+        ws.send("drained")
+        assert ws.recv() == str(times)
+
+
+def test_web_socket_slow_consumer(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_slow_consumer")
+
+    ws.send("400")
+    # the client doesn't read for longer than slow_consumer_timeout
+    time.sleep(3)
+    opcode, frame = ws.recv_data_frame(control_frame=True)
+    while opcode == ABNF.OPCODE_TEXT:
+        opcode, frame = ws.recv_data_frame(control_frame=True)
+    assert opcode == ABNF.OPCODE_CLOSE
+    assert struct.unpack("!H", frame.data[:2])[0] == 1013
+
+
+def test_web_socket_slow_consumer_reads(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_slow_consumer")
+
+    # a client that keeps up stays connected
+    for _ in range(3):
+        ws.send("20")
+        assert [len(ws.recv()) for _ in range(20)] == [65_536] * 20
+        time.sleep(1)
+    ws.send("1")
+    assert len(ws.recv()) == 65_536
+
+
+def test_web_socket_handler_error_frame(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_error_frame")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send("raise")
+    assert json.loads(ws.recv()) == {
+        "error": "Raised inside an async web socket handler",
+        "type": "handler_error",
+    }
+    ws.send_binary(b"raise")
+    assert json.loads(ws.recv()) == {"error": "'binary'", "type": "handler_error"}
+
+    # the connection is kept alive
+    ws.send("still alive")
+    assert ws.recv() == "still alive"
+
+
+def test_web_socket_raw_text(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_raw_text")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send("hello")
+    assert ws.recv() == "bytes 68656c6c6f"
+
+    # not valid UTF-8, the connection would be closed with 1007 otherwise
+    ws.send(b"\xff\xfeok", opcode=ABNF.OPCODE_TEXT)
+    assert ws.recv() == "bytes fffe6f6b"
+
+    # the fragments of a text message are not decoded either
+    ws.send_frame(ABNF.create_frame(b"\xff", ABNF.OPCODE_TEXT, fin=0))
+    ws.send_frame(ABNF.create_frame(b"ok", ABNF.OPCODE_CONT, fin=1))
+    assert ws.recv() == "bytes ff6f6b"
+
+    # binary messages are echoed back without a binary handler
+    ws.send_binary(b"\x01\x02")
+    assert ws.recv() == b"\x01\x02"
+
+
+def test_web_socket_active_connection_count(session):
+    first = create_connection(f"{BASE_URL}/web_socket_error")
+    assert first.recv() == "Hello world, from ws"
+    first.send("connections")
+    assert int(first.recv()) >= 1
+
+    second = create_connection(f"{BASE_URL}/web_socket_error")
+    assert second.recv() == "Hello world, from ws"
+    # the connections of the other tests may be alive as well
+    first.send("connections")
+    assert int(first.recv()) >= 2
+
+
+def test_web_socket_connection_limit(web_socket_limit_session):
+    ws = create_connection("ws://127.0.0.1:8082/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+    ws.close()
+
+
+def test_web_socket_connection_limit_concurrent_upgrades(web_socket_limit_session):
+    # wait for the server to release the connections of the other tests
+    time.sleep(0.5)
+
+    def connect(_):
+        try:
+            return create_connection(
+                "ws://127.0.0.1:8082/web_socket_slow_connect?delay=0.5"
+            )
+        except WebSocketBadStatusException as e:
+            return e.status_code
+
+    # the upgrades are all in progress at once, while the connect handler sleeps
+    with ThreadPoolExecutor(4) as pool:
+        results = list(pool.map(connect, range(4)))
+    accepted = [result for result in results if not isinstance(result, int)]
+    assert len(accepted) == 1
+    assert [result for result in results if isinstance(result, int)] == [503] * 3
+    accepted[0].close()
+
+    with pytest.raises(WebSocketBadStatusException) as e:
+        create_connection("ws://127.0.0.1:8082/web_socket_error")
+    assert e.value.status_code == 503
+
+    ws.close()
+    # wait for the server to release the connection
+    time.sleep(0.5)
+    ws = create_connection("ws://127.0.0.1:8082/web_socket_error")
+    assert ws.recv() == "Hello world, from ws"
+
+
+def test_web_socket_async_generator(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_stream")
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send("3")
+    assert ws.recv() == "Frame 0"
+    assert ws.recv() == "Frame 1"
+    assert ws.recv() == "Frame 2"
+
+    # closing in the middle of a stream stops it
+    ws.send("1000")
+    assert ws.recv() == "Frame 0"
+    ws.close()
+
+
+def test_web_socket_cookies(session):
+    ws = create_connection(
+        f"{BASE_URL}/web_socket_error", cookie="session=abc123; theme=dark; malformed"
+    )
+    assert ws.recv() == "Hello world, from ws"
+
+    ws.send("cookies")
+    assert json.loads(ws.recv()) == {"session": "abc123", "theme": "dark"}
+
+
+def test_web_socket_path_params(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_room/lobby")
+    assert json.loads(ws.recv()) == {"room": "lobby"}
+
+    ws.send("hello")
+    assert ws.recv() == "hello in lobby"
+    # the route as it was declared, not the path
+    ws.send("route")
+    assert ws.recv() == "/web_socket_room/:room"
+
+
+def test_web_socket_subprotocol(session):
+    ws = create_connection(
+        f"{BASE_URL}/web_socket_subprotocol", subprotocols=["chat.v3", "chat.v1"]
+    )
+    assert ws.getsubprotocol() == "chat.v1"
+    assert ws.recv() == "Hello world, from ws"
+    ws.send("subprotocol")
+    assert ws.recv() == "chat.v1"
+
+
+def test_web_socket_subprotocol_from_connect(session):
+    ws = create_connection(
+        f"{BASE_URL}/web_socket_subprotocol?subprotocol=chat.v2",
+        subprotocols=["chat.v1", "chat.v2"],
+    )
+    assert ws.getsubprotocol() == "chat.v2"
+    assert ws.recv() == "Hello world, from ws"
+    ws.send("subprotocol")
+    assert ws.recv() == "chat.v2"
+
+    # the client didn't offer it
+    with pytest.raises(WebSocketBadStatusException) as e:
+        create_connection(
+            f"{BASE_URL}/web_socket_subprotocol?subprotocol=chat.v3",
+            subprotocols=["chat.v1", "chat.v2"],
+        )
+    assert e.value.status_code == 500
+
+
+def test_web_socket_async_connect_before_messages(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_slow_connect?delay=0.5")
+    # the first message is handled once the async connect handler set the state up
+    ws.send("ready")
+    assert ws.recv() == "Hello world, from ws"
+    assert ws.recv() == "True"
+
+
+def test_web_socket_handshake_timeout(session):
+    ws = create_connection(f"{BASE_URL}/web_socket_slow_connect?delay=0.1")
+    assert ws.recv() == "Hello world, from ws"
+
+    with pytest.raises(WebSocketBadStatusException) as e:
+        create_connection(f"{BASE_URL}/web_socket_slow_connect?delay=2")
+    assert e.value.status_code == 408
+
+
+def test_web_socket_handshake_timeout_slow_request(session):
+    # the headers of the upgrade request never end
+    client = socket.create_connection(("127.0.0.1", 8080))
+    client.settimeout(5)
+    client.sendall(b"GET /web_socket_slow_connect HTTP/1.1\r\nHost: 127.0.0.1\r\n")
+    started = time.time()
+    response = client.recv(1024)
+    # the server answers with a 408 or drops the connection once the timeout is over
+    assert response == b"" or response.startswith(b"HTTP/1.1 408")
+    assert time.time() - started < 3
+    client.close()
+
+
+def test_web_socket_subprotocol_required(session):
+    with pytest.raises(WebSocketBadStatusException) as e:
+        create_connection(
+            f"{BASE_URL}/web_socket_subprotocol", subprotocols=["chat.v3"]
+        )
+    assert e.value.status_code == 400
@@ -129,11 +129,19 @@ class Robyn:
 
     def _add_event_handler(self, event_type: Events, handler: Callable) -> None:
         logger.info(f"Add event {event_type} handler")
-        if event_type not in {Events.STARTUP, Events.SHUTDOWN}:
+        if event_type not in {
+            Events.STARTUP,
+            Events.SHUTDOWN,
+            Events.WEB_SOCKET_LIFECYCLE,
+        }:
             return
 
         is_async = asyncio.iscoroutinefunction(handler)
-        self.event_handlers[event_type] = FunctionInfo(handler, is_async, 0)
+        # the lifecycle handler receives the event
+        number_of_params = 1 if event_type == Events.WEB_SOCKET_LIFECYCLE else 0
+        self.event_handlers[event_type] = FunctionInfo(
+            handler, is_async, number_of_params
+        )
 
     def startup_handler(self, handler: Callable) -> None:
         self._add_event_handler(Events.STARTUP, handler)
@@ -141,6 +149,15 @@ class Robyn:
     def shutdown_handler(self, handler: Callable) -> None:
         self._add_event_handler(Events.SHUTDOWN, handler)
 
+    def web_socket_lifecycle_handler(self, handler: Callable) -> None:
+        """
+        Receives a dict for every web socket connection of the process that connects, disconnects
+        or fails, from every route. Its `event` is "connected", "disconnected" or "error", and it
+        has the `id` and the `route` of the connection, the `timestamp` in seconds since the
+        epoch, and the `detail`: the close code of a disconnection, or the error message.
+        """
+        self._add_event_handler(Events.WEB_SOCKET_LIFECYCLE, handler)
+
     def start(self, url: str = "127.0.0.1", port: int = 8080):
         """
         Starts the server
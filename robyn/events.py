@@ -4,3 +4,4 @@ from enum import Enum
 class Events(Enum):
     STARTUP = "startup"
     SHUTDOWN = "shutdown"
+    WEB_SOCKET_LIFECYCLE = "web_socket_lifecycle"
@@ -12,6 +12,9 @@ from robyn.router import GlobalMiddleware, RouteMiddleware, Route
 from robyn.types import Directory, Header
 from robyn.ws import WS
 
+# the number of seconds a process has to shut down before it is killed
+SHUTDOWN_TIMEOUT = 5
+
 
 def run_processes(
     url: str,
@@ -46,7 +49,11 @@ def run_processes(
 
     def terminating_signal_handler(_sig, _frame):
         logger.info("Terminating server!!", bold=True)
+        # give the processes a chance to close their web sockets before they are killed
+        for process in process_pool:
+            process.terminate()
         for process in process_pool:
+            process.join(SHUTDOWN_TIMEOUT)
             process.kill()
 
     signal.signal(signal.SIGINT, terminating_signal_handler)
@@ -161,6 +168,16 @@ def spawn_process(
 
     loop = initialize_event_loop()
 
+    def terminating_signal_handler(_sig, _frame):
+        # the server shuts down gracefully on a keyboard interrupt,
+        # the signals that follow must not interrupt the shutdown
+        signal.signal(signal.SIGINT, signal.SIG_IGN)
+        signal.signal(signal.SIGTERM, signal.SIG_IGN)
+        raise KeyboardInterrupt
+
+    signal.signal(signal.SIGINT, terminating_signal_handler)
+    signal.signal(signal.SIGTERM, terminating_signal_handler)
+
     server = Server()
 
     # TODO: if we remove the dot access
@@ -190,13 +207,25 @@ def spawn_process(
     if Events.SHUTDOWN in event_handlers:
         server.add_shutdown_handler(event_handlers[Events.SHUTDOWN])
 
+    if Events.WEB_SOCKET_LIFECYCLE in event_handlers:
+        server.add_web_socket_lifecycle_handler(
+            event_handlers[Events.WEB_SOCKET_LIFECYCLE]
+        )
+
     for endpoint in web_sockets:
         web_socket = web_sockets[endpoint]
+        optional_routes = {
+            socket_type: function
+            for socket_type, function in web_socket.methods.items()
+            if socket_type not in ["connect", "close", "message"]
+        }
         server.add_web_socket_route(
             endpoint,
-            web_socket.methods["connect"],
-            web_socket.methods["close"],
-            web_socket.methods["message"],
+            web_socket.methods.get("connect"),
+            web_socket.methods.get("close"),
+            web_socket.methods.get("message"),
+            optional_routes,
+            web_socket.config,
         )
 
     try:
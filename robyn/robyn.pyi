@@ -2,7 +2,7 @@ from __future__ import annotations
 
 from dataclasses import dataclass
 from enum import Enum
-from typing import Callable, Optional, Union
+from typing import Any, Callable, Coroutine, Optional, Union
 
 def get_version() -> str:
     pass
@@ -15,6 +15,321 @@ def jsonify(input_dict: dict) -> str:
         input_dict dict: response of the function
     """
 
+def broadcast(message: Union[str, bytes]) -> dict[str, int]:
+    """
+    Sends the message to every web socket connection of this process.
+    The message is converted once and shared by the connections, rather than once per connection
+
+    Attributes:
+        message Union[str, bytes]: the text message to send, or bytes for a binary message
+
+    Returns:
+        dict[str, int]: the number of connections that the message was queued for, "delivered",
+        and the number of connections that dropped it, "failed"
+    """
+
+def get_connection(id: str) -> Optional[WebSocketConnection]:
+    """
+    A handle on a single web socket connection of this process, to call its methods
+    instead of passing the id to `send_to`, `kick`, ...
+
+    Attributes:
+        id str: the id of the web socket connection
+
+    Returns:
+        Optional[WebSocketConnection]: None when there is no open connection with this id. While the "connect"
+        handler of the connection runs, what the handle is asked waits until the connection is open
+    """
+
+def send_to(id: str, message: Union[str, bytes]) -> bool:
+    """
+    Sends the message to a single web socket connection of this process
+
+    Attributes:
+        id str: the id of the web socket connection
+        message Union[str, bytes]: the text message to send, or bytes for a binary message
+
+    Returns:
+        bool: False when the backpressure policy of the connection dropped the message
+
+    Raises:
+        ValueError: if there is no open connection with this id
+    """
+
+def send_ping(id: str, payload: bytes = b"") -> None:
+    """
+    Sends a ping to a single web socket connection of this process.
+    The client answers with a pong carrying the same payload, which is passed to the "pong" handler.
+
+    Attributes:
+        id str: the id of the web socket connection
+        payload bytes: at most 125 bytes sent along with the ping
+
+    Raises:
+        ValueError: if there is no open connection with this id or the payload is too long
+    """
+
+def send_pong(id: str, payload: bytes = b"") -> None:
+    """
+    Sends a pong to a single web socket connection of this process,
+    to answer its pings on the routes created with auto_pong=False.
+
+    Attributes:
+        id str: the id of the web socket connection
+        payload bytes: at most 125 bytes sent along with the pong, usually the payload of the ping
+
+    Raises:
+        ValueError: if there is no open connection with this id or the payload is too long
+    """
+
+def join_room(id: str, room: str) -> None:
+    """
+    Adds a web socket connection to a room
+
+    Attributes:
+        id str: the id of the web socket connection
+        room str: the name of the room
+    """
+
+def leave_room(id: str, room: str) -> None:
+    """
+    Removes a web socket connection from a room
+
+    Attributes:
+        id str: the id of the web socket connection
+        room str: the name of the room
+    """
+
+def broadcast_to_room(room: str, message: Union[str, bytes]) -> dict[str, int]:
+    """
+    Sends the message to every web socket connection in the room
+
+    Attributes:
+        room str: the name of the room
+        message Union[str, bytes]: the text message to send, or bytes for a binary message
+
+    Returns:
+        dict[str, int]: the number of members that the message was queued for, "delivered",
+        and the number of members that dropped it, "failed"
+    """
+
+def broadcast_to_room_except(room: str, except_id: str, message: Union[str, bytes]) -> dict[str, int]:
+    """
+    Sends the message to every web socket connection in the room but one, e.g. the one that sent it
+
+    Attributes:
+        room str: the name of the room
+        except_id str: the id of the connection that doesn't receive the message
+        message Union[str, bytes]: the text message to send, or bytes for a binary message
+
+    Returns:
+        dict[str, int]: the number of members that the message was queued for, "delivered",
+        and the number of members that dropped it, "failed"
+    """
+
+def broadcast_sample(room: str, fraction_or_count: Union[float, int], message: Union[str, bytes]) -> dict[str, int]:
+    """
+    Sends the message to a random sample of the web socket connections in the room
+
+    Attributes:
+        room str: the name of the room
+        fraction_or_count Union[float, int]: a float from 0 to 1 is the fraction of the members
+        to send to, rounded to the nearest member, an int is the number of members to send to
+        message Union[str, bytes]: the text message to send, or bytes for a binary message
+
+    Returns:
+        dict[str, int]: the number of members that the message was queued for, "delivered",
+        and the number of members that dropped it, "failed"
+    """
+
+def broadcast_with_ack(message: dict, timeout: float = 30.0, room: Optional[str] = None) -> str:
+    """
+    Sends the JSON object to the open web socket connections of the routes with an `ack_field`, with a random id
+    in that field, and waits for the clients to send the id back in a JSON message with the same field
+
+    Attributes:
+        message dict: the JSON object to send
+        timeout float: the number of seconds that the acks are waited for, the later ones are passed to the handlers
+        room Optional[str]: only send to the members of the room
+
+    Returns:
+        str: the id of the message, for `ack_status`
+    """
+
+def ack_status(ack_id: str) -> Optional[dict]:
+    """
+    Which connections acked a message sent with `broadcast_with_ack`
+
+    Attributes:
+        ack_id str: the id returned by `broadcast_with_ack`
+
+    Returns:
+        Optional[dict]: the ids of the connections that acked the message, "acked", of the ones that didn't yet,
+        "pending", and whether the timeout is over, "expired". None for an unknown id, or a minute after the timeout
+    """
+
+def room_member_count(room: str) -> int:
+    """
+    The number of web socket connections of this process in the room, 0 when the room doesn't exist
+
+    Attributes:
+        room str: the name of the room
+    """
+
+def set_tag(id: str, label: str, value: str) -> None:
+    """
+    Attaches a label to a web socket connection of this process, e.g. plan=pro, replacing its value if any.
+    This can be called from the "connect" handler, the tags are dropped when the connection is closed
+
+    Attributes:
+        id str: the id of the web socket connection
+        label str: the name of the label
+        value str: the value of the label
+    """
+
+def remove_tag(id: str, label: str) -> None:
+    """
+    Removes a label from a web socket connection of this process
+
+    Attributes:
+        id str: the id of the web socket connection
+        label str: the name of the label
+    """
+
+def get_tags(id: str) -> dict[str, str]:
+    """
+    The labels attached to a web socket connection of this process
+
+    Attributes:
+        id str: the id of the web socket connection
+    """
+
+def broadcast_where(label: str, value: str, message: Union[str, bytes]) -> dict[str, int]:
+    """
+    Sends the message to every web socket connection of this process whose label has the value
+
+    Attributes:
+        label str: the name of the label
+        value str: the value that the label must have
+        message Union[str, bytes]: the text message to send, or bytes for a binary message
+
+    Returns:
+        dict[str, int]: the number of connections that the message was queued for, "delivered",
+        and the number of connections that dropped it, "failed"
+    """
+
+def list_connections(
+    limit: Optional[int] = None, offset: int = 0, details: bool = False
+) -> Union[list[str], list[dict[str, Any]]]:
+    """
+    The ids of the open web socket connections of this process, the oldest first
+
+    Attributes:
+        limit Optional[int]: the number of connections returned at most, all of them when it is None
+        offset int: the number of connections skipped, to page through them with the limit
+        details bool: return a dict per connection with its "id", its "tags" and its "rooms" instead
+
+    Returns:
+        Union[list[str], list[dict[str, Any]]]: the ids, or the dicts with details
+    """
+
+def kick(id: str, code: int = 1000, reason: Optional[str] = None) -> bool:
+    """
+    Closes a web socket connection of this process, the "close" handler receives the code and the reason
+
+    Attributes:
+        id str: the id of the web socket connection
+        code int: the close code sent to the client
+        reason Optional[str]: the reason sent along with the code, at most 123 bytes
+
+    Returns:
+        bool: False if there is no open connection with this id
+
+    Raises:
+        ValueError: if the code can't be sent in a close frame, see `CloseConnection`
+    """
+
+def pause(id: str) -> bool:
+    """
+    Stops passing the messages of a web socket connection to its handlers until it is resumed, e.g. while a long
+    operation runs for it. The messages are held in order, and the connection is closed with the code 1013
+    (try again later) once more than `mailbox_capacity` messages are held. The pings, pongs and close frames
+    are still handled.
+
+    Attributes:
+        id str: the id of the web socket connection
+
+    Returns:
+        bool: False if there is no open connection with this id
+    """
+
+def resume(id: str) -> bool:
+    """
+    Passes the messages held while a web socket connection was paused to its handlers, in order
+
+    Attributes:
+        id str: the id of the web socket connection
+
+    Returns:
+        bool: False if there is no open connection with this id
+    """
+
+def is_open(id: str) -> bool:
+    """
+    Whether the web socket connection is open. It is False once the client or the server started closing it
+
+    Attributes:
+        id str: the id of the connection
+    """
+
+def active_connection_count() -> int:
+    """
+    The number of web socket connections that are alive in this process, counting the ones whose upgrade is in progress
+    """
+
+def queue_depth(id: str) -> int:
+    """
+    The number of frames sent with `broadcast`, `send_to`, ... that are waiting to be sent to a web socket connection
+    Raises a ValueError when the connection is not open
+
+    Attributes:
+        id str: the id of the connection
+    """
+
+def reconnect_token(id: str) -> Optional[str]:
+    """
+    The token that the client of a web socket connection can reconnect with to get its rooms, tags and state back,
+    also sent in the `X-Reconnect-Token` header of the handshake response. None when its route has no reconnect_ttl
+
+    Attributes:
+        id str: the id of the connection
+    """
+
+def set_verbose(id: str, verbose: bool = True) -> None:
+    """
+    Logs the handlers called for a web socket connection and the frames sent to it, with the info level,
+    to debug a single client. Raises a ValueError when the connection is not open
+
+    Attributes:
+        id str: the id of the connection
+        verbose bool: False stops logging them
+    """
+
+def connection_metrics(id: Optional[str] = None) -> dict[str, Union[int, float]]:
+    """
+    The number of text and binary frames and bytes received and sent by a web socket connection
+    e.g. {"frames_received": 2, "bytes_received": 10, "frames_sent": 3, "bytes_sent": 42, "uptime": 1.5}.
+    The frames sent on the connections with permessage-deflate are also counted in "compressed_frames_sent" and
+    "uncompressed_frames_sent", and "compressed_bytes_sent" is the size of the compressed ones once compressed
+
+    Attributes:
+        id Optional[str]: the id of the connection, every connection of the process is counted when it is None.
+            The uptime in seconds is only returned for a single connection
+
+    Raises:
+        ValueError: if there is no open connection with this id
+    """
+
 class SocketHeld:
     def __init__(self, url: str, port: int):
         pass
@@ -42,6 +357,134 @@ class FunctionInfo:
     is_async: bool
     number_of_params: int
 
+@dataclass
+class AcceptConnection:
+    """
+    Returned from the "connect" handler of a web socket route to accept the connection with a subprotocol or an id.
+
+    Attributes:
+        subprotocol (Optional[str]): The subprotocol sent back to the client, it must be one that the client requested.
+        The one negotiated from the subprotocols of the route is kept when it is None
+        message (Any): Sent to the client as the first message, like the other values returned by the handler
+        id (Optional[str]): The id of the connection, passed to the other handlers and accepted by `send_to`, `kick`, ...
+        in place of its random UUID. The connection is refused with a 409 Conflict when the id is taken and the route
+        has unique_ids
+        background (Optional[Coroutine]): Runs on the event loop of the route once the connection is open, e.g. to push
+        updates with `send_to`. The task is cancelled once the connection is closed
+        headers (Optional[dict[str, str]]): Added to the handshake response, e.g. a session id assigned by the server.
+        The headers of the upgrade itself, like Upgrade or Sec-WebSocket-Accept, can't be set and fail the handshake
+    """
+
+    subprotocol: Optional[str] = None
+    message: Any = None
+    id: Optional[str] = None
+    background: Optional[Coroutine] = None
+    headers: Optional[dict[str, str]] = None
+
+class WebSocketConnection:
+    """
+    A handle on a single web socket connection, returned by `get_connection`.
+    Once the connection is closed, its methods return False instead of raising
+    """
+
+    @property
+    def id(self) -> str:
+        pass
+    def send(self, message: Union[str, bytes]) -> bool:
+        """
+        Sends the text message, or bytes for a binary message, to the client
+
+        Returns:
+            bool: False when the message was dropped, as the connection is closing or the client is too slow
+        """
+        pass
+    def ping(self, payload: Optional[bytes] = None) -> bool:
+        """
+        Pings the client, the pong is passed to the "pong" handler
+
+        Raises:
+            ValueError: if the payload is longer than 125 bytes
+        """
+        pass
+    def close(self, code: int = 1000, reason: Optional[str] = None) -> bool:
+        """
+        Closes the connection like `kick`, returns False when it is already closing
+        """
+        pass
+    def is_alive(self) -> bool:
+        """
+        Whether the connection is still open, like `is_open`
+        """
+        pass
+
+class WebSocketConfig:
+    """
+    The settings of a web socket route, built by `WS` from its params, see `WS` for their meaning.
+    They are checked when the config is built, and shared by all the connections of the route.
+    """
+
+    def __init__(
+        self,
+        mailbox_capacity: int = 16,
+        heartbeat_interval: Optional[int] = None,
+        heartbeat_timeout: int = 10,
+        max_payload_size: int = 65_536,
+        subprotocols: list[str] = [],
+        subprotocol_required: bool = False,
+        trust_forwarded_for: bool = False,
+        json_mode: bool = False,
+        idle_timeout: Optional[int] = None,
+        backpressure: Optional[str] = None,
+        rate_limit: Optional[float] = None,
+        rate_limit_burst: Optional[int] = None,
+        rate_limit_max_violations: Optional[int] = None,
+        msgpack_mode: bool = False,
+        cbor_mode: bool = False,
+        dedicated_event_loop: bool = False,
+        raw_text: bool = False,
+        auto_pong: bool = True,
+        ordered: bool = False,
+        handler_error_frame: Optional[dict] = None,
+        handshake_timeout: Optional[int] = None,
+        high_water_mark: Optional[int] = None,
+        handler_timeout: Optional[float] = None,
+        handler_timeout_action: str = "log",
+        app_heartbeat_interval: Optional[int] = None,
+        app_heartbeat_frame: str = '{"type":"ping"}',
+        app_heartbeat_pong: Optional[str] = None,
+        id_header: Optional[str] = None,
+        unique_ids: bool = False,
+        drain_timeout: Optional[float] = 5.0,
+        reconnect_ttl: Optional[int] = None,
+        slow_consumer_timeout: Optional[int] = None,
+        allowed_origins: Optional[list[str]] = None,
+        dedup_window: Optional[int] = None,
+        dedup_key: Optional[str] = "id",
+        invalid_json_reply: bool = True,
+        invalid_json_frame: Optional[dict] = None,
+        ack_field: Optional[str] = None,
+        compression: bool = False,
+        compression_threshold: int = 1024,
+        compression_dictionary: Optional[bytes] = None,
+    ) -> None:
+        pass
+
+@dataclass
+class CloseConnection:
+    """
+    Returned from a web socket handler to close the connection.
+    A ValueError is raised for the codes that can't be sent in a close frame, the ones allowed are 1000 to 1003,
+    1007 to 1014 and 3000 to 4999. 4000 to 4999 are left to the applications.
+    It is raised as well for a reason longer than 123 bytes, which wouldn't fit in the close frame.
+
+    Attributes:
+        code (int): The close code sent to the client. e.g. 1000
+        reason (Optional[str]): The reason sent along with the close code
+    """
+
+    code: int = 1000
+    reason: Optional[str] = None
+
 @dataclass
 class Url:
     """
@@ -141,12 +584,16 @@ class Server:
         pass
     def add_shutdown_handler(self, function: FunctionInfo) -> None:
         pass
+    def add_web_socket_lifecycle_handler(self, function: FunctionInfo) -> None:
+        pass
     def add_web_socket_route(
         self,
         route: str,
-        connect_route: FunctionInfo,
-        close_route: FunctionInfo,
-        message_route: FunctionInfo,
+        connect_route: Optional[FunctionInfo],
+        close_route: Optional[FunctionInfo],
+        message_route: Optional[FunctionInfo],
+        optional_routes: dict[str, FunctionInfo],
+        config: WebSocketConfig,
     ) -> None:
         pass
     def start(self, socket: SocketHeld, workers: int) -> None:
@@ -1,37 +1,472 @@
 from __future__ import annotations
 
 import asyncio
-from inspect import signature
-from typing import TYPE_CHECKING, Callable
+import json
+from dataclasses import dataclass
+from functools import partial
+from inspect import isasyncgenfunction, signature
+from typing import TYPE_CHECKING, Any, Callable, List, Optional, Union
 
-from robyn.robyn import FunctionInfo
+from robyn.robyn import (
+    AcceptConnection,
+    CloseConnection,
+    FunctionInfo,
+    WebSocketConfig,
+    WebSocketConnection,
+    ack_status,
+    active_connection_count,
+    broadcast,
+    broadcast_sample,
+    broadcast_to_room,
+    broadcast_to_room_except,
+    broadcast_where,
+    broadcast_with_ack,
+    connection_metrics,
+    get_connection,
+    get_tags,
+    is_open,
+    join_room,
+    kick,
+    leave_room,
+    list_connections,
+    pause,
+    queue_depth,
+    reconnect_token,
+    remove_tag,
+    resume,
+    room_member_count,
+    send_ping,
+    send_pong,
+    send_to,
+    set_tag,
+    set_verbose,
+)
 
 if TYPE_CHECKING:
     from robyn import Robyn
 
+__all__ = [
+    "WS",
+    "WebSocketRequest",
+    "AcceptConnection",
+    "CloseConnection",
+    "WebSocketConnection",
+    "ack_status",
+    "active_connection_count",
+    "broadcast",
+    "broadcast_sample",
+    "broadcast_to_room",
+    "broadcast_to_room_except",
+    "broadcast_where",
+    "broadcast_with_ack",
+    "connection_metrics",
+    "get_connection",
+    "get_tags",
+    "is_open",
+    "join_room",
+    "kick",
+    "leave_room",
+    "list_connections",
+    "pause",
+    "queue_depth",
+    "reconnect_token",
+    "remove_tag",
+    "resume",
+    "room_member_count",
+    "send_ping",
+    "send_pong",
+    "send_to",
+    "set_tag",
+    "set_verbose",
+]
+
+
+# the number of positional params that robyn passes to a web socket handler at most
+MAX_WEB_SOCKET_PARAMS = 13
+
+# the handlers of these events don't run in a connection and have their own params
+DETACHED_EVENT_TYPES = ["backpressure", "drained", "upgrade_failed"]
+
+# the handlers registered with on("all") receive these event types
+UNIFIED_EVENT_TYPES = {
+    "connect": "connect",
+    "message": "text",
+    "binary": "binary",
+    "ping": "ping",
+    "pong": "pong",
+    "close": "close",
+}
+
+# sent to the clients of the routes created with an app_heartbeat_interval
+DEFAULT_APP_HEARTBEAT_FRAME = {"type": "ping"}
+
+# sent to the client when a handler raises, on the routes created with handler_error_frame=True
+DEFAULT_HANDLER_ERROR_FRAME = {"error": "{message}", "type": "handler_error"}
+
+
+@dataclass
+class WebSocketRequest:
+    """
+    Passed to the web socket handlers that declare a single param named `ws`.
+
+    Attributes:
+        id (str): The id of the connection
+        message (Any): The message received, the close reason for the "close" handler
+        query_params (dict[str, str]): The query params of the handshake request
+        headers (dict[str, str]): The headers of the handshake request
+        state (dict): The dict that the handlers store the state of the connection in
+        remote_ip (Optional[str]): The ip of the client
+        cookies (dict[str, str]): The cookies of the handshake request
+        path_params (dict[str, str]): The params of the route e.g. /ws/:room -> {"room": "lobby"}
+        connected_at (float): The time of the handshake, in seconds since the epoch like `time.time()`
+        subprotocol (Optional[str]): The subprotocol agreed on during the handshake
+        route (str): The route that the connection was made to, as it was declared e.g. /ws/:room
+        queue_depth (int): The number of frames waiting to be sent to the client when the handler was called
+        extensions (list[dict]): The extensions requested in the `Sec-WebSocket-Extensions` headers, with their "name"
+        and their "params", e.g. {"name": "permessage-deflate", "params": {"client_max_window_bits": None}}
+    """
+
+    id: str
+    message: Any
+    query_params: dict
+    headers: dict
+    state: dict
+    remote_ip: Optional[str]
+    cookies: dict
+    path_params: dict
+    connected_at: float
+    subprotocol: Optional[str]
+    route: str
+    queue_depth: int
+    extensions: list
+
+    def is_open(self) -> bool:
+        """
+        Whether the connection is still open, e.g. to stop a long running handler
+        once the client is gone
+        """
+        return is_open(self.id)
+
+    def connection(self) -> Optional[WebSocketConnection]:
+        """
+        A handle on the connection, None once it is closed. In the "connect" handler
+        what it is asked waits until the connection is open, and is dropped if it is refused
+        """
+        return get_connection(self.id)
+
 
 class WS:
-    """This is the python wrapper for the web socket that will be used here."""
+    """This is the python wrapper for the web socket that will be used here.
+
+    :param mailbox_capacity int: the number of messages that can be queued for a connection
+    before they are dropped, it must be positive. Every queued message is held in memory, so raising it trades
+    memory per connection for tolerance to bursts.
+    :param heartbeat_interval Optional[int]: the number of seconds between two pings sent to the client, it must be
+    positive. Heartbeats are disabled when it is None.
+    :param heartbeat_timeout int: the number of seconds that the client has to answer a ping with a pong before the
+    connection is closed, counted from the ping
+    :param max_payload_size int: the maximum size in bytes of a message, once reassembled when it is fragmented.
+    It must be positive. Bigger messages close the connection with the code 1009 (message too big)
+    :param subprotocols Optional[List[str]]: the subprotocols that the route supports, the first one
+    requested by the client is echoed back in the `Sec-WebSocket-Protocol` header
+    :param subprotocol_required bool: refuse the handshake with a 400 when the client requests
+    none of the supported subprotocols
+    :param trust_forwarded_for bool: take the remote ip from the `X-Forwarded-For` header.
+    Only enable it behind a proxy that sets the header, clients can forge it otherwise
+    :param json_mode bool: parse the text messages as JSON and pass the parsed value to the "message" handler.
+    Messages that are not valid JSON are answered with an error message instead
+    :param idle_timeout Optional[int]: the number of seconds without a text or binary message from the client
+    after which the connection is closed with the code 1001 (going away), it must be positive. Disabled when it is None.
+    :param backpressure Optional[str]: what happens to the frames sent with `broadcast`, `send_to`, ...
+    once `mailbox_capacity` frames are waiting for a slow client. "drop_newest" drops the new frames,
+    "drop_oldest" drops the frames that waited the longest. The frames are queued without a limit when it is None.
+    :param rate_limit Optional[float]: the number of text and binary messages per second that a client can send,
+    the messages above the limit are dropped. Disabled when it is None.
+    :param rate_limit_burst Optional[int]: the number of messages that a client can send at once, `rate_limit` by default
+    :param rate_limit_max_violations Optional[int]: close the connection with the code 1008 (policy violation)
+    once this many messages were dropped. The connection is never closed when it is None.
+    :param msgpack_mode bool: decode the binary messages with msgpack before they are passed to the "binary" handler
+    and send the values returned by the handlers as msgpack binary messages
+    :param dedicated_event_loop bool: run the async handlers of the route on their own event loop, in a separate thread,
+    so that slow coroutines don't hold up the other routes. The sync handlers still run on the server workers.
+    :param raw_text bool: pass the text messages to the "message" handler as bytes, without decoding them as UTF-8,
+    including the messages that are not valid UTF-8. It can't be combined with json_mode.
+    :param auto_pong bool: answer the pings of the client automatically. When it is False, the "ping" handler
+    can answer them with send_pong.
+    :param ordered bool: run the async handlers of a connection one after the other, so that the answers
+    are sent in the order of the messages. By default, the async handlers of a connection run concurrently.
+    :param handler_error_frame Union[bool, dict, None]: send a JSON message to the client when a handler raises,
+    instead of only logging the error. True sends {"error": "{message}", "type": "handler_error"}, a dict is used as the
+    template of the message. The "{type}" and "{message}" placeholders in its strings are replaced with the type and
+    the message of the exception. The traceback is only logged.
+    :param handshake_timeout Optional[int]: the number of seconds that a client has to send the upgrade request, and
+    then that the "connect" handler has to accept the connection. The handshake fails with a 408 (request timeout) once
+    it is over. The longest handshake_timeout of the routes bounds the upgrade requests of the whole server, as the
+    route isn't known before the request is read. It must be positive, disabled when it is None.
+    :param high_water_mark Optional[int]: the "backpressure" handler is called once this many frames wait in the mailbox
+    of a connection, with the id of the connection and the number of frames. It is called again after the mailbox
+    went back below the mark. The handler is not called when it is None. The "drained" handler is called with the id of
+    the connection once its mailbox is empty again.
+    :param cbor_mode bool: like msgpack_mode, with CBOR. It can't be combined with msgpack_mode.
+    :param handler_timeout Optional[float]: the number of seconds that an async handler can run. A handler that takes
+    longer is cancelled and the error is logged. Disabled when it is None.
+    :param handler_timeout_action str: what happens to the connection once a handler was cancelled, "log" keeps it open
+    and "close" closes it with the code 1011 (internal error)
+    :param app_heartbeat_interval Optional[int]: the number of seconds between two `app_heartbeat_frame` text messages
+    sent to the client, for the clients that can't see the protocol pings, e.g. the browsers. Disabled when it is None.
+    :param app_heartbeat_frame Union[str, dict]: the heartbeat message, a dict is sent as JSON
+    :param app_heartbeat_pong Union[str, dict, None]: the message that the client answers the heartbeats with.
+    It is not passed to the handlers, and the connection is closed when the client doesn't send it within
+    `heartbeat_timeout` seconds of a heartbeat. The answers are not checked when it is None.
+    :param id_header Optional[str]: the request header that holds the id of the connection, instead of a random UUID.
+    The connect handler can also pick the id by returning an `AcceptConnection` with an id. It lets an application
+    that already has ids for its clients call `send_to`, `kick`, ... with them.
+    :param unique_ids bool: refuse the connection with a 409 Conflict when another connection of the process already
+    has its id, otherwise the id refers to the newest connection.
+    :param drain_timeout Optional[float]: the number of seconds that a connection closed by the server, e.g. with `kick`,
+    waits for the frames already queued with `broadcast`, `send_to`, ... to be sent before the close frame.
+    The frames sent once it is closing are dropped. 5 seconds by default, the queued frames are dropped when it is None.
+    :param reconnect_ttl Optional[int]: issue a reconnection token to every connection, in the `X-Reconnect-Token` header
+    of the handshake response and from `reconnect_token`. When a connection drops, its rooms, tags and state are kept for
+    this many seconds, and a client that connects with the token in the `reconnect_token` query param or the
+    `X-Reconnect-Token` header gets them back. Disabled when it is None.
+    :param slow_consumer_timeout Optional[int]: close the connections whose mailbox stays above the `high_water_mark`
+    for this many seconds with the code 1013 (try again later). It must be positive and needs a `high_water_mark`,
+    disabled when it is None.
+    :param allowed_origins Optional[List[str]]: the `Origin` headers that the handshake accepts, to protect the route
+    from cross-site web socket hijacking. A `*` matches any part of the origin, e.g. "https://*.example.com", and the
+    case is ignored. The other origins and the clients without an `Origin` header are refused with a 403 Forbidden.
+    Any origin is accepted when it is None.
+    :param dedup_window Optional[int]: pass the messages that a client sends again within this many seconds to the
+    "duplicate" handler instead of the usual ones, for the clients that resend their messages until they are acked.
+    They are dropped when no "duplicate" handler is registered. Disabled when it is None.
+    :param dedup_key Optional[str]: the field of the JSON messages that holds their id, the messages without one are
+    never duplicates. The whole message is its id when it is None.
+    :param invalid_json_reply bool: answer the messages of a json_mode route that are not valid JSON with
+    `invalid_json_frame`. They are dropped without an answer when it is False, the handler never receives them.
+    :param invalid_json_frame Optional[dict]: the template of the answer to the invalid JSON messages, like
+    handler_error_frame. "{message}" is replaced with the parse error and "{type}" with "invalid_json".
+    {"error": "invalid_json", "detail": "{message}"} by default.
+    :param ack_field Optional[str]: the field of the JSON objects sent with `broadcast_with_ack` that holds their id.
+    The clients ack them with a JSON message that has the same id in this field, the acks don't reach the handlers.
+    The connections of the route aren't sent the messages of `broadcast_with_ack` when it is None.
+    :param compression bool: negotiate the permessage-deflate extension with the clients that offer it, and compress
+    the messages both ways. The other clients get uncompressed frames.
+    :param compression_threshold int: the messages smaller than this many bytes are sent uncompressed, 1 KiB by
+    default. `connection_metrics` counts the compressed and the uncompressed frames, to tune it.
+    :param compression_dictionary Optional[bytes]: a preset dictionary of at most 32 KiB that the compression contexts
+    of both sides are primed with, e.g. the keys of the JSON messages. It isn't negotiated, the clients of the
+    route have to use the same one.
+    """
 
-    def __init__(self, robyn_object: "Robyn", endpoint: str) -> None:
+    def __init__(
+        self,
+        robyn_object: "Robyn",
+        endpoint: str,
+        mailbox_capacity: int = 16,
+        heartbeat_interval: Optional[int] = None,
+        heartbeat_timeout: int = 10,
+        max_payload_size: int = 65_536,
+        subprotocols: Optional[List[str]] = None,
+        subprotocol_required: bool = False,
+        trust_forwarded_for: bool = False,
+        json_mode: bool = False,
+        idle_timeout: Optional[int] = None,
+        backpressure: Optional[str] = None,
+        rate_limit: Optional[float] = None,
+        rate_limit_burst: Optional[int] = None,
+        rate_limit_max_violations: Optional[int] = None,
+        msgpack_mode: bool = False,
+        dedicated_event_loop: bool = False,
+        raw_text: bool = False,
+        auto_pong: bool = True,
+        ordered: bool = False,
+        handler_error_frame: Union[bool, dict, None] = None,
+        handshake_timeout: Optional[int] = None,
+        high_water_mark: Optional[int] = None,
+        cbor_mode: bool = False,
+        handler_timeout: Optional[float] = None,
+        handler_timeout_action: str = "log",
+        app_heartbeat_interval: Optional[int] = None,
+        app_heartbeat_frame: Union[str, dict] = DEFAULT_APP_HEARTBEAT_FRAME,
+        app_heartbeat_pong: Union[str, dict, None] = None,
+        id_header: Optional[str] = None,
+        unique_ids: bool = False,
+        drain_timeout: Optional[float] = 5.0,
+        reconnect_ttl: Optional[int] = None,
+        slow_consumer_timeout: Optional[int] = None,
+        allowed_origins: Optional[List[str]] = None,
+        dedup_window: Optional[int] = None,
+        dedup_key: Optional[str] = "id",
+        invalid_json_reply: bool = True,
+        invalid_json_frame: Optional[dict] = None,
+        ack_field: Optional[str] = None,
+        compression: bool = False,
+        compression_threshold: int = 1024,
+        compression_dictionary: Optional[bytes] = None,
+    ) -> None:
         self.robyn_object = robyn_object
         self.endpoint = endpoint
+        if handler_error_frame is True:
+            handler_error_frame = DEFAULT_HANDLER_ERROR_FRAME
+        # the settings are checked here, so that a mistake fails when the route is declared
+        self.config = WebSocketConfig(
+            mailbox_capacity=mailbox_capacity,
+            heartbeat_interval=heartbeat_interval,
+            heartbeat_timeout=heartbeat_timeout,
+            max_payload_size=max_payload_size,
+            subprotocols=subprotocols or [],
+            subprotocol_required=subprotocol_required,
+            trust_forwarded_for=trust_forwarded_for,
+            json_mode=json_mode,
+            idle_timeout=idle_timeout,
+            backpressure=backpressure,
+            rate_limit=rate_limit,
+            rate_limit_burst=rate_limit_burst,
+            rate_limit_max_violations=rate_limit_max_violations,
+            msgpack_mode=msgpack_mode,
+            cbor_mode=cbor_mode,
+            dedicated_event_loop=dedicated_event_loop,
+            raw_text=raw_text,
+            auto_pong=auto_pong,
+            ordered=ordered,
+            handler_error_frame=handler_error_frame or None,
+            handshake_timeout=handshake_timeout,
+            high_water_mark=high_water_mark,
+            handler_timeout=handler_timeout,
+            handler_timeout_action=handler_timeout_action,
+            app_heartbeat_interval=app_heartbeat_interval,
+            app_heartbeat_frame=self._to_text(app_heartbeat_frame),
+            app_heartbeat_pong=self._to_text(app_heartbeat_pong),
+            id_header=id_header,
+            unique_ids=unique_ids,
+            drain_timeout=drain_timeout,
+            reconnect_ttl=reconnect_ttl,
+            slow_consumer_timeout=slow_consumer_timeout,
+            allowed_origins=allowed_origins,
+            dedup_window=dedup_window,
+            dedup_key=dedup_key,
+            invalid_json_reply=invalid_json_reply,
+            invalid_json_frame=invalid_json_frame,
+            ack_field=ack_field,
+            compression=compression,
+            compression_threshold=compression_threshold,
+            compression_dictionary=compression_dictionary,
+        )
         self.methods = {}
+        # the handlers registered for each type, in order
+        self.handlers = {}
+
+    def _to_text(self, message):
+        if isinstance(message, dict):
+            return json.dumps(message, separators=(",", ":"))
+        return message
 
     def on(self, type: str) -> Callable[..., None]:
         def inner(handler):
-            if type not in ["connect", "close", "message"]:
+            if type not in [
+                "connect",
+                "close",
+                "message",
+                "binary",
+                "ping",
+                "pong",
+                "error",
+                "before_close",
+                "backpressure",
+                "drained",
+                "upgrade_failed",
+                "duplicate",
+                "all",
+            ]:
                 raise Exception(f"Socket method {type} does not exist")
+            elif type == "all":
+                # the handler receives the kind of the event before the usual params
+                for event, event_type in UNIFIED_EVENT_TYPES.items():
+                    self._register(event, partial(handler, event_type))
             else:
-                self.methods[type] = FunctionInfo(
-                    handler, self._is_async(handler), self._num_params(handler)
-                )
-                self.robyn_object.add_web_socket(self.endpoint, self)
+                self._register(type, handler)
+            self.robyn_object.add_web_socket(self.endpoint, self)
 
         return inner
 
+    def _register(self, type, handler):
+        handlers = self.handlers.setdefault(type, [])
+        handlers.append(handler)
+        if len(handlers) == 1:
+            self.methods[type] = FunctionInfo(*self._link(type, handler))
+        else:
+            self.methods[type] = self._chain(type, handlers)
+
+    def _link(self, type, handler):
+        """The handler, whether it is async and the number of params robyn passes to it"""
+        # the "backpressure", "drained" and "upgrade_failed" handlers run outside of
+        # the connection, with their own params only
+        if type not in DETACHED_EVENT_TYPES and self._takes_request(handler):
+            return (
+                self._request_handler(handler),
+                self._is_async(handler),
+                MAX_WEB_SOCKET_PARAMS,
+            )
+        return handler, self._is_async(handler), self._num_params(handler)
+
+    def _chain(self, type, handlers):
+        # the handlers run in the order they were registered, until one of them returns
+        # something else than None. That value is the answer to the message
+        if any(isasyncgenfunction(self._unwrap(handler)) for handler in handlers):
+            raise Exception(
+                f"An async generator can't be chained with other {type} handlers"
+            )
+        links = [self._link(type, handler) for handler in handlers]
+        number_of_params = max(params for _, _, params in links)
+
+        if any(is_async for _, is_async, _ in links):
+
+            async def async_chain(*args):
+                for handler, is_async, params in links:
+                    output = handler(*args[:params])
+                    if is_async:
+                        output = await output
+                    if output is not None:
+                        return output
+                return None
+
+            return FunctionInfo(async_chain, True, number_of_params)
+
+        def chain(*args):
+            for handler, _, params in links:
+                output = handler(*args[:params])
+                if output is not None:
+                    return output
+            return None
+
+        return FunctionInfo(chain, False, number_of_params)
+
     def _num_params(self, handler):
         return len(signature(handler).parameters)
 
+    def _takes_request(self, handler):
+        return list(signature(handler).parameters) == ["ws"]
+
+    def _request_handler(self, handler):
+        # robyn passes every param positionally, they are bundled in a single object here
+        if self._is_async(handler):
+
+            async def async_inner(*args):
+                return await handler(WebSocketRequest(*args))
+
+            return async_inner
+
+        def inner(*args):
+            return handler(WebSocketRequest(*args))
+
+        return inner
+
     def _is_async(self, handler):
-        return asyncio.iscoroutinefunction(handler)
+        return asyncio.iscoroutinefunction(self._unwrap(handler))
+
+    def _unwrap(self, handler):
+        # python < 3.8 doesn't look through partials when telling if a function is async
+        while isinstance(handler, partial):
+            handler = handler.func
+        return handler
@@ -0,0 +1,82 @@
+//! The acknowledgements of the messages sent with `broadcast_with_ack`.
+//! Each message carries a random id that the clients send back in the `ack_field` of their route,
+//! and the connections it was queued for are pending until they ack it or it expires.
+//! Once expired, the acks are ignored and the outcome is kept for `RETENTION` to be read.
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use uuid::Uuid;
+
+/// how long the outcome of an expired message can still be read
+const RETENTION: Duration = Duration::from_secs(60);
+
+struct Tracked {
+    expires_at: Instant,
+    /// the connections that didn't ack yet, with the id that the handlers know them by
+    pending: HashMap<Uuid, String>,
+    acked: Vec<String>,
+}
+
+/// Who acked a message, and who didn't
+pub struct AckStatus {
+    pub acked: Vec<String>,
+    pub pending: Vec<String>,
+    pub expired: bool,
+}
+
+fn tracked() -> &'static DashMap<String, Tracked> {
+    static TRACKED: OnceLock<DashMap<String, Tracked>> = OnceLock::new();
+    TRACKED.get_or_init(DashMap::new)
+}
+
+/// Waits for the acks of the recipients, under a new id that is returned.
+/// It is tracked before the message is sent so that an early ack isn't missed
+pub fn track(recipients: HashMap<Uuid, String>, timeout: Duration) -> String {
+    let now = Instant::now();
+    tracked().retain(|_, tracked| tracked.expires_at + RETENTION > now);
+    let ack_id = Uuid::new_v4().simple().to_string();
+    tracked().insert(
+        ack_id.clone(),
+        Tracked {
+            expires_at: now + timeout,
+            pending: recipients,
+            acked: Vec::new(),
+        },
+    );
+    ack_id
+}
+
+/// Stops waiting for a recipient that the message couldn't be queued for
+pub fn forget(ack_id: &str, id: &Uuid) {
+    if let Some(mut tracked) = tracked().get_mut(ack_id) {
+        tracked.pending.remove(id);
+    }
+}
+
+/// Records the ack of the connection, returns false when it wasn't waited for
+pub fn acknowledge(ack_id: &str, id: &Uuid) -> bool {
+    let Some(mut tracked) = tracked().get_mut(ack_id) else {
+        return false;
+    };
+    if tracked.expires_at <= Instant::now() {
+        return false;
+    }
+    match tracked.pending.remove(id) {
+        Some(websocket_id) => {
+            tracked.acked.push(websocket_id);
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn status(ack_id: &str) -> Option<AckStatus> {
+    tracked().get(ack_id).map(|tracked| AckStatus {
+        acked: tracked.acked.clone(),
+        pending: tracked.pending.values().cloned().collect(),
+        expired: tracked.expires_at <= Instant::now(),
+    })
+}
@@ -0,0 +1,299 @@
+//! A minimal CBOR codec for the web socket CBOR mode.
+//! It covers the types that have a python equivalent: null, booleans, integers, floats,
+//! text and byte strings, arrays and maps, including the ones with an indefinite length.
+//! Tags are ignored, the value that they wrap is decoded in their place.
+
+use std::convert::TryInto;
+
+use pyo3::exceptions::{PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{
+    PyBool, PyByteArray, PyBytes, PyDict, PyFloat, PyList, PyLong, PyString, PyTuple,
+};
+
+/// nested arrays and maps deeper than this are refused, so that a message can't exhaust the stack
+const MAX_DEPTH: usize = 256;
+
+const MAJOR_UNSIGNED: u8 = 0;
+const MAJOR_NEGATIVE: u8 = 1;
+const MAJOR_BYTES: u8 = 2;
+const MAJOR_TEXT: u8 = 3;
+const MAJOR_ARRAY: u8 = 4;
+const MAJOR_MAP: u8 = 5;
+const MAJOR_TAG: u8 = 6;
+
+/// the additional information of the items that have an indefinite length
+const INDEFINITE: u8 = 31;
+const BREAK: u8 = 0xff;
+
+pub fn encode(value: &PyAny) -> PyResult<Vec<u8>> {
+    let mut buf = Vec::new();
+    encode_into(value, &mut buf, 0)?;
+    Ok(buf)
+}
+
+fn encode_into(value: &PyAny, buf: &mut Vec<u8>, depth: usize) -> PyResult<()> {
+    if depth > MAX_DEPTH {
+        return Err(PyValueError::new_err("The value is nested too deeply"));
+    }
+    if value.is_none() {
+        buf.push(0xf6);
+    } else if let Ok(boolean) = value.downcast::<PyBool>() {
+        buf.push(if boolean.is_true() { 0xf5 } else { 0xf4 });
+    } else if value.is_instance_of::<PyLong>() {
+        let int = value
+            .extract::<i128>()
+            .ok()
+            .filter(|int| (-(1_i128 << 64)..(1_i128 << 64)).contains(int));
+        match int {
+            Some(int) if int >= 0 => encode_header(MAJOR_UNSIGNED, int as u64, buf),
+            // -1 - n is encoded as n
+            Some(int) => encode_header(MAJOR_NEGATIVE, (-1 - int) as u64, buf),
+            None => {
+                return Err(PyValueError::new_err(
+                    "Cannot encode an integer that doesn't fit in 64 bits with CBOR",
+                ))
+            }
+        }
+    } else if let Ok(float) = value.downcast::<PyFloat>() {
+        buf.push(0xfb);
+        buf.extend_from_slice(&float.value().to_be_bytes());
+    } else if let Ok(string) = value.downcast::<PyString>() {
+        let string = string.to_str()?;
+        encode_header(MAJOR_TEXT, string.len() as u64, buf);
+        buf.extend_from_slice(string.as_bytes());
+    } else if let Ok(bin) = value.downcast::<PyBytes>() {
+        encode_header(MAJOR_BYTES, bin.as_bytes().len() as u64, buf);
+        buf.extend_from_slice(bin.as_bytes());
+    } else if let Ok(bin) = value.downcast::<PyByteArray>() {
+        let bin = bin.to_vec();
+        encode_header(MAJOR_BYTES, bin.len() as u64, buf);
+        buf.extend_from_slice(&bin);
+    } else if value.is_instance_of::<PyList>() || value.is_instance_of::<PyTuple>() {
+        encode_header(MAJOR_ARRAY, value.len()? as u64, buf);
+        for item in value.iter()? {
+            encode_into(item?, buf, depth + 1)?;
+        }
+    } else if let Ok(dict) = value.downcast::<PyDict>() {
+        encode_header(MAJOR_MAP, dict.len() as u64, buf);
+        for (key, value) in dict {
+            encode_into(key, buf, depth + 1)?;
+            encode_into(value, buf, depth + 1)?;
+        }
+    } else {
+        return Err(PyTypeError::new_err(format!(
+            "Cannot encode {} with CBOR",
+            value.get_type().name()?
+        )));
+    }
+    Ok(())
+}
+
+/// Writes the major type of an item along with its argument in the shortest form,
+/// the argument is the value of an integer or the length of the other types
+fn encode_header(major: u8, argument: u64, buf: &mut Vec<u8>) {
+    let major = major << 5;
+    match argument {
+        0..=23 => buf.push(major | argument as u8),
+        24..=0xff => buf.extend_from_slice(&[major | 24, argument as u8]),
+        0x100..=0xffff => {
+            buf.push(major | 25);
+            buf.extend_from_slice(&(argument as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            buf.push(major | 26);
+            buf.extend_from_slice(&(argument as u32).to_be_bytes());
+        }
+        _ => {
+            buf.push(major | 27);
+            buf.extend_from_slice(&argument.to_be_bytes());
+        }
+    }
+}
+
+pub fn decode(py: Python, bin: &[u8]) -> PyResult<PyObject> {
+    let mut decoder = Decoder { bin, pos: 0 };
+    let value = decoder.decode(py, 0)?;
+    if decoder.pos != bin.len() {
+        return Err(PyValueError::new_err("Unexpected data after the value"));
+    }
+    Ok(value)
+}
+
+struct Decoder<'a> {
+    bin: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> PyResult<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.bin.len())
+            .ok_or_else(|| PyValueError::new_err("Unexpected end of data"))?;
+        let bytes = &self.bin[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> PyResult<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    /// Consumes the break that ends the items with an indefinite length
+    fn at_break(&mut self) -> PyResult<bool> {
+        match self.bin.get(self.pos) {
+            Some(&BREAK) => {
+                self.pos += 1;
+                Ok(true)
+            }
+            Some(_) => Ok(false),
+            None => Err(PyValueError::new_err("Unexpected end of data")),
+        }
+    }
+
+    /// Reads the argument that follows the initial byte, None for an indefinite length
+    fn argument(&mut self, info: u8) -> PyResult<Option<u64>> {
+        Ok(Some(match info {
+            0..=23 => info as u64,
+            24 => self.take_array::<1>()?[0] as u64,
+            25 => u16::from_be_bytes(self.take_array()?) as u64,
+            26 => u32::from_be_bytes(self.take_array()?) as u64,
+            27 => u64::from_be_bytes(self.take_array()?),
+            INDEFINITE => return Ok(None),
+            _ => {
+                return Err(PyValueError::new_err(format!(
+                    "Reserved CBOR additional information {info}"
+                )))
+            }
+        }))
+    }
+
+    fn length(&mut self, info: u8) -> PyResult<Option<usize>> {
+        self.argument(info)?
+            .map(|len| {
+                len.try_into()
+                    .map_err(|_| PyValueError::new_err("The CBOR item is too long"))
+            })
+            .transpose()
+    }
+
+    fn decode(&mut self, py: Python, depth: usize) -> PyResult<PyObject> {
+        if depth > MAX_DEPTH {
+            return Err(PyValueError::new_err("The value is nested too deeply"));
+        }
+        let initial = self.take_array::<1>()?[0];
+        let (major, info) = (initial >> 5, initial & 0x1f);
+        Ok(match major {
+            MAJOR_UNSIGNED | MAJOR_NEGATIVE => {
+                let argument = self
+                    .argument(info)?
+                    .ok_or_else(|| PyValueError::new_err("An integer can't be indefinite"))?;
+                if major == MAJOR_UNSIGNED {
+                    argument.to_object(py)
+                } else {
+                    (-1 - argument as i128).to_object(py)
+                }
+            }
+            MAJOR_BYTES => PyBytes::new(py, &self.decode_string(MAJOR_BYTES, info)?).into(),
+            MAJOR_TEXT => {
+                let string = String::from_utf8(self.decode_string(MAJOR_TEXT, info)?)
+                    .map_err(|_| PyValueError::new_err("Invalid UTF-8 in a string"))?;
+                string.to_object(py)
+            }
+            MAJOR_ARRAY => {
+                let list = PyList::empty(py);
+                match self.length(info)? {
+                    Some(len) => {
+                        for _ in 0..len {
+                            list.append(self.decode(py, depth + 1)?)?;
+                        }
+                    }
+                    None => {
+                        while !self.at_break()? {
+                            list.append(self.decode(py, depth + 1)?)?;
+                        }
+                    }
+                }
+                list.into()
+            }
+            MAJOR_MAP => {
+                let dict = PyDict::new(py);
+                let len = self.length(info)?;
+                let mut read = 0;
+                loop {
+                    let done = match len {
+                        Some(len) => read == len,
+                        None => self.at_break()?,
+                    };
+                    if done {
+                        break;
+                    }
+                    let key = self.decode(py, depth + 1)?;
+                    let value = self.decode(py, depth + 1)?;
+                    dict.set_item(key, value)?;
+                    read += 1;
+                }
+                dict.into()
+            }
+            MAJOR_TAG => {
+                self.argument(info)?
+                    .ok_or_else(|| PyValueError::new_err("A tag can't be indefinite"))?;
+                self.decode(py, depth + 1)?
+            }
+            // the simple values and the floats
+            _ => self.decode_simple(py, info)?,
+        })
+    }
+
+    /// The chunks of an indefinite string are definite strings of the same type
+    fn decode_string(&mut self, major: u8, info: u8) -> PyResult<Vec<u8>> {
+        if let Some(len) = self.length(info)? {
+            return Ok(self.take(len)?.to_vec());
+        }
+        let mut string = Vec::new();
+        while !self.at_break()? {
+            let initial = self.take_array::<1>()?[0];
+            let len = if initial >> 5 == major {
+                self.length(initial & 0x1f)?
+            } else {
+                None
+            };
+            let len =
+                len.ok_or_else(|| PyValueError::new_err("Invalid chunk in an indefinite string"))?;
+            string.extend_from_slice(self.take(len)?);
+        }
+        Ok(string)
+    }
+
+    fn decode_simple(&mut self, py: Python, info: u8) -> PyResult<PyObject> {
+        Ok(match info {
+            20 => false.to_object(py),
+            21 => true.to_object(py),
+            // null and undefined
+            22 | 23 => py.None(),
+            25 => f16_to_f64(u16::from_be_bytes(self.take_array()?)).to_object(py),
+            26 => f32::from_be_bytes(self.take_array()?).to_object(py),
+            27 => f64::from_be_bytes(self.take_array()?).to_object(py),
+            _ => {
+                return Err(PyValueError::new_err(format!(
+                    "Unsupported CBOR simple value {info}"
+                )))
+            }
+        })
+    }
+}
+
+/// Half precision floats are only decoded, the encoder always writes 64 bits floats
+fn f16_to_f64(half: u16) -> f64 {
+    let sign = if half & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((half >> 10) & 0x1f) as i32;
+    let mantissa = (half & 0x3ff) as f64;
+    sign * match exponent {
+        0 => mantissa * 2f64.powi(-24),
+        31 if mantissa == 0.0 => f64::INFINITY,
+        31 => f64::NAN,
+        _ => (1.0 + mantissa / 1024.0) * 2f64.powi(exponent - 15),
+    }
+}
@@ -0,0 +1,740 @@
+//! The permessage-deflate extension of RFC 7692, for the web socket routes created with `compression`.
+//! actix's codec ignores the RSV1 bit that marks the compressed messages, so the frames are
+//! rewritten on both sides of it, like the raw text frames. The compressed messages of the client
+//! reach the actor as binary frames without the bit, and the actor sends its compressed messages
+//! as binary frames that get the bit and their opcode back on their way out.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::error::PayloadError;
+use actix_web::web::Bytes;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use futures::Stream;
+
+use crate::raw_text::{FrameHeaders, OPCODE_BINARY, OPCODE_MASK, OPCODE_TEXT};
+
+pub const EXTENSION: &str = "permessage-deflate";
+const RSV1: u8 = 0x40;
+/// the end of the sync flush, that the sender strips from every message
+const TAIL: [u8; 4] = [0, 0, 0xff, 0xff];
+/// the only window that the compressor uses, 32 KiB
+const MAX_WINDOW_BITS: &str = "15";
+/// a preset dictionary can't be larger than the window
+pub const MAX_DICTIONARY_SIZE: usize = 32 * 1024;
+
+/// For each of the data messages, in their order, whether it is compressed and if so whether it
+/// is a text message
+pub type Compressed = Rc<RefCell<VecDeque<Option<bool>>>>;
+
+/// The params of the extension agreed on during the handshake
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Params {
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+    /// the offer asked for the window of the server, the response has to confirm it
+    pub server_max_window_bits: bool,
+}
+
+impl Params {
+    /// The params of an offer of the client, None when the offer can't be accepted as it is
+    pub fn accept(offer: &[(String, Option<String>)]) -> Option<Self> {
+        let mut params = Self::default();
+        let mut client_max_window_bits = false;
+        for (name, value) in offer {
+            let (seen, valid) = match name.as_str() {
+                "server_no_context_takeover" => (
+                    std::mem::replace(&mut params.server_no_context_takeover, true),
+                    value.is_none(),
+                ),
+                "client_no_context_takeover" => (
+                    std::mem::replace(&mut params.client_no_context_takeover, true),
+                    value.is_none(),
+                ),
+                // miniz always compresses with the largest window, so a smaller one is refused
+                "server_max_window_bits" => (
+                    std::mem::replace(&mut params.server_max_window_bits, true),
+                    value.as_deref() == Some(MAX_WINDOW_BITS),
+                ),
+                // the inflater reads any window, the client keeps the one it picked
+                "client_max_window_bits" => (
+                    std::mem::replace(&mut client_max_window_bits, true),
+                    value.as_deref().is_none_or(|bits| {
+                        bits.parse::<u8>()
+                            .is_ok_and(|bits| (8..=15).contains(&bits))
+                    }),
+                ),
+                _ => (false, false),
+            };
+            if seen || !valid {
+                return None;
+            }
+        }
+        Some(params)
+    }
+
+    /// The value of the `Sec-WebSocket-Extensions` header of the handshake response
+    pub fn response_header(&self) -> String {
+        let mut header = EXTENSION.to_string();
+        if self.server_no_context_takeover {
+            header.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            header.push_str("; client_no_context_takeover");
+        }
+        if self.server_max_window_bits {
+            header.push_str("; server_max_window_bits=");
+            header.push_str(MAX_WINDOW_BITS);
+        }
+        header
+    }
+}
+
+/// Why a message of the client couldn't be decompressed
+#[derive(Debug)]
+pub enum InflateError {
+    /// the message is larger than the `max_payload_size` once decompressed
+    TooBig,
+    Corrupt(String),
+}
+
+/// The preset dictionary of a route. miniz can't be handed a dictionary, so the contexts are
+/// primed with it instead: compressing the dictionary leaves it in the window of the compressor,
+/// and inflating what it compressed to leaves it in the window of the decompressor, the way
+/// `deflateSetDictionary` and `inflateSetDictionary` do on the client
+struct Dictionary {
+    bytes: Bytes,
+    /// the dictionary once compressed, to prime the decompressor with
+    compressed: Vec<u8>,
+}
+
+/// The compression contexts of a connection. They are kept from one message to the next, unless
+/// the no context takeover params were agreed on
+pub struct Deflate {
+    params: Params,
+    /// the messages sent are compressed from this many bytes
+    threshold: usize,
+    dictionary: Option<Dictionary>,
+    compressor: RefCell<Compress>,
+    decompressor: RefCell<Decompress>,
+    received: Compressed,
+    sent: Compressed,
+}
+
+/// Compresses the whole payload and flushes it, the output ends with the `TAIL`
+fn compress_sync(compressor: &mut Compress, payload: &[u8]) -> Vec<u8> {
+    let start = compressor.total_in();
+    let mut output = Vec::with_capacity(payload.len() / 2 + 64);
+    loop {
+        let consumed = (compressor.total_in() - start) as usize;
+        compressor
+            .compress_vec(&payload[consumed..], &mut output, FlushCompress::Sync)
+            .expect("the compression of a buffer in memory can't fail");
+        let consumed = (compressor.total_in() - start) as usize;
+        // the flush is done once it didn't fill the whole output
+        if consumed == payload.len() && output.len() < output.capacity() {
+            break;
+        }
+        output.reserve(output.capacity().max(64));
+    }
+    output
+}
+
+impl Deflate {
+    /// The contexts of a connection, primed with the preset dictionary of the route if it has one
+    pub fn new(params: Params, threshold: usize, dictionary: Option<Bytes>) -> Self {
+        let mut compressor = Compress::new(Compression::default(), false);
+        let decompressor = Decompress::new(false);
+        let dictionary = dictionary.map(|bytes| {
+            let compressed = compress_sync(&mut compressor, &bytes);
+            Dictionary { bytes, compressed }
+        });
+        let deflate = Self {
+            params,
+            threshold,
+            dictionary,
+            compressor: RefCell::new(compressor),
+            decompressor: RefCell::new(decompressor),
+            received: Compressed::default(),
+            sent: Compressed::default(),
+        };
+        deflate.prime_decompressor(&mut deflate.decompressor.borrow_mut());
+        deflate
+    }
+
+    /// Resets the compressor to the preset dictionary, or to an empty window without one
+    fn reset_compressor(&self, compressor: &mut Compress) {
+        compressor.reset();
+        if let Some(dictionary) = &self.dictionary {
+            compress_sync(compressor, &dictionary.bytes);
+        }
+    }
+
+    /// Resets the decompressor to the preset dictionary, or to an empty window without one
+    fn reset_decompressor(&self, decompressor: &mut Decompress) {
+        decompressor.reset(false);
+        self.prime_decompressor(decompressor);
+    }
+
+    fn prime_decompressor(&self, decompressor: &mut Decompress) {
+        let Some(dictionary) = &self.dictionary else {
+            return;
+        };
+        let mut output = Vec::with_capacity(dictionary.bytes.len() + 64);
+        decompressor
+            .decompress_vec(&dictionary.compressed, &mut output, FlushDecompress::Sync)
+            .expect("the dictionary was compressed by miniz");
+        debug_assert_eq!(output, dictionary.bytes);
+    }
+
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    /// The kinds of the messages of the client, for `InboundFrames`
+    pub fn received(&self) -> Compressed {
+        self.received.clone()
+    }
+
+    /// The kinds of the messages of the actor, for `OutboundFrames`
+    pub fn sent(&self) -> Compressed {
+        self.sent.clone()
+    }
+
+    /// Whether the next data message of the client is compressed and if so whether it is text
+    pub fn next_received(&self) -> Option<bool> {
+        self.received.borrow_mut().pop_front().flatten()
+    }
+
+    /// The payload of a message to send once compressed, None when it is sent as it is.
+    /// Every text and binary frame of the actor has to go through it, in order
+    pub fn compress(&self, payload: &[u8], text: bool) -> Option<Bytes> {
+        if payload.len() < self.threshold {
+            self.sent.borrow_mut().push_back(None);
+            return None;
+        }
+        let mut compressor = self.compressor.borrow_mut();
+        let mut output = compress_sync(&mut compressor, payload);
+        if output.ends_with(&TAIL) {
+            output.truncate(output.len() - TAIL.len());
+        }
+        if self.params.server_no_context_takeover {
+            self.reset_compressor(&mut compressor);
+        }
+        self.sent.borrow_mut().push_back(Some(text));
+        Some(output.into())
+    }
+
+    /// Decompresses a whole message of the client, which can't be larger than `max_size`
+    pub fn decompress(&self, payload: &[u8], max_size: usize) -> Result<Bytes, InflateError> {
+        let mut decompressor = self.decompressor.borrow_mut();
+        let input = [payload, &TAIL].concat();
+        let start = decompressor.total_in();
+        let mut output = Vec::with_capacity((payload.len() * 2).clamp(64, max_size + 1));
+        loop {
+            let consumed = (decompressor.total_in() - start) as usize;
+            let status = decompressor
+                .decompress_vec(&input[consumed..], &mut output, FlushDecompress::Sync)
+                .map_err(|e| InflateError::Corrupt(e.to_string()))?;
+            if output.len() > max_size {
+                return Err(InflateError::TooBig);
+            }
+            let consumed = (decompressor.total_in() - start) as usize;
+            // a final block ends the stream, the next message starts a new one
+            if status == Status::StreamEnd {
+                self.reset_decompressor(&mut decompressor);
+                break;
+            }
+            if consumed == input.len() && output.len() < output.capacity() {
+                break;
+            }
+            if output.len() < output.capacity() && status == Status::BufError {
+                return Err(InflateError::Corrupt("truncated message".to_string()));
+            }
+            output.reserve(output.capacity().min(max_size + 1 - output.len()).max(1));
+        }
+        if self.params.client_no_context_takeover {
+            self.reset_decompressor(&mut decompressor);
+        }
+        Ok(output.into())
+    }
+}
+
+/// The frames of the client. The compressed messages lose their RSV1 bit and become binary
+/// messages, as they are not valid UTF-8, and the kind of every data message is recorded
+pub struct InboundFrames<S> {
+    stream: S,
+    received: Compressed,
+    headers: FrameHeaders,
+}
+
+impl<S> InboundFrames<S> {
+    pub fn new(stream: S, received: Compressed) -> Self {
+        Self {
+            stream,
+            received,
+            headers: FrameHeaders::default(),
+        }
+    }
+}
+
+impl<S> Stream for InboundFrames<S>
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+{
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.stream).poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                let received = &this.received;
+                // only the first frame of a message carries the bit
+                let chunk = this.headers.rewrite(chunk, |byte| {
+                    let text = match byte & OPCODE_MASK {
+                        OPCODE_TEXT => true,
+                        OPCODE_BINARY => false,
+                        _ => return byte,
+                    };
+                    if byte & RSV1 == 0 {
+                        received.borrow_mut().push_back(None);
+                        return byte;
+                    }
+                    received.borrow_mut().push_back(Some(text));
+                    (byte & !RSV1 & !OPCODE_MASK) | OPCODE_BINARY
+                });
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            poll => poll,
+        }
+    }
+}
+
+/// The frames that the actor sends. Its compressed messages get the RSV1 bit and their opcode back
+pub struct OutboundFrames {
+    body: BoxBody,
+    sent: Compressed,
+    headers: FrameHeaders,
+}
+
+impl OutboundFrames {
+    pub fn new(body: BoxBody, sent: Compressed) -> Self {
+        Self {
+            body,
+            sent,
+            headers: FrameHeaders::default(),
+        }
+    }
+}
+
+impl MessageBody for OutboundFrames {
+    type Error = Box<dyn Error>;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.body).poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                let sent = &this.sent;
+                let chunk = this.headers.rewrite(chunk, |byte| {
+                    if !matches!(byte & OPCODE_MASK, OPCODE_TEXT | OPCODE_BINARY) {
+                        return byte;
+                    }
+                    match sent.borrow_mut().pop_front().flatten() {
+                        Some(true) => (byte & !OPCODE_MASK) | RSV1 | OPCODE_TEXT,
+                        Some(false) => byte | RSV1,
+                        None => byte,
+                    }
+                });
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            poll => poll,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_client::TestClient;
+    use crate::web_socket_connection::connection_metrics;
+    use actix_http::ws::{Frame, Message, OpCode, Parser};
+    use actix_web::http::header;
+    use actix_web::web::BytesMut;
+    use pyo3::Python;
+    use std::collections::HashMap;
+
+    const OFFER: (&str, &str) = ("Sec-WebSocket-Extensions", "permessage-deflate");
+    const REPEAT: &str = "def message(ws_id, msg):\n    return msg * 20\n";
+
+    /// A message compressed by the client, split in frames of `frame_size` bytes
+    fn compressed_frames(client: &Deflate, message: &str, frame_size: usize) -> Bytes {
+        let payload = client.compress(message.as_bytes(), true).unwrap();
+        let chunks: Vec<&[u8]> = payload.chunks(frame_size).collect();
+        let mut frames = BytesMut::new();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let opcode = if i == 0 {
+                OpCode::Text
+            } else {
+                OpCode::Continue
+            };
+            let start = frames.len();
+            Parser::write_message(&mut frames, chunk, opcode, i == chunks.len() - 1, true);
+            if i == 0 {
+                frames[start] |= RSV1;
+            }
+        }
+        frames.freeze()
+    }
+
+    fn offer(params: &[(&str, Option<&str>)]) -> Vec<(String, Option<String>)> {
+        params
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.map(str::to_string)))
+            .collect()
+    }
+
+    #[test]
+    fn offers_are_accepted_when_their_params_can_be_honoured() {
+        assert_eq!(Params::accept(&[]), Some(Params::default()));
+        let params = Params::accept(&offer(&[
+            ("client_max_window_bits", None),
+            ("server_no_context_takeover", None),
+        ]))
+        .unwrap();
+        assert_eq!(
+            params.response_header(),
+            "permessage-deflate; server_no_context_takeover"
+        );
+        let params = Params::accept(&offer(&[("server_max_window_bits", Some("15"))])).unwrap();
+        assert_eq!(
+            params.response_header(),
+            "permessage-deflate; server_max_window_bits=15"
+        );
+
+        assert!(Params::accept(&offer(&[("server_max_window_bits", Some("10"))])).is_none());
+        assert!(Params::accept(&offer(&[("client_max_window_bits", Some("16"))])).is_none());
+        assert!(Params::accept(&offer(&[("server_no_context_takeover", Some("1"))])).is_none());
+        assert!(Params::accept(&offer(&[
+            ("client_no_context_takeover", None),
+            ("client_no_context_takeover", None),
+        ]))
+        .is_none());
+        assert!(Params::accept(&offer(&[("unknown", None)])).is_none());
+    }
+
+    #[test]
+    fn messages_survive_the_round_trip() {
+        for params in [
+            Params::default(),
+            Params {
+                server_no_context_takeover: true,
+                client_no_context_takeover: true,
+                server_max_window_bits: false,
+            },
+        ] {
+            // the server reads what it sent, as if it was its own client
+            let deflate = Deflate::new(params, 0, None);
+            for message in ["hello", "hello hello hello", ""] {
+                let compressed = deflate.compress(message.as_bytes(), true).unwrap();
+                assert!(!compressed.ends_with(&TAIL));
+                let decompressed = deflate.decompress(&compressed, 1024).unwrap();
+                assert_eq!(decompressed, message.as_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn small_messages_are_sent_as_they_are() {
+        let deflate = Deflate::new(Params::default(), 10, None);
+        assert!(deflate.compress(b"short", true).is_none());
+        assert!(deflate.compress(b"long enough", false).is_some());
+        assert_eq!(*deflate.sent.borrow(), [None, Some(false)]);
+    }
+
+    #[test]
+    fn decompressed_messages_are_bounded() {
+        let deflate = Deflate::new(Params::default(), 0, None);
+        let compressed = deflate.compress(&[b'a'; 4096], false).unwrap();
+        let receiver = Deflate::new(Params::default(), 0, None);
+        assert!(matches!(
+            receiver.decompress(&compressed, 1024),
+            Err(InflateError::TooBig)
+        ));
+        assert!(matches!(
+            Deflate::new(Params::default(), 0, None).decompress(b"\xff\xff\xff", 1024),
+            Err(InflateError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn a_preset_dictionary_primes_both_sides() {
+        let dictionary = Bytes::from_static(br#"{"type": "quote", "symbol": "", "bid": "#);
+        let message = br#"{"type": "quote", "symbol": "ACME", "bid": 42}"#;
+        for params in [
+            Params::default(),
+            Params {
+                server_no_context_takeover: true,
+                client_no_context_takeover: true,
+                server_max_window_bits: false,
+            },
+        ] {
+            let deflate = Deflate::new(params.clone(), 0, Some(dictionary.clone()));
+            let receiver = Deflate::new(params.clone(), 0, Some(dictionary.clone()));
+            let without = Deflate::new(params, 0, None)
+                .compress(message, true)
+                .unwrap();
+            // the contexts are primed again after each reset
+            for _ in 0..3 {
+                let compressed = deflate.compress(message, true).unwrap();
+                assert!(compressed.len() < without.len());
+                let decompressed = receiver.decompress(&compressed, 1024).unwrap();
+                assert_eq!(decompressed, &message[..]);
+            }
+        }
+        // the clients without the dictionary can't read the messages
+        let deflate = Deflate::new(Params::default(), 0, Some(dictionary));
+        let compressed = deflate.compress(message, true).unwrap();
+        let receiver = Deflate::new(Params::default(), 0, None);
+        assert!(!matches!(
+            receiver.decompress(&compressed, 1024),
+            Ok(decompressed) if decompressed == message[..]
+        ));
+    }
+
+    #[actix_web::test]
+    async fn clients_that_offer_it_get_compressed_frames() {
+        let mut client = TestClient::connect_with_headers(
+            REPEAT,
+            "compression=True, compression_threshold=0",
+            &[OFFER],
+        )
+        .await;
+        assert_eq!(
+            client
+                .response
+                .headers()
+                .get(header::SEC_WEBSOCKET_EXTENSIONS),
+            Some(&header::HeaderValue::from_static("permessage-deflate"))
+        );
+        let deflate = Deflate::new(Params::default(), 0, None);
+        // the context of each side is kept from one message to the next
+        for _ in 0..2 {
+            client.send_raw(compressed_frames(&deflate, "hello ", usize::MAX));
+            let Some(Frame::Text(reply)) = client.recv().await else {
+                panic!("expected a text frame");
+            };
+            assert!(client.compressed);
+            assert!(reply.len() < 120);
+            let reply = deflate.decompress(&reply, 1024).unwrap();
+            assert_eq!(reply, "hello ".repeat(20).as_bytes());
+        }
+        // the client doesn't have to compress its messages
+        client.send(Message::Text("hi".into()));
+        let Some(Frame::Text(reply)) = client.recv().await else {
+            panic!("expected a text frame");
+        };
+        assert!(client.compressed);
+        assert_eq!(deflate.decompress(&reply, 1024).unwrap(), "hi".repeat(20));
+    }
+
+    #[actix_web::test]
+    async fn routes_with_a_dictionary_prime_the_contexts() {
+        let mut client = TestClient::connect_with_headers(
+            "def message(ws_id, msg):\n    return msg\n",
+            "compression=True, compression_threshold=0, \
+             compression_dictionary=b'{\"event\": \"tick\", \"seq\": '",
+            &[OFFER],
+        )
+        .await;
+        let deflate = Deflate::new(
+            Params::default(),
+            0,
+            Some(Bytes::from_static(br#"{"event": "tick", "seq": "#)),
+        );
+        for seq in 0..2 {
+            let message = format!(r#"{{"event": "tick", "seq": {seq}}}"#);
+            client.send_raw(compressed_frames(&deflate, &message, usize::MAX));
+            let Some(Frame::Text(reply)) = client.recv().await else {
+                panic!("expected a text frame");
+            };
+            assert!(client.compressed);
+            assert!(reply.len() < message.len() / 2);
+            assert_eq!(deflate.decompress(&reply, 1024).unwrap(), message);
+        }
+    }
+
+    #[actix_web::test]
+    async fn fragmented_messages_are_decompressed_whole() {
+        let mut client = TestClient::connect_with_headers(
+            REPEAT,
+            "compression=True, compression_threshold=0",
+            &[OFFER],
+        )
+        .await;
+        let deflate = Deflate::new(Params::default(), 0, None);
+        let message = "a fragmented message, ".repeat(10);
+        client.send_raw(compressed_frames(&deflate, &message, 4));
+        let Some(Frame::Text(reply)) = client.recv().await else {
+            panic!("expected a text frame");
+        };
+        let reply = deflate.decompress(&reply, 1 << 16).unwrap();
+        assert_eq!(reply, message.repeat(20).as_bytes());
+    }
+
+    #[actix_web::test]
+    async fn raw_text_routes_receive_the_decompressed_bytes() {
+        let mut client = TestClient::connect_with_headers(
+            "def message(ws_id, msg):\n    return msg.hex()\n",
+            "compression=True, compression_threshold=0, raw_text=True",
+            &[(
+                "Sec-WebSocket-Extensions",
+                "permessage-deflate; client_no_context_takeover",
+            )],
+        )
+        .await;
+        // the compressor of this side resets after every message, as the client agreed to
+        let deflate = Deflate::new(
+            Params {
+                server_no_context_takeover: true,
+                ..Params::default()
+            },
+            0,
+            None,
+        );
+        let receiver = Deflate::new(Params::default(), 0, None);
+        for _ in 0..2 {
+            client.send_raw(compressed_frames(&deflate, "hi hi", usize::MAX));
+            let Some(Frame::Text(reply)) = client.recv().await else {
+                panic!("expected a text frame");
+            };
+            assert_eq!(receiver.decompress(&reply, 1024).unwrap(), "6869206869");
+        }
+    }
+
+    #[actix_web::test]
+    async fn other_clients_get_uncompressed_frames() {
+        for (config, headers) in [
+            ("compression=True", vec![]),
+            ("", vec![OFFER]),
+            (
+                "compression=True",
+                vec![(
+                    "Sec-WebSocket-Extensions",
+                    "permessage-deflate; server_max_window_bits=9",
+                )],
+            ),
+        ] {
+            let mut client = TestClient::connect_with_headers(REPEAT, config, &headers).await;
+            assert!(!client
+                .response
+                .headers()
+                .contains_key(header::SEC_WEBSOCKET_EXTENSIONS));
+            client.send(Message::Text("hi".into()));
+            assert_eq!(
+                client.recv().await,
+                Some(Frame::Text("hi".repeat(20).into()))
+            );
+            assert!(!client.compressed);
+        }
+    }
+
+    #[actix_web::test]
+    async fn messages_below_the_threshold_are_sent_as_they_are() {
+        let mut client = TestClient::connect_with_headers(
+            REPEAT,
+            "compression=True, compression_threshold=100",
+            &[OFFER],
+        )
+        .await;
+        client.send(Message::Text("hi".into()));
+        assert_eq!(
+            client.recv().await,
+            Some(Frame::Text("hi".repeat(20).into()))
+        );
+        assert!(!client.compressed);
+        client.send(Message::Text("hello".into()));
+        let Some(Frame::Text(reply)) = client.recv().await else {
+            panic!("expected a text frame");
+        };
+        assert!(client.compressed);
+        let deflate = Deflate::new(Params::default(), 0, None);
+        assert_eq!(
+            deflate.decompress(&reply, 1024).unwrap(),
+            "hello".repeat(20)
+        );
+    }
+
+    #[actix_web::test]
+    async fn the_metrics_count_the_compressed_frames() {
+        let mut client = TestClient::connect_with_headers(
+            &format!("def connect(ws_id):\n    return ws_id\n{REPEAT}"),
+            "compression=True, compression_threshold=50",
+            &[OFFER],
+        )
+        .await;
+        // the id is shorter than the threshold
+        let Some(Frame::Text(id)) = client.recv().await else {
+            panic!("expected a text frame");
+        };
+        assert!(!client.compressed);
+        client.send(Message::Text("hello".into()));
+        client.recv().await.unwrap();
+        assert!(client.compressed);
+
+        let id = std::str::from_utf8(&id).unwrap();
+        let metrics: HashMap<String, f64> = Python::with_gil(|py| {
+            connection_metrics(py, Some(id))
+                .unwrap()
+                .extract(py)
+                .unwrap()
+        });
+        assert_eq!(metrics["uncompressed_frames_sent"], 1.0);
+        assert_eq!(metrics["compressed_frames_sent"], 1.0);
+        assert!(metrics["compressed_bytes_sent"] < 100.0);
+        assert_eq!(metrics["bytes_sent"], 136.0);
+    }
+
+    #[actix_web::test]
+    async fn decompression_bombs_close_the_connection() {
+        let mut client = TestClient::connect_with_headers(
+            REPEAT,
+            "compression=True, max_payload_size=1024",
+            &[OFFER],
+        )
+        .await;
+        let deflate = Deflate::new(Params::default(), 0, None);
+        client.send_raw(compressed_frames(&deflate, &"a".repeat(4096), usize::MAX));
+        assert_eq!(
+            client.recv().await,
+            Some(crate::test_client::close_frame(1009))
+        );
+    }
+
+    #[test]
+    fn the_bit_is_set_on_the_compressed_frames_only() {
+        let deflate = Deflate::new(Params::default(), 4, None);
+        deflate.compress(b"compressed", true).unwrap();
+        assert!(deflate.compress(b"as", true).is_none());
+        // the compressed text as the binary frame that the codec wrote, a ping and a text frame
+        let frames = Bytes::from_static(b"\x82\x01a\x89\x00\x81\x02as");
+        let mut outbound = OutboundFrames::new(BoxBody::new(frames), deflate.sent());
+        let chunk = futures::executor::block_on(std::future::poll_fn(|cx| {
+            Pin::new(&mut outbound).poll_next(cx)
+        }))
+        .unwrap()
+        .unwrap();
+        assert_eq!(&chunk[..], b"\xc1\x01a\x89\x00\x81\x02as");
+    }
+}
@@ -1,13 +1,30 @@
+mod acks;
+mod cbor;
+mod deflate;
 mod executors;
 mod io_helpers;
+mod lifecycle;
+mod msgpack;
+mod raw_text;
+mod reconnect;
 mod routers;
 mod server;
 mod shared_socket;
+#[cfg(test)]
+mod test_client;
 mod types;
 mod web_socket_connection;
 
+use routers::web_socket_router::WebSocketConfig;
 use server::Server;
 use shared_socket::SocketHeld;
+use web_socket_connection::{
+    ack_status, active_connection_count, broadcast, broadcast_sample, broadcast_to_room,
+    broadcast_to_room_except, broadcast_where, broadcast_with_ack, connection_metrics,
+    get_connection, get_tags, is_open, join_room, kick, leave_room, list_connections, pause,
+    queue_depth, reconnect_token, remove_tag, resume, room_member_count, send_ping, send_pong,
+    send_to, set_tag, set_verbose, WebSocketConnection,
+};
 
 use pythonize::{depythonize, PythonizeError};
 use serde_json::Value;
@@ -15,6 +32,8 @@ use serde_json::Value;
 // pyO3 module
 use pyo3::{exceptions::PyValueError, prelude::*};
 use types::{
+    accept_connection::AcceptConnection,
+    close_connection::CloseConnection,
     function_info::{FunctionInfo, MiddlewareType},
     identity::Identity,
     request::PyRequest,
@@ -50,6 +69,33 @@ pub fn robyn(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
     // the pymodule class/function to make the rustPyFunctions available
     m.add_function(wrap_pyfunction!(get_version, m)?)?;
     m.add_function(wrap_pyfunction!(jsonify, m)?)?;
+    m.add_function(wrap_pyfunction!(broadcast, m)?)?;
+    m.add_function(wrap_pyfunction!(send_to, m)?)?;
+    m.add_function(wrap_pyfunction!(get_connection, m)?)?;
+    m.add_function(wrap_pyfunction!(send_ping, m)?)?;
+    m.add_function(wrap_pyfunction!(send_pong, m)?)?;
+    m.add_function(wrap_pyfunction!(join_room, m)?)?;
+    m.add_function(wrap_pyfunction!(leave_room, m)?)?;
+    m.add_function(wrap_pyfunction!(broadcast_to_room, m)?)?;
+    m.add_function(wrap_pyfunction!(broadcast_to_room_except, m)?)?;
+    m.add_function(wrap_pyfunction!(broadcast_sample, m)?)?;
+    m.add_function(wrap_pyfunction!(broadcast_with_ack, m)?)?;
+    m.add_function(wrap_pyfunction!(ack_status, m)?)?;
+    m.add_function(wrap_pyfunction!(room_member_count, m)?)?;
+    m.add_function(wrap_pyfunction!(set_tag, m)?)?;
+    m.add_function(wrap_pyfunction!(remove_tag, m)?)?;
+    m.add_function(wrap_pyfunction!(get_tags, m)?)?;
+    m.add_function(wrap_pyfunction!(broadcast_where, m)?)?;
+    m.add_function(wrap_pyfunction!(is_open, m)?)?;
+    m.add_function(wrap_pyfunction!(kick, m)?)?;
+    m.add_function(wrap_pyfunction!(pause, m)?)?;
+    m.add_function(wrap_pyfunction!(resume, m)?)?;
+    m.add_function(wrap_pyfunction!(active_connection_count, m)?)?;
+    m.add_function(wrap_pyfunction!(list_connections, m)?)?;
+    m.add_function(wrap_pyfunction!(connection_metrics, m)?)?;
+    m.add_function(wrap_pyfunction!(queue_depth, m)?)?;
+    m.add_function(wrap_pyfunction!(reconnect_token, m)?)?;
+    m.add_function(wrap_pyfunction!(set_verbose, m)?)?;
     m.add_class::<Server>()?;
     m.add_class::<SocketHeld>()?;
     m.add_class::<FunctionInfo>()?;
@@ -58,6 +104,10 @@ pub fn robyn(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
     m.add_class::<PyResponse>()?;
     m.add_class::<MiddlewareType>()?;
     m.add_class::<HttpMethod>()?;
+    m.add_class::<AcceptConnection>()?;
+    m.add_class::<CloseConnection>()?;
+    m.add_class::<WebSocketConfig>()?;
+    m.add_class::<WebSocketConnection>()?;
     pyo3::prepare_freethreaded_python();
     Ok(())
 }
@@ -0,0 +1,71 @@
+//! The lifecycle events of the web socket connections of the process, sent on a broadcast
+//! channel so that auditing or monitoring doesn't need handlers on every route.
+//! Nothing is sent while there are no subscribers, and a subscriber that falls behind
+//! loses the oldest events rather than holding up the connections.
+
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::sync::broadcast;
+
+/// the number of events that a subscriber can fall behind before it loses the oldest ones
+const CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub enum EventKind {
+    Connected,
+    Disconnected,
+    Error,
+}
+
+impl EventKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Connected => "connected",
+            Self::Disconnected => "disconnected",
+            Self::Error => "error",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LifecycleEvent {
+    pub kind: EventKind,
+    /// the id that the handlers receive as `websocket_id`
+    pub id: String,
+    /// the route of the connection, as it was registered e.g. `/ws/:room`
+    pub route: String,
+    /// seconds since the epoch
+    pub timestamp: f64,
+    /// the close code of a disconnection, or the message of an error
+    pub detail: Option<String>,
+}
+
+fn sender() -> &'static broadcast::Sender<LifecycleEvent> {
+    static SENDER: OnceLock<broadcast::Sender<LifecycleEvent>> = OnceLock::new();
+    SENDER.get_or_init(|| broadcast::channel(CHANNEL_CAPACITY).0)
+}
+
+/// Receives the events sent from now on
+pub fn subscribe() -> broadcast::Receiver<LifecycleEvent> {
+    sender().subscribe()
+}
+
+/// Sends the event to the subscribers, the detail is only built when there are some
+pub fn emit(kind: EventKind, id: String, route: &str, detail: impl FnOnce() -> Option<String>) {
+    let sender = sender();
+    if sender.receiver_count() == 0 {
+        return;
+    }
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |elapsed| elapsed.as_secs_f64());
+    // the only error is that the last subscriber went away in the meantime
+    let _ = sender.send(LifecycleEvent {
+        kind,
+        id,
+        route: route.to_string(),
+        timestamp,
+        detail: detail(),
+    });
+}
@@ -0,0 +1,239 @@
+//! A minimal msgpack codec for the web socket msgpack mode.
+//! It covers the types that have a python equivalent: nil, booleans, integers, floats,
+//! strings, binaries, arrays and maps. Extension types are not supported.
+
+use std::convert::TryInto;
+
+use pyo3::exceptions::{PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{
+    PyBool, PyByteArray, PyBytes, PyDict, PyFloat, PyList, PyLong, PyString, PyTuple,
+};
+
+/// nested arrays and maps deeper than this are refused, so that a message can't exhaust the stack
+const MAX_DEPTH: usize = 256;
+
+pub fn encode(value: &PyAny) -> PyResult<Vec<u8>> {
+    let mut buf = Vec::new();
+    encode_into(value, &mut buf, 0)?;
+    Ok(buf)
+}
+
+fn encode_into(value: &PyAny, buf: &mut Vec<u8>, depth: usize) -> PyResult<()> {
+    if depth > MAX_DEPTH {
+        return Err(PyValueError::new_err("The value is nested too deeply"));
+    }
+    if value.is_none() {
+        buf.push(0xc0);
+    } else if let Ok(boolean) = value.downcast::<PyBool>() {
+        buf.push(if boolean.is_true() { 0xc3 } else { 0xc2 });
+    } else if value.is_instance_of::<PyLong>() {
+        if let Ok(int) = value.extract::<i64>() {
+            encode_int(int, buf);
+        } else {
+            // only fits if it is a positive integer above i64::MAX
+            buf.push(0xcf);
+            buf.extend_from_slice(&value.extract::<u64>()?.to_be_bytes());
+        }
+    } else if let Ok(float) = value.downcast::<PyFloat>() {
+        buf.push(0xcb);
+        buf.extend_from_slice(&float.value().to_be_bytes());
+    } else if let Ok(string) = value.downcast::<PyString>() {
+        let string = string.to_str()?;
+        encode_header(string.len(), buf, Some((0xa0, 31)), Some(0xd9), 0xda, 0xdb);
+        buf.extend_from_slice(string.as_bytes());
+    } else if let Ok(bin) = value.downcast::<PyBytes>() {
+        encode_header(bin.as_bytes().len(), buf, None, Some(0xc4), 0xc5, 0xc6);
+        buf.extend_from_slice(bin.as_bytes());
+    } else if let Ok(bin) = value.downcast::<PyByteArray>() {
+        let bin = bin.to_vec();
+        encode_header(bin.len(), buf, None, Some(0xc4), 0xc5, 0xc6);
+        buf.extend_from_slice(&bin);
+    } else if value.is_instance_of::<PyList>() || value.is_instance_of::<PyTuple>() {
+        encode_header(value.len()?, buf, Some((0x90, 15)), None, 0xdc, 0xdd);
+        for item in value.iter()? {
+            encode_into(item?, buf, depth + 1)?;
+        }
+    } else if let Ok(dict) = value.downcast::<PyDict>() {
+        encode_header(dict.len(), buf, Some((0x80, 15)), None, 0xde, 0xdf);
+        for (key, value) in dict {
+            encode_into(key, buf, depth + 1)?;
+            encode_into(value, buf, depth + 1)?;
+        }
+    } else {
+        return Err(PyTypeError::new_err(format!(
+            "Cannot encode {} with msgpack",
+            value.get_type().name()?
+        )));
+    }
+    Ok(())
+}
+
+fn encode_int(int: i64, buf: &mut Vec<u8>) {
+    match int {
+        0..=0x7f => buf.push(int as u8),
+        -32..=-1 => buf.push(int as i8 as u8),
+        0x80..=0xff => buf.extend_from_slice(&[0xcc, int as u8]),
+        0x100..=0xffff => {
+            buf.push(0xcd);
+            buf.extend_from_slice(&(int as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            buf.push(0xce);
+            buf.extend_from_slice(&(int as u32).to_be_bytes());
+        }
+        0x1_0000_0000.. => {
+            buf.push(0xcf);
+            buf.extend_from_slice(&(int as u64).to_be_bytes());
+        }
+        -0x80..=-33 => buf.extend_from_slice(&[0xd0, int as i8 as u8]),
+        -0x8000..=-0x81 => {
+            buf.push(0xd1);
+            buf.extend_from_slice(&(int as i16).to_be_bytes());
+        }
+        -0x8000_0000..=-0x8001 => {
+            buf.push(0xd2);
+            buf.extend_from_slice(&(int as i32).to_be_bytes());
+        }
+        _ => {
+            buf.push(0xd3);
+            buf.extend_from_slice(&int.to_be_bytes());
+        }
+    }
+}
+
+/// Writes the header of a string, a binary, an array or a map.
+/// `fix` is the prefix and the maximum length of the short form, `len8` the prefix
+/// of the 8 bits form, for the types that have them
+fn encode_header(
+    len: usize,
+    buf: &mut Vec<u8>,
+    fix: Option<(u8, usize)>,
+    len8: Option<u8>,
+    len16: u8,
+    len32: u8,
+) {
+    match (fix, len8) {
+        (Some((prefix, max)), _) if len <= max => buf.push(prefix | len as u8),
+        (_, Some(prefix)) if len <= 0xff => buf.extend_from_slice(&[prefix, len as u8]),
+        _ if len <= 0xffff => {
+            buf.push(len16);
+            buf.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        _ => {
+            buf.push(len32);
+            buf.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+}
+
+pub fn decode(py: Python, bin: &[u8]) -> PyResult<PyObject> {
+    let mut decoder = Decoder { bin, pos: 0 };
+    let value = decoder.decode(py, 0)?;
+    if decoder.pos != bin.len() {
+        return Err(PyValueError::new_err("Unexpected data after the value"));
+    }
+    Ok(value)
+}
+
+struct Decoder<'a> {
+    bin: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> PyResult<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.bin.len())
+            .ok_or_else(|| PyValueError::new_err("Unexpected end of data"))?;
+        let bytes = &self.bin[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> PyResult<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn take_len(&mut self, size: usize) -> PyResult<usize> {
+        Ok(match size {
+            1 => self.take_array::<1>()?[0] as usize,
+            2 => u16::from_be_bytes(self.take_array()?) as usize,
+            _ => u32::from_be_bytes(self.take_array()?) as usize,
+        })
+    }
+
+    fn decode(&mut self, py: Python, depth: usize) -> PyResult<PyObject> {
+        if depth > MAX_DEPTH {
+            return Err(PyValueError::new_err("The value is nested too deeply"));
+        }
+        let marker = self.take_array::<1>()?[0];
+        Ok(match marker {
+            0x00..=0x7f => marker.to_object(py),
+            0x80..=0x8f => self.decode_map(py, (marker & 0x0f) as usize, depth)?,
+            0x90..=0x9f => self.decode_array(py, (marker & 0x0f) as usize, depth)?,
+            0xa0..=0xbf => self.decode_str(py, (marker & 0x1f) as usize)?,
+            0xc0 => py.None(),
+            0xc2 => false.to_object(py),
+            0xc3 => true.to_object(py),
+            0xc4..=0xc6 => {
+                let len = self.take_len(1 << (marker - 0xc4))?;
+                PyBytes::new(py, self.take(len)?).into()
+            }
+            0xca => f32::from_be_bytes(self.take_array()?).to_object(py),
+            0xcb => f64::from_be_bytes(self.take_array()?).to_object(py),
+            0xcc => self.take_array::<1>()?[0].to_object(py),
+            0xcd => u16::from_be_bytes(self.take_array()?).to_object(py),
+            0xce => u32::from_be_bytes(self.take_array()?).to_object(py),
+            0xcf => u64::from_be_bytes(self.take_array()?).to_object(py),
+            0xd0 => (self.take_array::<1>()?[0] as i8).to_object(py),
+            0xd1 => i16::from_be_bytes(self.take_array()?).to_object(py),
+            0xd2 => i32::from_be_bytes(self.take_array()?).to_object(py),
+            0xd3 => i64::from_be_bytes(self.take_array()?).to_object(py),
+            0xd9..=0xdb => {
+                let len = self.take_len(1 << (marker - 0xd9))?;
+                self.decode_str(py, len)?
+            }
+            0xdc | 0xdd => {
+                let len = self.take_len(if marker == 0xdc { 2 } else { 4 })?;
+                self.decode_array(py, len, depth)?
+            }
+            0xde | 0xdf => {
+                let len = self.take_len(if marker == 0xde { 2 } else { 4 })?;
+                self.decode_map(py, len, depth)?
+            }
+            0xe0..=0xff => (marker as i8).to_object(py),
+            _ => {
+                return Err(PyValueError::new_err(format!(
+                    "Unsupported msgpack type 0x{marker:02x}"
+                )))
+            }
+        })
+    }
+
+    fn decode_str(&mut self, py: Python, len: usize) -> PyResult<PyObject> {
+        let string = std::str::from_utf8(self.take(len)?)
+            .map_err(|_| PyValueError::new_err("Invalid UTF-8 in a string"))?;
+        Ok(string.to_object(py))
+    }
+
+    fn decode_array(&mut self, py: Python, len: usize, depth: usize) -> PyResult<PyObject> {
+        let list = PyList::empty(py);
+        for _ in 0..len {
+            list.append(self.decode(py, depth + 1)?)?;
+        }
+        Ok(list.into())
+    }
+
+    fn decode_map(&mut self, py: Python, len: usize, depth: usize) -> PyResult<PyObject> {
+        let dict = PyDict::new(py);
+        for _ in 0..len {
+            let key = self.decode(py, depth + 1)?;
+            let value = self.decode(py, depth + 1)?;
+            dict.set_item(key, value)?;
+        }
+        Ok(dict.into())
+    }
+}
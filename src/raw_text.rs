@@ -0,0 +1,143 @@
+//! Lets a web socket route receive its text messages as raw bytes.
+//! actix refuses the text frames that are not valid UTF-8 while it decodes them, so the opcode
+//! of the text frames sent by the client is switched to binary before they reach the codec,
+//! and the kind of every message is recorded for the actor in the order of the frames.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use actix_web::error::PayloadError;
+use actix_web::web::{Bytes, BytesMut};
+use futures::Stream;
+
+pub const OPCODE_MASK: u8 = 0x0f;
+pub const OPCODE_TEXT: u8 = 0x1;
+pub const OPCODE_BINARY: u8 = 0x2;
+/// 2 bytes, a 64 bits extended length and a mask
+const MAX_HEADER_LEN: usize = 14;
+
+/// Whether each of the data frames that were not yet handled started a text message
+pub type FrameKinds = Rc<RefCell<VecDeque<bool>>>;
+
+pub struct RawTextFrames<S> {
+    stream: S,
+    kinds: FrameKinds,
+    headers: FrameHeaders,
+}
+
+impl<S> RawTextFrames<S> {
+    pub fn new(stream: S, kinds: FrameKinds) -> Self {
+        Self {
+            stream,
+            kinds,
+            headers: FrameHeaders::default(),
+        }
+    }
+
+    fn rewrite(&mut self, chunk: Bytes) -> Bytes {
+        let kinds = &self.kinds;
+        // continuation and control frames keep their opcode
+        self.headers
+            .rewrite(chunk, |byte| match byte & OPCODE_MASK {
+                OPCODE_TEXT => {
+                    kinds.borrow_mut().push_back(true);
+                    (byte & !OPCODE_MASK) | OPCODE_BINARY
+                }
+                OPCODE_BINARY => {
+                    kinds.borrow_mut().push_back(false);
+                    byte
+                }
+                _ => byte,
+            })
+    }
+}
+
+/// Walks the headers of the frames of a byte stream, whose chunks can start or end anywhere
+/// in a frame, so that the first byte of each frame, with its FIN and RSV bits and its opcode,
+/// can be rewritten. It reads the masked frames of the clients and the frames of the server
+#[derive(Default)]
+pub struct FrameHeaders {
+    header: [u8; MAX_HEADER_LEN],
+    /// the number of bytes of the current header that were read
+    header_len: usize,
+    /// the number of bytes of the current payload that are still to come
+    remaining: u64,
+}
+
+impl FrameHeaders {
+    /// The length of the payload once the whole header was read
+    fn payload_len(&self) -> Option<u64> {
+        if self.header_len < 2 {
+            return None;
+        }
+        let len = self.header[1] & 0x7f;
+        let extended = match len {
+            126 => 2,
+            127 => 8,
+            _ => 0,
+        };
+        let mask = if self.header[1] & 0x80 != 0 { 4 } else { 0 };
+        if self.header_len < 2 + extended + mask {
+            return None;
+        }
+        Some(match extended {
+            2 => u16::from_be_bytes([self.header[2], self.header[3]]) as u64,
+            8 => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&self.header[2..10]);
+                u64::from_be_bytes(bytes)
+            }
+            _ => len as u64,
+        })
+    }
+
+    /// Passes the first byte of every frame that starts in the chunk to `first_byte`,
+    /// and replaces it with the byte that it returns
+    pub fn rewrite(&mut self, chunk: Bytes, mut first_byte: impl FnMut(u8) -> u8) -> Bytes {
+        let mut rewritten: Option<BytesMut> = None;
+        let mut pos = 0;
+        while pos < chunk.len() {
+            if self.remaining > 0 {
+                let skipped = self.remaining.min((chunk.len() - pos) as u64);
+                pos += skipped as usize;
+                self.remaining -= skipped;
+                continue;
+            }
+
+            let byte = chunk[pos];
+            if self.header_len == 0 {
+                let new_byte = first_byte(byte);
+                if new_byte != byte {
+                    let rewritten = rewritten.get_or_insert_with(|| BytesMut::from(&chunk[..]));
+                    rewritten[pos] = new_byte;
+                }
+            }
+            self.header[self.header_len] = byte;
+            self.header_len += 1;
+            pos += 1;
+            if let Some(len) = self.payload_len() {
+                self.header_len = 0;
+                self.remaining = len;
+            }
+        }
+        rewritten.map_or(chunk, BytesMut::freeze)
+    }
+}
+
+impl<S> Stream for RawTextFrames<S>
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+{
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.stream).poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) => Poll::Ready(Some(Ok(this.rewrite(chunk)))),
+            poll => poll,
+        }
+    }
+}
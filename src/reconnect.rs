@@ -0,0 +1,110 @@
+//! The reconnection tokens of the web socket routes created with a `reconnect_ttl`.
+//! A token is a random nonce, it is only ever compared with the ones this process issued.
+//! The state of a dropped connection is kept under its token until it expires or is restored,
+//! so a token only works once and only in the process that issued it.
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+/// What a connection restores when it reconnects with the token of a dropped one
+pub struct Snapshot {
+    pub rooms: Vec<String>,
+    pub tags: HashMap<String, String>,
+    pub state: Py<PyDict>,
+    expires_at: Instant,
+}
+
+fn snapshots() -> &'static DashMap<String, Snapshot> {
+    static SNAPSHOTS: OnceLock<DashMap<String, Snapshot>> = OnceLock::new();
+    SNAPSHOTS.get_or_init(DashMap::new)
+}
+
+/// A new token, 256 random bits
+pub fn issue() -> String {
+    rand::random::<[u8; 32]>()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Keeps the state of a connection that dropped under its token for `ttl`.
+/// The state is copied, as the connection clears its own once it stopped
+pub fn save(
+    token: String,
+    rooms: Vec<String>,
+    tags: HashMap<String, String>,
+    state: &PyDict,
+    ttl: Duration,
+) -> PyResult<()> {
+    let state = state.copy()?.into();
+    let now = Instant::now();
+    // the snapshots of the clients that never came back are dropped here
+    snapshots().retain(|_, snapshot| snapshot.expires_at > now);
+    snapshots().insert(
+        token,
+        Snapshot {
+            rooms,
+            tags,
+            state,
+            expires_at: now + ttl,
+        },
+    );
+    Ok(())
+}
+
+/// Takes the state saved under the token, None when the token is invalid, unknown or expired
+pub fn restore(token: &str) -> Option<Snapshot> {
+    snapshots()
+        .remove(token)
+        .map(|(_, snapshot)| snapshot)
+        .filter(|snapshot| snapshot.expires_at > Instant::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_survives_the_connection_clearing_it() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let state = PyDict::new(py);
+            state.set_item("count", 2).unwrap();
+            let token = issue();
+            save(
+                token.clone(),
+                vec!["lobby".to_string()],
+                HashMap::new(),
+                state,
+                Duration::from_secs(60),
+            )
+            .unwrap();
+            // what `stopped` does once the state is saved
+            state.clear();
+
+            let snapshot = restore(&token).unwrap();
+            assert_eq!(snapshot.rooms, ["lobby"]);
+            let count: i32 = snapshot
+                .state
+                .as_ref(py)
+                .get_item("count")
+                .unwrap()
+                .extract()
+                .unwrap();
+            assert_eq!(count, 2);
+            // a token only works once
+            assert!(restore(&token).is_none());
+        });
+    }
+
+    #[test]
+    fn unknown_tokens_restore_nothing() {
+        assert!(restore(&issue()).is_none());
+        assert!(restore("").is_none());
+    }
+}
@@ -1,12 +1,502 @@
 use std::collections::HashMap;
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
+use actix_web::web::Bytes;
 use log::debug;
+use pyo3::{exceptions::PyValueError, prelude::*};
+use pyo3_asyncio::TaskLocals;
+use pythonize::depythonize;
+use serde_json::{json, Value};
 
+use crate::deflate::MAX_DICTIONARY_SIZE;
 use crate::types::function_info::FunctionInfo;
+use crate::{cbor, msgpack};
+
+/// What happens to the frames sent to a connection from outside of its handlers
+/// once `mailbox_capacity` of them are waiting to be sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackpressurePolicy {
+    /// the frames are queued without a limit
+    Unbounded,
+    DropNewest,
+    DropOldest,
+}
+
+impl BackpressurePolicy {
+    pub fn from_name(name: Option<&str>) -> PyResult<Self> {
+        match name {
+            None => Ok(Self::Unbounded),
+            Some("drop_newest") => Ok(Self::DropNewest),
+            Some("drop_oldest") => Ok(Self::DropOldest),
+            Some(name) => Err(PyValueError::new_err(format!(
+                "Unknown backpressure policy {name}, expected drop_newest or drop_oldest"
+            ))),
+        }
+    }
+}
+
+/// How the binary messages of a route are decoded and the values returned by its handlers encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryCodec {
+    Msgpack,
+    Cbor,
+}
+
+impl BinaryCodec {
+    pub fn from_modes(msgpack_mode: bool, cbor_mode: bool) -> PyResult<Option<Self>> {
+        match (msgpack_mode, cbor_mode) {
+            (true, true) => Err(PyValueError::new_err(
+                "msgpack_mode and cbor_mode can't be used together",
+            )),
+            (true, false) => Ok(Some(Self::Msgpack)),
+            (false, true) => Ok(Some(Self::Cbor)),
+            (false, false) => Ok(None),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Msgpack => "msgpack",
+            Self::Cbor => "CBOR",
+        }
+    }
+
+    pub fn encode(self, value: &PyAny) -> PyResult<Vec<u8>> {
+        match self {
+            Self::Msgpack => msgpack::encode(value),
+            Self::Cbor => cbor::encode(value),
+        }
+    }
+
+    pub fn decode(self, py: Python, bin: &[u8]) -> PyResult<PyObject> {
+        match self {
+            Self::Msgpack => msgpack::decode(py, bin),
+            Self::Cbor => cbor::decode(py, bin),
+        }
+    }
+}
+
+/// The number of text and binary messages per second that a client can send
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub rate: f64,
+    /// the number of messages that can be sent at once after a quiet period
+    pub burst: f64,
+    /// the connection is closed once this many messages were dropped
+    pub max_violations: Option<u32>,
+}
+
+impl RateLimit {
+    pub fn new(rate: f64, burst: Option<u32>, max_violations: Option<u32>) -> PyResult<Self> {
+        if rate.is_nan() || rate <= 0.0 {
+            return Err(PyValueError::new_err(format!(
+                "The rate limit must be positive, got {rate}"
+            )));
+        }
+        Ok(Self {
+            rate,
+            burst: burst.map_or(rate, f64::from).max(1.0),
+            max_violations,
+        })
+    }
+}
+
+/// How long the async handlers of a route can run, and what happens to the connection after
+#[derive(Debug, Clone, Copy)]
+pub struct HandlerTimeout {
+    pub seconds: f64,
+    /// close the connection once a handler was cancelled, otherwise the error is only logged
+    pub close: bool,
+}
+
+impl HandlerTimeout {
+    pub fn new(seconds: f64, action: &str) -> PyResult<Self> {
+        if seconds.is_nan() || seconds <= 0.0 {
+            return Err(PyValueError::new_err(format!(
+                "The handler timeout must be positive, got {seconds}"
+            )));
+        }
+        let close = match action {
+            "log" => false,
+            "close" => true,
+            action => {
+                return Err(PyValueError::new_err(format!(
+                    "Unknown handler timeout action {action}, expected log or close"
+                )))
+            }
+        };
+        Ok(Self { seconds, close })
+    }
+}
+
+/// A text frame sent to the client on an interval, for the clients that can't see the pings
+#[derive(Debug, Clone)]
+pub struct AppHeartbeat {
+    pub interval: u64,
+    pub frame: String,
+    /// the answer that the client has to send within the heartbeat timeout, if any
+    pub pong: Option<String>,
+    /// the answer parsed as JSON, so that the formatting of the client doesn't matter
+    pong_json: Option<Value>,
+}
+
+impl AppHeartbeat {
+    pub fn new(interval: u64, frame: String, pong: Option<String>) -> PyResult<Self> {
+        if interval == 0 {
+            return Err(PyValueError::new_err(
+                "The app heartbeat interval must be positive",
+            ));
+        }
+        let pong_json = pong
+            .as_deref()
+            .and_then(|pong| serde_json::from_str(pong).ok());
+        Ok(Self {
+            interval,
+            frame,
+            pong,
+            pong_json,
+        })
+    }
+
+    /// Whether the text message is the answer to a heartbeat
+    pub fn is_pong(&self, text: &str) -> bool {
+        let Some(pong) = &self.pong else {
+            return false;
+        };
+        if text == pong {
+            return true;
+        }
+        // the answers are short, so the other messages are not parsed
+        self.pong_json.is_some()
+            && text.len() <= 2 * pong.len() + 16
+            && serde_json::from_str::<Value>(text).ok() == self.pong_json
+    }
+}
+
+/// The settings of a web socket route, built once from the params of `WS` and shared by
+/// all of its connections. The defaults are the ones of `WS`
+#[pyclass]
+#[derive(Debug, Clone)]
+pub struct WebSocketConfig {
+    pub mailbox_capacity: usize,
+    pub heartbeat_interval: Option<u64>,
+    pub heartbeat_timeout: u64,
+    pub max_payload_size: usize,
+    /// the subprotocols that the route supports
+    pub subprotocols: Vec<String>,
+    pub subprotocol_required: bool,
+    /// read the remote ip from the `X-Forwarded-For` header
+    pub trust_forwarded_for: bool,
+    /// pass the text messages to the "message" handler as parsed JSON
+    pub json_mode: bool,
+    pub idle_timeout: Option<u64>,
+    pub backpressure: BackpressurePolicy,
+    pub rate_limit: Option<RateLimit>,
+    /// decode the binary messages and encode the values returned by the handlers, e.g. with msgpack
+    pub binary_codec: Option<BinaryCodec>,
+    /// run the async handlers of the route on an event loop of their own
+    pub dedicated_event_loop: bool,
+    /// pass the text messages to the "message" handler as bytes, without decoding them
+    pub raw_text: bool,
+    /// answer the pings of the client, otherwise the "ping" handler has to
+    pub auto_pong: bool,
+    /// run the async handlers of a connection one after the other, in the order of the messages
+    pub ordered: bool,
+    /// sent to the client as JSON when a handler raises, with the error filled in
+    pub handler_error_frame: Option<Value>,
+    /// the number of seconds that the "connect" handler has to accept the connection
+    pub handshake_timeout: Option<u64>,
+    /// the "backpressure" handler is called once this many frames wait in the mailbox of a connection
+    pub high_water_mark: Option<usize>,
+    /// how long the async handlers can run before they are cancelled
+    pub handler_timeout: Option<HandlerTimeout>,
+    /// the text frame sent to the client on an interval, for the clients that can't see the pings
+    pub app_heartbeat: Option<AppHeartbeat>,
+    /// the request header that holds the id that the application gave to the client
+    pub id_header: Option<String>,
+    /// refuse the connections whose id is already used by another connection
+    pub unique_ids: bool,
+    /// how long a closing connection waits for the frames queued in its mailbox to be sent
+    pub drain_timeout: Option<Duration>,
+    /// how long the state of a dropped connection is kept for the client to reconnect, in seconds
+    pub reconnect_ttl: Option<u64>,
+    /// disconnect the clients whose mailbox stays above the high water mark for this many seconds
+    pub slow_consumer_timeout: Option<u64>,
+    /// the `Origin` headers accepted by the handshake, lowercased, where `*` matches anything.
+    /// Any origin is accepted when it is None
+    pub allowed_origins: Option<Vec<String>>,
+    /// the messages whose id was received within this many seconds are duplicates
+    pub dedup_window: Option<u64>,
+    /// the field of the JSON messages that holds their id, the whole message is the id when it is None
+    pub dedup_key: Option<String>,
+    /// sent to the client as JSON when a message of a JSON mode route isn't valid JSON,
+    /// with the error filled in. Nothing is sent when it is None
+    pub invalid_json_frame: Option<Value>,
+    /// the field of the frames of `broadcast_with_ack` that holds their id, and of the JSON messages
+    /// that the clients ack them with. The connections of the route aren't sent them when it is None
+    pub ack_field: Option<String>,
+    /// negotiate permessage-deflate with the clients that offer it
+    pub compression: bool,
+    /// the messages sent are compressed from this many bytes, the smaller ones are sent as they are
+    pub compression_threshold: usize,
+    /// the preset dictionary that both sides prime their compression contexts with. It isn't
+    /// negotiated, the clients of the route have to use the same one
+    pub compression_dictionary: Option<Bytes>,
+}
+
+#[pymethods]
+impl WebSocketConfig {
+    #[new]
+    #[pyo3(signature = (
+        mailbox_capacity=16,
+        heartbeat_interval=None,
+        heartbeat_timeout=10,
+        max_payload_size=65_536,
+        subprotocols=Vec::new(),
+        subprotocol_required=false,
+        trust_forwarded_for=false,
+        json_mode=false,
+        idle_timeout=None,
+        backpressure=None,
+        rate_limit=None,
+        rate_limit_burst=None,
+        rate_limit_max_violations=None,
+        msgpack_mode=false,
+        cbor_mode=false,
+        dedicated_event_loop=false,
+        raw_text=false,
+        auto_pong=true,
+        ordered=false,
+        handler_error_frame=None,
+        handshake_timeout=None,
+        high_water_mark=None,
+        handler_timeout=None,
+        handler_timeout_action="log",
+        app_heartbeat_interval=None,
+        app_heartbeat_frame=String::from("{\"type\":\"ping\"}"),
+        app_heartbeat_pong=None,
+        id_header=None,
+        unique_ids=false,
+        drain_timeout=5.0,
+        reconnect_ttl=None,
+        slow_consumer_timeout=None,
+        allowed_origins=None,
+        dedup_window=None,
+        dedup_key=String::from("id"),
+        invalid_json_reply=true,
+        invalid_json_frame=None,
+        ack_field=None,
+        compression=false,
+        compression_threshold=1024,
+        compression_dictionary=None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        mailbox_capacity: usize,
+        heartbeat_interval: Option<u64>,
+        heartbeat_timeout: u64,
+        max_payload_size: usize,
+        subprotocols: Vec<String>,
+        subprotocol_required: bool,
+        trust_forwarded_for: bool,
+        json_mode: bool,
+        idle_timeout: Option<u64>,
+        backpressure: Option<&str>,
+        rate_limit: Option<f64>,
+        rate_limit_burst: Option<u32>,
+        rate_limit_max_violations: Option<u32>,
+        msgpack_mode: bool,
+        cbor_mode: bool,
+        dedicated_event_loop: bool,
+        raw_text: bool,
+        auto_pong: bool,
+        ordered: bool,
+        handler_error_frame: Option<&PyAny>,
+        handshake_timeout: Option<u64>,
+        high_water_mark: Option<usize>,
+        handler_timeout: Option<f64>,
+        handler_timeout_action: &str,
+        app_heartbeat_interval: Option<u64>,
+        app_heartbeat_frame: String,
+        app_heartbeat_pong: Option<String>,
+        id_header: Option<String>,
+        unique_ids: bool,
+        drain_timeout: Option<f64>,
+        reconnect_ttl: Option<u64>,
+        slow_consumer_timeout: Option<u64>,
+        allowed_origins: Option<Vec<String>>,
+        dedup_window: Option<u64>,
+        dedup_key: Option<String>,
+        invalid_json_reply: bool,
+        invalid_json_frame: Option<&PyAny>,
+        ack_field: Option<String>,
+        compression: bool,
+        compression_threshold: usize,
+        compression_dictionary: Option<&[u8]>,
+    ) -> PyResult<Self> {
+        if json_mode && raw_text {
+            return Err(PyValueError::new_err(
+                "json_mode and raw_text can't be used together",
+            ));
+        }
+        // the drop policies couldn't queue a single frame
+        if mailbox_capacity == 0 {
+            return Err(PyValueError::new_err(
+                "The mailbox capacity must be positive",
+            ));
+        }
+        // every message would close the connection
+        if max_payload_size == 0 {
+            return Err(PyValueError::new_err(
+                "The maximum payload size must be positive",
+            ));
+        }
+        // an interval of 0 would run the periodic check in a busy loop
+        if heartbeat_interval == Some(0) {
+            return Err(PyValueError::new_err(
+                "The heartbeat interval must be positive",
+            ));
+        }
+        // 0 would disable the request head timeout of the whole server, see `Server::start`
+        if handshake_timeout == Some(0) {
+            return Err(PyValueError::new_err(
+                "The handshake timeout must be positive",
+            ));
+        }
+        if idle_timeout == Some(0) {
+            return Err(PyValueError::new_err("The idle timeout must be positive"));
+        }
+        if high_water_mark == Some(0) {
+            return Err(PyValueError::new_err(
+                "The high water mark must be positive",
+            ));
+        }
+        // a timeout of 0 would close a connection as soon as its mailbox fills up once
+        if slow_consumer_timeout == Some(0) {
+            return Err(PyValueError::new_err(
+                "The slow consumer timeout must be positive",
+            ));
+        }
+        if slow_consumer_timeout.is_some() && high_water_mark.is_none() {
+            return Err(PyValueError::new_err(
+                "slow_consumer_timeout needs a high_water_mark",
+            ));
+        }
+        if compression_dictionary.is_some() && !compression {
+            return Err(PyValueError::new_err(
+                "compression_dictionary needs compression",
+            ));
+        }
+        if compression_dictionary.is_some_and(<[u8]>::is_empty) {
+            return Err(PyValueError::new_err(
+                "The compression dictionary can't be empty",
+            ));
+        }
+        // the compressor can only refer back to the last 32 KiB
+        if compression_dictionary.is_some_and(|dictionary| dictionary.len() > MAX_DICTIONARY_SIZE) {
+            return Err(PyValueError::new_err(format!(
+                "The compression dictionary can't be larger than {MAX_DICTIONARY_SIZE} bytes"
+            )));
+        }
+        if dedup_window == Some(0) {
+            return Err(PyValueError::new_err("The dedup window must be positive"));
+        }
+        if reconnect_ttl == Some(0) {
+            return Err(PyValueError::new_err("The reconnect ttl must be positive"));
+        }
+        let drain_timeout = drain_timeout
+            .map(|seconds| {
+                Duration::try_from_secs_f64(seconds)
+                    .map_err(|_| PyValueError::new_err(format!("Invalid drain timeout {seconds}")))
+            })
+            .transpose()?;
+        let handler_error_frame = handler_error_frame
+            .map(depythonize::<Value>)
+            .transpose()
+            .map_err(|e| PyValueError::new_err(format!("Invalid handler_error_frame - {e}")))?;
+        let invalid_json_frame = invalid_json_frame
+            .map(depythonize::<Value>)
+            .transpose()
+            .map_err(|e| PyValueError::new_err(format!("Invalid invalid_json_frame - {e}")))?
+            .unwrap_or_else(|| json!({"error": "invalid_json", "detail": "{message}"}));
+        Ok(Self {
+            mailbox_capacity,
+            heartbeat_interval,
+            heartbeat_timeout,
+            max_payload_size,
+            subprotocols,
+            subprotocol_required,
+            trust_forwarded_for,
+            json_mode,
+            idle_timeout,
+            backpressure: BackpressurePolicy::from_name(backpressure)?,
+            rate_limit: rate_limit
+                .map(|rate| RateLimit::new(rate, rate_limit_burst, rate_limit_max_violations))
+                .transpose()?,
+            binary_codec: BinaryCodec::from_modes(msgpack_mode, cbor_mode)?,
+            dedicated_event_loop,
+            raw_text,
+            auto_pong,
+            ordered,
+            handler_error_frame,
+            handshake_timeout,
+            high_water_mark,
+            handler_timeout: handler_timeout
+                .map(|seconds| HandlerTimeout::new(seconds, handler_timeout_action))
+                .transpose()?,
+            app_heartbeat: app_heartbeat_interval
+                .map(|interval| {
+                    AppHeartbeat::new(interval, app_heartbeat_frame, app_heartbeat_pong)
+                })
+                .transpose()?,
+            id_header,
+            unique_ids,
+            drain_timeout,
+            reconnect_ttl,
+            slow_consumer_timeout,
+            // the origins are compared without their case, like the host names
+            allowed_origins: allowed_origins.map(|origins| {
+                origins
+                    .iter()
+                    .map(|origin| origin.to_ascii_lowercase())
+                    .collect()
+            }),
+            dedup_window,
+            dedup_key,
+            invalid_json_frame: invalid_json_reply.then_some(invalid_json_frame),
+            ack_field,
+            compression,
+            compression_threshold,
+            compression_dictionary: compression_dictionary.map(Bytes::copy_from_slice),
+        })
+    }
+}
+
+/// The handlers and the settings of a single web socket route
+#[derive(Debug, Clone)]
+pub struct WebSocketRoute {
+    pub handlers: HashMap<String, FunctionInfo>,
+    pub config: Arc<WebSocketConfig>,
+    /// the event loop that runs the async handlers of the route, the main one when it is None
+    pub task_locals: Option<TaskLocals>,
+}
+
+/// Converts the `:name` path params of a route to the `{name}` syntax of actix
+/// e.g. `/ws/room/:room_id` -> `/ws/room/{room_id}`
+pub fn to_actix_path(route: &str) -> String {
+    route
+        .split('/')
+        .map(|segment| match segment.strip_prefix(':') {
+            Some(name) => format!("{{{name}}}"),
+            None => segment.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join("/")
+}
 
 /// Contains the thread safe hashmaps of different routes
-type WebSocketRoutes = RwLock<HashMap<String, HashMap<String, FunctionInfo>>>;
+type WebSocketRoutes = RwLock<HashMap<String, WebSocketRoute>>;
 
 pub struct WebSocketRouter {
     web_socket_routes: WebSocketRoutes,
@@ -26,28 +516,48 @@ impl WebSocketRouter {
 
     // Checks if the functions is an async function
     // Inserts them in the router according to their nature(CoRoutine/SyncFunction)
+    #[allow(clippy::too_many_arguments)]
     pub fn add_websocket_route(
         &self,
         route: &str,
-        connect_route: FunctionInfo,
-        close_route: FunctionInfo,
-        message_route: FunctionInfo,
+        connect_route: Option<FunctionInfo>,
+        close_route: Option<FunctionInfo>,
+        message_route: Option<FunctionInfo>,
+        optional_routes: HashMap<String, FunctionInfo>,
+        config: WebSocketConfig,
+        task_locals: Option<TaskLocals>,
     ) {
         let table = self.get_web_socket_map();
+        let mut handlers = HashMap::new();
 
-        let insert_in_router = |function: FunctionInfo, socket_type: &str| {
-            debug!("socket type is {:?} {:?}", table, route);
+        let mut insert_in_router = |function: FunctionInfo, socket_type: &str| {
+            debug!("socket type is {:?} {:?}", socket_type, route);
 
-            table
-                .write()
-                .unwrap()
-                .entry(route.to_string())
-                .or_default()
-                .insert(socket_type.to_string(), function)
+            handlers.insert(socket_type.to_string(), function)
         };
 
-        insert_in_router(connect_route, "connect");
-        insert_in_router(close_route, "close");
-        insert_in_router(message_route, "message");
+        // a route can leave out any of them, the events without a handler are ignored
+        for (function, socket_type) in [
+            (connect_route, "connect"),
+            (close_route, "close"),
+            (message_route, "message"),
+        ] {
+            if let Some(function) = function {
+                insert_in_router(function, socket_type);
+            }
+        }
+        // e.g. "binary" and "ping"
+        for (socket_type, function) in optional_routes {
+            insert_in_router(function, &socket_type);
+        }
+
+        table.write().unwrap().insert(
+            route.to_string(),
+            WebSocketRoute {
+                handlers,
+                config: Arc::new(config),
+                task_locals,
+            },
+        );
     }
 }
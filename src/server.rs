@@ -4,22 +4,29 @@ use crate::routers::const_router::ConstRouter;
 use crate::routers::Router;
 
 use crate::routers::http_router::HttpRouter;
-use crate::routers::{middleware_router::MiddlewareRouter, web_socket_router::WebSocketRouter};
+use crate::routers::{
+    middleware_router::MiddlewareRouter,
+    web_socket_router::{to_actix_path, WebSocketConfig, WebSocketRouter},
+};
 use crate::shared_socket::SocketHeld;
 use crate::types::function_info::{FunctionInfo, MiddlewareType};
 use crate::types::request::Request;
 use crate::types::response::Response;
 use crate::types::HttpMethod;
 use crate::types::MiddlewareReturn;
-use crate::web_socket_connection::start_web_socket;
+use crate::web_socket_connection::{
+    close_all_connections, forward_lifecycle_events, set_max_connections, start_web_socket,
+};
 
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::{Relaxed, SeqCst};
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use std::process::abort;
-use std::{env, thread};
+use std::{env, fmt, thread};
 
 use actix_files::Files;
 use actix_http::KeepAlive;
@@ -31,9 +38,13 @@ use dashmap::DashMap;
 use log::{debug, error};
 use pyo3::exceptions::PyValueError;
 use pyo3::prelude::*;
+use pyo3::types::IntoPyDict;
 
 const MAX_PAYLOAD_SIZE: &str = "ROBYN_MAX_PAYLOAD_SIZE";
 const DEFAULT_MAX_PAYLOAD_SIZE: usize = 1_000_000; // 1Mb
+const MAX_WEB_SOCKET_CONNECTIONS: &str = "ROBYN_MAX_WEB_SOCKET_CONNECTIONS";
+// the time the web sockets have to send their close frames when the server shuts down
+const WEB_SOCKET_SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(1);
 
 static STARTED: AtomicBool = AtomicBool::new(false);
 
@@ -56,6 +67,7 @@ pub struct Server {
     directories: Arc<RwLock<Vec<Directory>>>,
     startup_handler: Option<Arc<FunctionInfo>>,
     shutdown_handler: Option<Arc<FunctionInfo>>,
+    web_socket_lifecycle_handler: Option<Arc<FunctionInfo>>,
 }
 
 #[pymethods]
@@ -72,6 +84,7 @@ impl Server {
             directories: Arc::new(RwLock::new(Vec::new())),
             startup_handler: None,
             shutdown_handler: None,
+            web_socket_lifecycle_handler: None,
         }
     }
 
@@ -112,6 +125,11 @@ impl Server {
         let task_locals = pyo3_asyncio::TaskLocals::new(event_loop).copy_context(py)?;
         let task_locals_copy = task_locals.clone();
 
+        // subscribed before the first connection, so that it gets all of their events
+        if let Some(function) = &self.web_socket_lifecycle_handler {
+            forward_lifecycle_events(function.as_ref().clone(), task_locals.clone());
+        }
+
         let max_payload_size = env::var(MAX_PAYLOAD_SIZE)
             .unwrap_or(DEFAULT_MAX_PAYLOAD_SIZE.to_string())
             .trim()
@@ -122,6 +140,31 @@ impl Server {
                 ))
             })?;
 
+        // web socket connections are unlimited unless the variable is set
+        if let Ok(max_web_socket_connections) = env::var(MAX_WEB_SOCKET_CONNECTIONS) {
+            let max_web_socket_connections = max_web_socket_connections
+                .trim()
+                .parse::<usize>()
+                .map_err(|e| {
+                    PyValueError::new_err(format!(
+                        "Failed to parse environment variable {MAX_WEB_SOCKET_CONNECTIONS} - {e}"
+                    ))
+                })?;
+            set_max_connections(max_web_socket_connections);
+        }
+
+        // the route of a request is only known once its head was read, so a client that trickles
+        // the upgrade request is bounded by the longest handshake timeout of the routes.
+        // 0 disables the timeout, as before any route had one
+        let request_head_timeout = web_socket_router
+            .get_web_socket_map()
+            .read()
+            .unwrap()
+            .values()
+            .filter_map(|route| route.config.handshake_timeout)
+            .max()
+            .unwrap_or(0);
+
         thread::spawn(move || {
             actix_web::rt::System::new().block_on(async move {
                 debug!("The number of workers is {}", workers.clone());
@@ -168,16 +211,22 @@ impl Server {
                     let web_socket_map = web_socket_router.get_web_socket_map();
                     for (elem, value) in (web_socket_map.read().unwrap()).iter() {
                         let route = elem.clone();
-                        let path_params = value.clone();
-                        let task_locals = task_locals.clone();
+                        let handlers = value.handlers.clone();
+                        let config = value.config.clone();
+                        let task_locals = value
+                            .task_locals
+                            .clone()
+                            .unwrap_or_else(|| task_locals.clone());
                         app = app.route(
-                            &route.clone(),
+                            &to_actix_path(&route),
                             web::get().to(move |stream: web::Payload, req: HttpRequest| {
                                 start_web_socket(
                                     req,
                                     stream,
-                                    path_params.clone(),
+                                    route.clone(),
+                                    handlers.clone(),
                                     task_locals.clone(),
+                                    config.clone(),
                                 )
                             }),
                         );
@@ -211,7 +260,7 @@ impl Server {
                 })
                 .keep_alive(KeepAlive::Os)
                 .workers(*workers.clone())
-                .client_request_timeout(std::time::Duration::from_secs(0))
+                .client_request_timeout(std::time::Duration::from_secs(request_head_timeout))
                 .listen(raw_socket.try_into().unwrap())
                 .unwrap()
                 .run()
@@ -223,6 +272,10 @@ impl Server {
         let event_loop = (*event_loop).call_method0("run_forever");
         if event_loop.is_err() {
             debug!("Ctrl c handler");
+            // the clients are told to reconnect elsewhere instead of seeing the connection drop
+            if close_all_connections() > 0 {
+                py.allow_threads(|| thread::sleep(WEB_SOCKET_SHUTDOWN_GRACE_PERIOD));
+            }
             Python::with_gil(|py| {
                 pyo3_asyncio::tokio::run(py, async move {
                     execute_event_handler(shutdown_handler, &task_locals.clone())
@@ -338,15 +391,33 @@ impl Server {
 
     /// Add a new web socket route to the routing tables
     /// can be called after the server has been started
+    #[pyo3(signature = (route, connect_route, close_route, message_route, optional_routes, config))]
+    #[allow(clippy::too_many_arguments)]
     pub fn add_web_socket_route(
         &mut self,
+        py: Python,
         route: &str,
-        connect_route: FunctionInfo,
-        close_route: FunctionInfo,
-        message_route: FunctionInfo,
-    ) {
-        self.websocket_router
-            .add_websocket_route(route, connect_route, close_route, message_route);
+        connect_route: Option<FunctionInfo>,
+        close_route: Option<FunctionInfo>,
+        message_route: Option<FunctionInfo>,
+        optional_routes: HashMap<String, FunctionInfo>,
+        config: WebSocketConfig,
+    ) -> PyResult<()> {
+        let task_locals = if config.dedicated_event_loop {
+            Some(start_dedicated_event_loop(py)?)
+        } else {
+            None
+        };
+        self.websocket_router.add_websocket_route(
+            route,
+            connect_route,
+            close_route,
+            message_route,
+            optional_routes,
+            config,
+            task_locals,
+        );
+        Ok(())
     }
 
     /// Add a new startup handler
@@ -360,6 +431,15 @@ impl Server {
         self.shutdown_handler = Some(Arc::new(function));
         debug!("Added shutdown handler {:?}", self.shutdown_handler);
     }
+
+    /// Add the handler of the lifecycle events of the web socket connections
+    pub fn add_web_socket_lifecycle_handler(&mut self, function: FunctionInfo) {
+        self.web_socket_lifecycle_handler = Some(Arc::new(function));
+        debug!(
+            "Added web socket lifecycle handler {:?}",
+            self.web_socket_lifecycle_handler
+        );
+    }
 }
 
 impl Default for Server {
@@ -475,16 +555,47 @@ async fn index(
     response
 }
 
-fn get_traceback(error: &PyErr) -> String {
-    Python::with_gil(|py| -> String {
-        if let Some(traceback) = error.traceback(py) {
-            let msg = match traceback.format() {
-                Ok(msg) => format!("\n{msg} {error}"),
-                Err(e) => e.to_string(),
-            };
-            return msg;
-        };
+/// Starts an event loop in a daemon thread, for the routes that run their async handlers
+/// away from the main loop. The futures are scheduled on it with `call_soon_threadsafe`
+/// so it can be used from any worker
+pub(crate) fn start_dedicated_event_loop(py: Python) -> PyResult<pyo3_asyncio::TaskLocals> {
+    let event_loop = py.import("asyncio")?.call_method0("new_event_loop")?;
+    let kwargs = [
+        ("target", event_loop.getattr("run_forever")?),
+        ("daemon", true.into_py(py).into_ref(py)),
+    ]
+    .into_py_dict(py);
+    py.import("threading")?
+        .call_method("Thread", (), Some(kwargs))?
+        .call_method0("start")?;
+    pyo3_asyncio::TaskLocals::new(event_loop).copy_context(py)
+}
+
+/// The parts of a python error, so that e.g. only its type is sent to a web socket client
+/// while the whole traceback is logged.
+/// It is displayed as the formatted traceback followed by the error
+#[derive(Debug, Clone)]
+pub struct Traceback {
+    pub error_type: String,
+    pub message: String,
+    pub traceback: Option<String>,
+}
+
+impl fmt::Display for Traceback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.traceback {
+            Some(traceback) => write!(f, "\n{} {}: {}", traceback, self.error_type, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
 
-        error.value(py).to_string()
+pub fn get_traceback(error: &PyErr) -> Traceback {
+    Python::with_gil(|py| Traceback {
+        error_type: error.get_type(py).name().unwrap_or("Exception").to_string(),
+        message: error.value(py).to_string(),
+        traceback: error
+            .traceback(py)
+            .and_then(|traceback| traceback.format().ok()),
     })
 }
@@ -0,0 +1,302 @@
+//! An in-process client for the web socket routes, so that the actor can be tested without a
+//! server. The handshake goes through `start_web_socket` like the requests of the server do,
+//! the frames of the client are written to the payload of the request, and the frames that
+//! `MyWs` sends are decoded from the body of the response.
+
+use std::collections::HashMap;
+use std::future::poll_fn;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use actix_http::ws::{CloseReason, Frame, Item, Message, OpCode, Parser};
+use actix_http::BoxedPayloadStream;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::error::PayloadError;
+use actix_web::http::header;
+use actix_web::web::{self, Bytes, BytesMut};
+use actix_web::{dev, test, FromRequest, HttpResponse};
+use futures::channel::mpsc::{self, UnboundedSender};
+use pyo3::prelude::*;
+use pyo3::types::{IntoPyDict, PyModule};
+use pyo3_asyncio::TaskLocals;
+use uuid::Uuid;
+
+use crate::routers::web_socket_router::WebSocketConfig;
+use crate::server::start_dedicated_event_loop;
+use crate::types::function_info::FunctionInfo;
+use crate::web_socket_connection::start_web_socket;
+
+/// how long `recv` waits for a frame before the test fails
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The event loop of the async handlers, it runs in a thread of its own for the whole process
+fn task_locals() -> TaskLocals {
+    static TASK_LOCALS: OnceLock<TaskLocals> = OnceLock::new();
+    TASK_LOCALS
+        .get_or_init(|| {
+            pyo3::prepare_freethreaded_python();
+            Python::with_gil(|py| start_dedicated_event_loop(py).unwrap())
+        })
+        .clone()
+}
+
+pub struct TestClient {
+    /// the handshake response, without its body
+    pub response: HttpResponse<()>,
+    /// the module of the handlers, to read what they recorded
+    pub handlers: Py<PyModule>,
+    /// whether the last frame received had the RSV1 bit, that marks the compressed messages
+    pub compressed: bool,
+    frames: Option<UnboundedSender<Result<Bytes, PayloadError>>>,
+    body: BoxBody,
+    buffer: BytesMut,
+}
+
+impl TestClient {
+    /// Connects to a route whose handlers are the functions of `code`, each being the handler of
+    /// the event it is named after, e.g. `message`. The settings of the route are the keyword
+    /// arguments of `config`, written the way they are passed to `WS`
+    pub async fn connect(code: &str, config: &str) -> Self {
+        Self::connect_with_headers(code, config, &[]).await
+    }
+
+    /// Connects like `connect`, with more headers in the handshake request
+    pub async fn connect_with_headers(code: &str, config: &str, headers: &[(&str, &str)]) -> Self {
+        let task_locals = task_locals();
+        let (handlers, router, config) = Python::with_gil(|py| {
+            // modules are cached by name, each client gets its own handlers
+            let name = format!("handlers_{}", Uuid::new_v4().simple());
+            let module = PyModule::from_code(py, code, &format!("{name}.py"), &name).unwrap();
+            let inspect = py.import("inspect").unwrap();
+            let mut router = HashMap::new();
+            for (name, function) in module.dict() {
+                if !inspect
+                    .call_method1("isfunction", (function,))
+                    .unwrap()
+                    .is_true()
+                    .unwrap()
+                {
+                    continue;
+                }
+                let is_async = inspect
+                    .call_method1("iscoroutinefunction", (function,))
+                    .unwrap()
+                    .is_true()
+                    .unwrap();
+                let number_of_params = inspect
+                    .call_method1("signature", (function,))
+                    .unwrap()
+                    .getattr("parameters")
+                    .unwrap()
+                    .len()
+                    .unwrap();
+                router.insert(
+                    name.to_string(),
+                    FunctionInfo::new(function.into(), is_async, number_of_params as u8),
+                );
+            }
+            let locals = [("WebSocketConfig", py.get_type::<WebSocketConfig>())].into_py_dict(py);
+            let config: WebSocketConfig = py
+                .eval(&format!("WebSocketConfig({config})"), None, Some(locals))
+                .unwrap()
+                .extract()
+                .unwrap();
+            (Py::from(module), router, config)
+        });
+
+        let mut req = test::TestRequest::get()
+            .uri("/web_socket")
+            .insert_header((header::UPGRADE, "websocket"))
+            .insert_header((header::CONNECTION, "Upgrade"))
+            .insert_header((header::SEC_WEBSOCKET_VERSION, "13"))
+            .insert_header((header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ=="));
+        for &header in headers {
+            req = req.append_header(header);
+        }
+        let req = req.to_http_request();
+        let (frames, receiver) = mpsc::unbounded();
+        let mut payload = dev::Payload::from(Box::pin(receiver) as BoxedPayloadStream);
+        let stream = web::Payload::from_request(&req, &mut payload)
+            .await
+            .unwrap();
+        let response = start_web_socket(
+            req,
+            stream,
+            "/web_socket".to_string(),
+            router,
+            task_locals,
+            Arc::new(config),
+        )
+        .await
+        .unwrap();
+        let (response, body) = response.into_parts();
+        Self {
+            response,
+            handlers,
+            compressed: false,
+            frames: Some(frames),
+            body,
+            buffer: BytesMut::new(),
+        }
+    }
+
+    /// Sends a frame to the actor, masked like the frames of a client
+    pub fn send(&self, message: Message) {
+        let mut frame = BytesMut::new();
+        match message {
+            Message::Text(text) => {
+                Parser::write_message(&mut frame, text, OpCode::Text, true, true)
+            }
+            Message::Binary(bytes) => {
+                Parser::write_message(&mut frame, bytes, OpCode::Binary, true, true)
+            }
+            Message::Ping(bytes) => {
+                Parser::write_message(&mut frame, bytes, OpCode::Ping, true, true)
+            }
+            Message::Pong(bytes) => {
+                Parser::write_message(&mut frame, bytes, OpCode::Pong, true, true)
+            }
+            Message::Close(reason) => Parser::write_close(&mut frame, reason, true),
+            message => panic!("{:?} can't be sent by the test client", message),
+        }
+        self.send_raw(frame.freeze());
+    }
+
+    /// Writes bytes to the connection as they are, e.g. a malformed frame
+    pub fn send_raw(&self, bytes: Bytes) {
+        self.frames
+            .as_ref()
+            .expect("the connection was hung up")
+            .unbounded_send(Ok(bytes))
+            .unwrap();
+    }
+
+    /// Ends the payload of the request, like a client whose socket dropped
+    pub fn hang_up(&mut self) {
+        self.frames = None;
+    }
+
+    /// The next frame sent by the actor, None once it stopped
+    pub async fn recv(&mut self) -> Option<Frame> {
+        loop {
+            let first_byte = self.buffer.first().copied();
+            if let Some((finished, opcode, payload)) =
+                Parser::parse(&mut self.buffer, false, usize::MAX).unwrap()
+            {
+                self.compressed = first_byte.is_some_and(|byte| byte & 0x40 != 0);
+                let payload = payload.map_or_else(Bytes::new, BytesMut::freeze);
+                return Some(match opcode {
+                    OpCode::Text if !finished => Frame::Continuation(Item::FirstText(payload)),
+                    OpCode::Binary if !finished => Frame::Continuation(Item::FirstBinary(payload)),
+                    OpCode::Continue if !finished => Frame::Continuation(Item::Continue(payload)),
+                    OpCode::Continue => Frame::Continuation(Item::Last(payload)),
+                    OpCode::Text => Frame::Text(payload),
+                    OpCode::Binary => Frame::Binary(payload),
+                    OpCode::Ping => Frame::Ping(payload),
+                    OpCode::Pong => Frame::Pong(payload),
+                    OpCode::Close => Frame::Close(Parser::parse_close_payload(&payload)),
+                    OpCode::Bad => panic!("the actor sent a frame with a bad opcode"),
+                });
+            }
+            let chunk = tokio::time::timeout(
+                RECV_TIMEOUT,
+                poll_fn(|cx| Pin::new(&mut self.body).poll_next(cx)),
+            )
+            .await
+            .expect("the actor sent no frame in time");
+            match chunk {
+                Some(chunk) => self.buffer.extend_from_slice(&chunk.unwrap()),
+                None => {
+                    assert!(self.buffer.is_empty(), "the actor stopped within a frame");
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// The value of a global of the handlers, e.g. a list that they append to
+    pub fn global<T: for<'py> FromPyObject<'py>>(&self, name: &str) -> T {
+        Python::with_gil(|py| {
+            self.handlers
+                .as_ref(py)
+                .getattr(name)
+                .unwrap()
+                .extract()
+                .unwrap()
+        })
+    }
+}
+
+/// The close frame that a code and no description make
+pub fn close_frame(code: u16) -> Frame {
+    Frame::Close(Some(CloseReason {
+        code: code.into(),
+        description: None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_http::ws::CloseCode;
+
+    #[actix_web::test]
+    async fn messages_reach_the_handler() {
+        let mut client = TestClient::connect(
+            "def connect(ws_id):\n    return 'welcome'\n\
+             def message(ws_id, msg):\n    return msg.upper()\n",
+            "",
+        )
+        .await;
+        assert_eq!(client.response.status(), 101);
+        assert_eq!(client.recv().await, Some(Frame::Text("welcome".into())));
+        client.send(Message::Text("hello".into()));
+        assert_eq!(client.recv().await, Some(Frame::Text("HELLO".into())));
+    }
+
+    #[actix_web::test]
+    async fn pings_are_answered() {
+        let mut client = TestClient::connect("", "").await;
+        client.send(Message::Ping("beat".into()));
+        assert_eq!(client.recv().await, Some(Frame::Pong("beat".into())));
+    }
+
+    #[actix_web::test]
+    async fn the_close_frame_of_the_client_is_echoed() {
+        let mut client = TestClient::connect("", "").await;
+        client.send(Message::Close(Some(CloseReason {
+            code: 4000.into(),
+            description: Some("bye".to_string()),
+        })));
+        assert_eq!(client.recv().await, Some(close_frame(4000)));
+        assert_eq!(client.recv().await, None);
+    }
+
+    #[actix_web::test]
+    async fn async_close_handlers_run_before_the_actor_stops() {
+        let mut client = TestClient::connect(
+            "closed = []\n\
+             async def close(ws_id):\n    closed.append(ws_id)\n",
+            "",
+        )
+        .await;
+        client.send(Message::Close(Some(CloseCode::Normal.into())));
+        assert_eq!(client.recv().await, Some(close_frame(1000)));
+        assert_eq!(client.recv().await, None);
+        assert_eq!(client.global::<Vec<String>>("closed").len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn a_dropped_client_stops_the_actor() {
+        let mut client = TestClient::connect(
+            "closed = []\n\
+             def close(ws_id):\n    closed.append(ws_id)\n",
+            "",
+        )
+        .await;
+        client.hang_up();
+        assert_eq!(client.recv().await, None);
+        assert_eq!(client.global::<Vec<String>>("closed").len(), 1);
+    }
+}
@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+
+use pyo3::prelude::*;
+
+/// Returned from the "connect" handler of a web socket route to accept the connection
+/// with a subprotocol or an id chosen by the handler
+#[pyclass]
+#[derive(Debug, Clone)]
+pub struct AcceptConnection {
+    #[pyo3(get, set)]
+    pub subprotocol: Option<String>,
+    /// sent as the first frame, like the other values returned by the handler
+    #[pyo3(get, set)]
+    pub message: Option<PyObject>,
+    /// the id of the connection in place of its random UUID, e.g. the id of the user
+    #[pyo3(get, set)]
+    pub id: Option<String>,
+    /// a coroutine that runs for as long as the connection is open, e.g. to push updates
+    #[pyo3(get, set)]
+    pub background: Option<PyObject>,
+    /// added to the handshake response, e.g. a session id assigned by the server
+    #[pyo3(get, set)]
+    pub headers: Option<HashMap<String, String>>,
+}
+
+#[pymethods]
+impl AcceptConnection {
+    #[new]
+    #[pyo3(signature = (subprotocol=None, message=None, id=None, background=None, headers=None))]
+    pub fn new(
+        subprotocol: Option<String>,
+        message: Option<PyObject>,
+        id: Option<String>,
+        background: Option<PyObject>,
+        headers: Option<HashMap<String, String>>,
+    ) -> Self {
+        Self {
+            subprotocol,
+            message,
+            id,
+            background,
+            headers,
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use pyo3::{exceptions::PyValueError, pyclass, pymethods, PyResult};
+
+/// Returned from a web socket handler to close the connection
+#[pyclass]
+#[derive(Debug, Clone)]
+pub struct CloseConnection {
+    #[pyo3(get)]
+    pub code: u16,
+    #[pyo3(get)]
+    pub reason: Option<String>,
+}
+
+#[pymethods]
+impl CloseConnection {
+    #[new]
+    #[pyo3(signature = (code=1000, reason=None))]
+    pub fn new(code: u16, reason: Option<String>) -> PyResult<Self> {
+        Ok(Self {
+            code: validate_close_code(code)?,
+            reason: validate_close_reason(reason)?,
+        })
+    }
+
+    #[setter]
+    fn set_code(&mut self, code: u16) -> PyResult<()> {
+        self.code = validate_close_code(code)?;
+        Ok(())
+    }
+
+    #[setter]
+    fn set_reason(&mut self, reason: Option<String>) -> PyResult<()> {
+        self.reason = validate_close_reason(reason)?;
+        Ok(())
+    }
+}
+
+/// A close frame is a control frame of at most 125 bytes, 2 of which hold the code
+pub const MAX_CLOSE_REASON_LEN: usize = 123;
+
+/// Whether the code can be sent in a close frame.
+/// 1004 and 1016 to 2999 are reserved, and 1005, 1006 and 1015 are only reported locally,
+/// e.g. when the connection dropped without a close frame. 3000 to 3999 are registered
+/// codes of libraries and frameworks, 4000 to 4999 are left to the applications
+pub fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+pub fn validate_close_code(code: u16) -> PyResult<u16> {
+    if !is_valid_close_code(code) {
+        return Err(PyValueError::new_err(format!(
+            "{code} can't be sent as a close code, the applications can use 4000 to 4999"
+        )));
+    }
+    Ok(code)
+}
+
+pub fn validate_close_reason(reason: Option<String>) -> PyResult<Option<String>> {
+    if reason
+        .as_ref()
+        .is_some_and(|reason| reason.len() > MAX_CLOSE_REASON_LEN)
+    {
+        return Err(PyValueError::new_err(format!(
+            "The reason of a close can't be longer than {MAX_CLOSE_REASON_LEN} bytes"
+        )));
+    }
+    Ok(reason)
+}
@@ -4,6 +4,8 @@ use pyo3::{
     types::{PyBytes, PyString},
 };
 
+pub mod accept_connection;
+pub mod close_connection;
 pub mod function_info;
 pub mod identity;
 pub mod request;
@@ -1,149 +1,3827 @@
-use crate::types::function_info::FunctionInfo;
+use crate::acks;
+use crate::deflate::{self, Deflate, InboundFrames, InflateError, OutboundFrames};
+use crate::lifecycle::{self, EventKind};
+use crate::raw_text::{FrameKinds, RawTextFrames};
+use crate::reconnect;
+use crate::routers::web_socket_router::{
+    BackpressurePolicy, BinaryCodec, RateLimit, WebSocketConfig,
+};
+use crate::server::get_traceback;
+use crate::types::{
+    accept_connection::AcceptConnection,
+    close_connection::{
+        is_valid_close_code, validate_close_code, validate_close_reason, CloseConnection,
+        MAX_CLOSE_REASON_LEN,
+    },
+    function_info::FunctionInfo,
+};
 
 use actix::prelude::*;
 use actix::{Actor, AsyncContext, StreamHandler};
-use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_http::ws::Item;
+use actix_http::BoxedPayloadStream;
+use actix_web::{
+    http::header,
+    web,
+    web::{Bytes, BytesMut},
+    Error, HttpRequest, HttpResponse, ResponseError,
+};
 use actix_web_actors::ws;
-use log::debug;
+use actix_web_actors::ws::{CloseCode, CloseReason};
+use bytestring::ByteString;
+use dashmap::{mapref::entry::Entry, DashMap, DashSet};
+use log::{debug, error, info, warn};
+use pyo3::exceptions::{PyStopAsyncIteration, PyTypeError, PyValueError};
 use pyo3::prelude::*;
+use pyo3::types::{IntoPyDict, PyBool, PyBytes, PyDict, PyFloat, PyList, PyString, PyTuple};
 use pyo3_asyncio::TaskLocals;
+use pythonize::{depythonize, pythonize};
+use rand::seq::SliceRandom;
+use serde_json::{json, Value};
+use tokio::sync::broadcast::error::RecvError;
 use uuid::Uuid;
 
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 /// Define HTTP actor
-#[derive(Clone)]
 struct MyWs {
     id: Uuid,
+    /// the route that the connection was made to, as it was registered e.g. `/ws/:room`
+    route: String,
     router: HashMap<String, FunctionInfo>,
     task_locals: TaskLocals,
+    /// the settings of the route, shared by all of its connections
+    config: Arc<WebSocketConfig>,
+    close_reason: Option<CloseReason>,
+    /// when the oldest heartbeat ping that the client didn't answer yet was sent
+    unanswered_ping: Option<Instant>,
+    /// when the oldest app heartbeat that the client didn't answer yet was sent
+    unanswered_app_ping: Option<Instant>,
+    last_activity: Instant,
+    /// since when more frames than the high water mark wait in the mailbox
+    slow_since: Option<Instant>,
+    /// the kinds of the frames when the text messages are passed to the handlers as bytes
+    raw_text_frames: Option<FrameKinds>,
+    /// the compression contexts, once permessage-deflate was agreed on
+    deflate: Option<Deflate>,
+    /// the async handlers waiting for the previous ones, when the order of the messages is kept
+    ordered: Option<Rc<RefCell<OrderedHandlers>>>,
+    rate_limit: Option<TokenBucket>,
+    /// the ids of the messages received within the de-duplication window of the route
+    seen_ids: Option<SeenIds>,
+    /// the messages received since the connection was paused, None while it is not paused
+    paused: Option<VecDeque<Inbound>>,
+    /// the frames received so far of a fragmented message
+    fragments: Option<Fragments>,
+    /// the close frame to send once the "before_close" handler ran
+    pending_close: RefCell<Option<CloseReason>>,
+    /// the answer to the close frame of the client, sent once the "before_close" handler ran
+    close_echo: Option<CloseReason>,
+    /// nothing is written to the client after the close frame
+    close_sent: bool,
+    /// the actor keeps running until an async "close" handler is done
+    close_running: bool,
+    before_close_executed: bool,
+    /// the "close" handler runs once, while the actor is stopping when it is async
+    /// and once it stopped otherwise
+    close_executed: bool,
+    query_params: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    /// the ip of the client, None when it is unknown
+    remote_ip: Option<String>,
+    cookies: HashMap<String, String>,
+    /// the params matched in the path of the route e.g. `/ws/:room` -> `{"room": "lobby"}`
+    path_params: HashMap<String, String>,
+    /// a dict that the handlers of this connection can use to store state across messages
+    state: Py<PyDict>,
+    connect_output: Option<WsMessage>,
+    /// the coroutine that the "connect" handler asked to run for as long as the connection is open
+    background: Option<PyObject>,
+    /// the future of the task of the background coroutine, cancelled once the connection is closed
+    background_task: Option<PyObject>,
+    metrics: Arc<Metrics>,
+    connected_at: Instant,
+    /// the time of the handshake in seconds since the epoch, passed to the handlers
+    connected_at_epoch: f64,
+    /// the subprotocol agreed on during the handshake
+    subprotocol: Option<String>,
+    /// the extensions requested by the client, only permessage-deflate is negotiated
+    extensions: Vec<Extension>,
+    queue: Arc<OutboundQueue>,
+    /// set once the connection started closing, shared with the registry
+    closing: Arc<AtomicBool>,
+    /// given back once the connection stopped, or with the actor when the upgrade fails
+    slot: Option<ConnectionSlot>,
+    /// what the handles of the connection asked of it while it was upgraded
+    upgrade: Option<Upgrade>,
 }
 
-fn get_function_output<'a>(
-    function: &'a FunctionInfo,
-    fn_msg: Option<String>,
-    py: Python<'a>,
-    ws: &MyWs,
-) -> Result<&'a PyAny, PyErr> {
-    let handler = function.handler.as_ref(py);
+/// The futures of the async handlers that wait for the one that runs
+#[derive(Default)]
+struct OrderedHandlers {
+    running: bool,
+    waiting: VecDeque<Pin<Box<dyn ActorFuture<MyWs, Output = ()>>>>,
+}
 
-    // this makes the request object accessible across every route
-    match function.number_of_params {
-        0 => handler.call0(),
-        1 => handler.call1((ws.id.to_string(),)),
-        // this is done to accommodate any future params
-        2_u8..=u8::MAX => handler.call1((ws.id.to_string(), fn_msg.unwrap_or_default())),
+/// A text or binary message that is received in several frames
+#[derive(Clone)]
+struct Fragments {
+    text: bool,
+    /// the message was compressed with permessage-deflate
+    compressed: bool,
+    data: BytesMut,
+}
+
+impl Fragments {
+    fn new(text: bool, compressed: bool) -> Self {
+        Self {
+            text,
+            compressed,
+            data: BytesMut::new(),
+        }
     }
 }
 
-fn execute_ws_function(
-    function: &FunctionInfo,
-    text: Option<String>,
-    task_locals: &TaskLocals,
-    ctx: &mut ws::WebsocketContext<MyWs>,
-    ws: &MyWs,
-    // add number of params here
-) {
-    if function.is_async {
-        let fut = Python::with_gil(|py| {
-            pyo3_asyncio::into_future_with_locals(
-                task_locals,
-                get_function_output(function, text, py, ws).unwrap(),
+/// A text or binary message of the client, once it was accounted for
+#[derive(Clone)]
+enum Inbound {
+    Text(String),
+    /// a text message of a route that receives them as bytes
+    RawText(Bytes),
+    Binary(Bytes),
+}
+
+/// The ids of the messages that a connection received lately, oldest first.
+/// A message whose id was seen within the window is a duplicate
+#[derive(Clone)]
+struct SeenIds {
+    window: Duration,
+    order: VecDeque<(Instant, String)>,
+    seen: HashSet<String>,
+}
+
+impl SeenIds {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            order: VecDeque::new(),
+            seen: HashSet::new(),
+        }
+    }
+
+    /// Records the id, returns false when it was already seen within the window
+    fn insert(&mut self, id: String) -> bool {
+        let now = Instant::now();
+        while let Some((received_at, _)) = self.order.front() {
+            if now.duration_since(*received_at) < self.window {
+                break;
+            }
+            if let Some((_, expired)) = self.order.pop_front() {
+                self.seen.remove(&expired);
+            }
+        }
+        if !self.seen.insert(id.clone()) {
+            return false;
+        }
+        self.order.push_back((now, id));
+        true
+    }
+}
+
+/// The id of a message for the de-duplication, the `key` field of a JSON object
+/// or a hash of the whole message when the route has no key.
+/// The messages without an id are never duplicates
+fn message_id(key: Option<&str>, message: &[u8]) -> Option<String> {
+    let Some(key) = key else {
+        let mut hasher = DefaultHasher::new();
+        message.hash(&mut hasher);
+        return Some(format!("{:016x}", hasher.finish()));
+    };
+    match serde_json::from_slice::<Value>(message).ok()?.get(key)? {
+        Value::String(id) => Some(id.clone()),
+        Value::Number(id) => Some(id.to_string()),
+        _ => None,
+    }
+}
+
+/// A token bucket that refills at `rate` tokens per second, up to `burst` tokens
+#[derive(Clone)]
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+    /// the number of messages dropped so far
+    violations: u32,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst,
+            last_refill: Instant::now(),
+            violations: 0,
+        }
+    }
+
+    fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            self.violations += 1;
+            false
+        }
+    }
+}
+
+/// Keeps track of the frames that were sent to a connection from outside of its handlers,
+/// e.g. with `broadcast`, and are waiting in its mailbox.
+/// The mailbox only drains as fast as the client reads, so its depth tells when a client is too slow
+struct OutboundQueue {
+    depth: AtomicUsize,
+    /// the number of frames at the front of the mailbox that are dropped instead of being sent
+    dropped_oldest: AtomicUsize,
+    capacity: usize,
+    policy: BackpressurePolicy,
+    /// the warning is logged once every time the queue backs up
+    backed_up: AtomicBool,
+    high_water: Option<HighWater>,
+}
+
+/// Calls the "backpressure" handler of the route once every time the queue crosses the mark,
+/// and the "drained" handler once the queue is empty again
+struct HighWater {
+    mark: usize,
+    above: AtomicBool,
+    handler: Option<(FunctionInfo, TaskLocals)>,
+    /// set when the queue crossed the mark, until it is empty
+    draining: AtomicBool,
+    drained_handler: Option<(FunctionInfo, TaskLocals)>,
+}
+
+impl HighWater {
+    /// The handler runs on the runtime of pyo3-asyncio rather than in the actor,
+    /// as the actor only gets to it once the frames queued before were sent
+    fn notify(&self, id: &Uuid, depth: usize) {
+        let Some((function, task_locals)) = self.handler.clone() else {
+            return;
+        };
+        let id = *id;
+        spawn_detached_handler(
+            function,
+            task_locals,
+            format!("The backpressure handler of web socket {id}"),
+            move |py| vec![websocket_id(&id).to_object(py), depth.to_object(py)],
+        );
+    }
+
+    fn notify_drained(&self, id: &Uuid) {
+        let Some((function, task_locals)) = self.drained_handler.clone() else {
+            return;
+        };
+        let id = *id;
+        spawn_detached_handler(
+            function,
+            task_locals,
+            format!("The drained handler of web socket {id}"),
+            move |py| vec![websocket_id(&id).to_object(py)],
+        );
+    }
+}
+
+/// Calls a handler that doesn't belong to the actor of a connection on the runtime of pyo3-asyncio,
+/// with as many of the args as it has params, and logs its errors
+fn spawn_detached_handler<F>(
+    function: FunctionInfo,
+    task_locals: TaskLocals,
+    description: String,
+    args: F,
+) where
+    F: FnOnce(Python) -> Vec<PyObject> + Send + 'static,
+{
+    pyo3_asyncio::tokio::get_runtime().spawn(call_detached_handler(
+        function,
+        task_locals,
+        description,
+        args,
+    ));
+}
+
+async fn call_detached_handler<F>(
+    function: FunctionInfo,
+    task_locals: TaskLocals,
+    description: String,
+    args: F,
+) where
+    F: FnOnce(Python) -> Vec<PyObject>,
+{
+    let output = Python::with_gil(|py| {
+        let args = args(py)
+            .into_iter()
+            .take(function.number_of_params as usize);
+        let output = function.handler.as_ref(py).call1(PyTuple::new(py, args));
+        output.and_then(|output| {
+            if function.is_async {
+                pyo3_asyncio::into_future_with_locals(&task_locals, output).map(Some)
+            } else {
+                Ok(None)
+            }
+        })
+    });
+    let output = match output {
+        Ok(Some(future)) => future.await.map(|_| ()),
+        Ok(None) => Ok(()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = output {
+        error!("{} raised an error: {}", description, get_traceback(&e));
+    }
+}
+
+/// Passes the lifecycle events of the web socket connections of the process to the handler,
+/// one after the other. It receives a dict with the `event`, the `id` and the `route` of the
+/// connection, the `timestamp` and the `detail`, the close code or the error message
+pub fn forward_lifecycle_events(function: FunctionInfo, task_locals: TaskLocals) {
+    let mut events = lifecycle::subscribe();
+    pyo3_asyncio::tokio::get_runtime().spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!(
+                        "The web socket lifecycle handler fell behind and missed {missed} events"
+                    );
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            call_detached_handler(
+                function.clone(),
+                task_locals.clone(),
+                "The web socket lifecycle handler".to_string(),
+                move |py| {
+                    let dict = PyDict::new(py);
+                    let items = [
+                        ("event", event.kind.name().to_object(py)),
+                        ("id", event.id.to_object(py)),
+                        ("route", event.route.to_object(py)),
+                        ("timestamp", event.timestamp.to_object(py)),
+                        ("detail", event.detail.to_object(py)),
+                    ];
+                    for (key, value) in items {
+                        // setting a str key can't fail
+                        dict.set_item(key, value).ok();
+                    }
+                    vec![dict.to_object(py)]
+                },
             )
-            .unwrap()
-        });
-        let f = async {
-            let output = fut.await.unwrap();
-            Python::with_gil(|py| output.extract::<&str>(py).unwrap().to_string())
+            .await;
         }
-        .into_actor(ws)
-        .map(|res, _, ctx| ctx.text(res));
-        ctx.spawn(f);
-    } else {
-        Python::with_gil(|py| {
-            if let Some(op) = get_function_output(function, text, py, ws)
-                .unwrap()
-                .extract::<Option<&str>>()
-                .unwrap()
-            {
-                ctx.text(op);
+    });
+}
+
+/// Reports the upgrades refused by `start_web_socket` to the "upgrade_failed" handler of the route,
+/// e.g. to count them. Nothing is collected for the routes that don't have one
+struct UpgradeFailures(Option<(FunctionInfo, TaskLocals, Value)>);
+
+impl UpgradeFailures {
+    fn new(
+        router: &HashMap<String, FunctionInfo>,
+        task_locals: &TaskLocals,
+        req: &HttpRequest,
+        route: &str,
+        remote_ip: Option<&str>,
+        headers: &HashMap<String, String>,
+        query_params: &HashMap<String, String>,
+    ) -> Self {
+        Self(router.get("upgrade_failed").map(|function| {
+            let request = json!({
+                "route": route,
+                "path": req.path(),
+                "remote_ip": remote_ip,
+                "headers": headers,
+                "query_params": query_params,
+            });
+            (function.clone(), task_locals.clone(), request)
+        }))
+    }
+
+    /// Calls the handler with the reason and the request, and returns the response that refuses it
+    fn report(&self, reason: &'static str, response: HttpResponse) -> HttpResponse {
+        let Some((function, task_locals, request)) = &self.0 else {
+            return response;
+        };
+        let mut request = request.clone();
+        request["status"] = response.status().as_u16().into();
+        spawn_detached_handler(
+            function.clone(),
+            task_locals.clone(),
+            format!(
+                "The upgrade_failed handler of {}",
+                request["route"].as_str().unwrap_or_default()
+            ),
+            move |py| {
+                vec![
+                    reason.to_object(py),
+                    pythonize(py, &request).unwrap_or_else(|_| py.None()),
+                ]
+            },
+        );
+        response
+    }
+}
+
+impl OutboundQueue {
+    fn new(
+        capacity: usize,
+        policy: BackpressurePolicy,
+        high_water_mark: Option<usize>,
+        handler: Option<(FunctionInfo, TaskLocals)>,
+        drained_handler: Option<(FunctionInfo, TaskLocals)>,
+    ) -> Self {
+        Self {
+            depth: AtomicUsize::new(0),
+            dropped_oldest: AtomicUsize::new(0),
+            capacity,
+            policy,
+            backed_up: AtomicBool::new(false),
+            high_water: high_water_mark.map(|mark| HighWater {
+                mark,
+                above: AtomicBool::new(false),
+                handler,
+                draining: AtomicBool::new(false),
+                drained_handler,
+            }),
+        }
+    }
+
+    /// The number of frames waiting to be sent
+    fn len(&self) -> usize {
+        self.depth
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.dropped_oldest.load(Ordering::Relaxed))
+    }
+
+    /// Returns false when the frame must be dropped instead of being queued
+    fn push(&self, id: &Uuid) -> bool {
+        if self.len() >= self.capacity {
+            if !self.backed_up.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "Web socket {} has {} frames waiting to be sent, the client is too slow",
+                    id, self.capacity
+                );
             }
-        });
+            match self.policy {
+                BackpressurePolicy::Unbounded => (),
+                BackpressurePolicy::DropNewest => return false,
+                BackpressurePolicy::DropOldest => {
+                    self.dropped_oldest.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        self.depth.fetch_add(1, Ordering::Relaxed);
+        if let Some(high_water) = &self.high_water {
+            let depth = self.len();
+            if depth >= high_water.mark && !high_water.above.swap(true, Ordering::Relaxed) {
+                high_water.draining.store(true, Ordering::Relaxed);
+                high_water.notify(id, depth);
+            }
+        }
+        true
+    }
+
+    /// Called when the actor handles a queued frame, returns false when the frame must be dropped
+    fn pop(&self, id: &Uuid) -> bool {
+        self.depth.fetch_sub(1, Ordering::Relaxed);
+        let dropped = self
+            .dropped_oldest
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok();
+        if self.len() < self.capacity {
+            self.backed_up.store(false, Ordering::Relaxed);
+        }
+        if let Some(high_water) = &self.high_water {
+            let depth = self.len();
+            if depth < high_water.mark {
+                high_water.above.store(false, Ordering::Relaxed);
+            }
+            if depth == 0 && high_water.draining.swap(false, Ordering::Relaxed) {
+                high_water.notify_drained(id);
+            }
+        }
+        !dropped
     }
 }
 
-// By default mailbox capacity is 16 messages.
-impl Actor for MyWs {
-    type Context = ws::WebsocketContext<Self>;
+/// Counts the text and binary frames, relaxed atomics are enough as they are only read for reporting
+struct Metrics {
+    frames_received: AtomicU64,
+    bytes_received: AtomicU64,
+    frames_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    /// the frames of the connections with permessage-deflate, split by the `compression_threshold`
+    compressed_frames_sent: AtomicU64,
+    uncompressed_frames_sent: AtomicU64,
+    /// what the compressed frames weigh once compressed
+    compressed_bytes_sent: AtomicU64,
+}
 
-    fn started(&mut self, ctx: &mut Self::Context) {
-        let function = self.router.get("connect").unwrap();
-        execute_ws_function(function, None, &self.task_locals, ctx, self);
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            frames_received: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            frames_sent: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            compressed_frames_sent: AtomicU64::new(0),
+            uncompressed_frames_sent: AtomicU64::new(0),
+            compressed_bytes_sent: AtomicU64::new(0),
+        }
+    }
 
-        debug!("Actor is alive");
+    fn record_received(&self, bytes: usize) {
+        self.frames_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
     }
 
-    fn stopped(&mut self, ctx: &mut Self::Context) {
-        let function = self.router.get("close").unwrap();
-        execute_ws_function(function, None, &self.task_locals, ctx, self);
+    fn record_sent(&self, bytes: usize) {
+        self.frames_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a frame of a connection with permessage-deflate, with its compressed size when it
+    /// was compressed
+    fn record_compression(&self, compressed: Option<usize>) {
+        match compressed {
+            Some(bytes) => {
+                self.compressed_frames_sent.fetch_add(1, Ordering::Relaxed);
+                self.compressed_bytes_sent
+                    .fetch_add(bytes as u64, Ordering::Relaxed);
+            }
+            None => {
+                self.uncompressed_frames_sent
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
 
-        debug!("Actor is dead");
+    fn to_json(&self) -> Value {
+        json!({
+            "frames_received": self.frames_received.load(Ordering::Relaxed),
+            "bytes_received": self.bytes_received.load(Ordering::Relaxed),
+            "frames_sent": self.frames_sent.load(Ordering::Relaxed),
+            "bytes_sent": self.bytes_sent.load(Ordering::Relaxed),
+            "compressed_frames_sent": self.compressed_frames_sent.load(Ordering::Relaxed),
+            "uncompressed_frames_sent": self.uncompressed_frames_sent.load(Ordering::Relaxed),
+            "compressed_bytes_sent": self.compressed_bytes_sent.load(Ordering::Relaxed),
+        })
     }
 }
 
-#[derive(Message)]
-#[rtype(result = "Result<(), ()>")]
-struct CommandRunner(String);
+/// The frames of every web socket connection of this process
+static GLOBAL_METRICS: Metrics = Metrics::new();
 
-/// Handler for ws::Message message
-impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for MyWs {
-    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
-        match msg {
-            Ok(ws::Message::Ping(msg)) => {
-                debug!("Ping message {:?}", msg);
-                let function = self.router.get("connect").unwrap();
-                debug!("{:?}", function.handler);
-                execute_ws_function(function, None, &self.task_locals, ctx, self);
-                ctx.pong(&msg)
+/// What the registry keeps for every connection
+#[derive(Clone)]
+struct Connection {
+    addr: Addr<MyWs>,
+    metrics: Arc<Metrics>,
+    connected_at: Instant,
+    queue: Arc<OutboundQueue>,
+    closing: Arc<AtomicBool>,
+    /// the `ack_field` of its route, it is only sent the frames of `broadcast_with_ack` when set
+    ack_field: Option<String>,
+}
+
+impl Connection {
+    /// Returns false once the client or the server started closing it
+    fn is_open(&self) -> bool {
+        !self.closing.load(Ordering::Relaxed) && self.addr.connected()
+    }
+
+    /// Stops passing the messages of the client to the handlers until the connection is resumed.
+    /// Returns false when the connection is closing
+    fn pause(&self) -> bool {
+        if !self.is_open() {
+            return false;
+        }
+        self.addr.do_send(Pause);
+        true
+    }
+
+    /// Passes the messages received while the connection was paused to the handlers, in order.
+    /// Returns false when the connection is closing
+    fn resume(&self) -> bool {
+        if !self.is_open() {
+            return false;
+        }
+        self.addr.do_send(Resume);
+        true
+    }
+
+    /// Closes the connection, the "close" handler receives the reason.
+    /// Returns false when the connection is already closing
+    fn kick(&self, close_reason: CloseReason) -> bool {
+        if !self.is_open() {
+            return false;
+        }
+        self.addr.do_send(Kick(close_reason));
+        true
+    }
+
+    /// Queues the frame in the mailbox of the connection unless the client is too slow.
+    /// Returns false when the frame was dropped, by the backpressure policy or because
+    /// the connection is closing
+    fn send<M>(&self, id: &Uuid, msg: M) -> bool
+    where
+        M: Message + Send + 'static,
+        M::Result: Send,
+        MyWs: Handler<M>,
+    {
+        if !self.is_open() {
+            return false;
+        }
+        if !self.queue.push(id) {
+            return false;
+        }
+        self.addr.do_send(msg);
+        true
+    }
+
+    fn run(&self, id: &Uuid, command: HeldCommand) -> bool {
+        match command {
+            HeldCommand::Send(message) => self.send(id, CommandRunner(message)),
+            HeldCommand::Ping(payload) => {
+                if !self.is_open() {
+                    return false;
+                }
+                self.addr.do_send(SendPing(payload));
+                true
             }
-            Ok(ws::Message::Pong(msg)) => {
-                debug!("Pong message {:?}", msg);
+            HeldCommand::Close(close_reason) => self.kick(close_reason),
+        }
+    }
+}
+
+/// What the handle of a connection asks of it
+enum HeldCommand {
+    Send(Payload),
+    Ping(Bytes),
+    Close(CloseReason),
+}
+
+enum UpgradeState {
+    /// the "connect" handler runs, the commands wait for the actor
+    Running(Vec<HeldCommand>),
+    Started(Connection),
+    Failed,
+}
+
+/// A connection that is being upgraded. Its actor doesn't exist yet, so the commands that its
+/// handle receives, e.g. in the "connect" handler, are held until the actor started.
+/// They are dropped when the upgrade fails
+struct Upgrade {
+    id: Uuid,
+    state: Arc<Mutex<UpgradeState>>,
+}
+
+impl Upgrade {
+    fn start(id: Uuid) -> Self {
+        let state = Arc::new(Mutex::new(UpgradeState::Running(Vec::new())));
+        upgrades().insert(id, state.clone());
+        Self { id, state }
+    }
+
+    /// Sends the held commands to the actor that started
+    fn started(self, connection: &Connection) {
+        let held = std::mem::replace(
+            &mut *self.state.lock().unwrap(),
+            UpgradeState::Started(connection.clone()),
+        );
+        if let UpgradeState::Running(held) = held {
+            for command in held {
+                connection.run(&self.id, command);
             }
-            Ok(ws::Message::Text(text)) => {
-                // need to also pass this text as a param
-                let function = self.router.get("message").unwrap();
-                execute_ws_function(
-                    function,
-                    Some(text.to_string()),
-                    &self.task_locals,
-                    ctx,
-                    self,
-                );
+        }
+    }
+}
+
+impl Drop for Upgrade {
+    fn drop(&mut self) {
+        upgrades().remove(&self.id);
+        let mut state = self.state.lock().unwrap();
+        if matches!(*state, UpgradeState::Running(_)) {
+            *state = UpgradeState::Failed;
+        }
+    }
+}
+
+/// The connections whose "connect" handler runs
+fn upgrades() -> &'static DashMap<Uuid, Arc<Mutex<UpgradeState>>> {
+    static UPGRADES: OnceLock<DashMap<Uuid, Arc<Mutex<UpgradeState>>>> = OnceLock::new();
+    UPGRADES.get_or_init(DashMap::new)
+}
+
+/// The number of connections that a broadcast frame was queued for and dropped for
+#[derive(Default)]
+struct Delivery {
+    delivered: usize,
+    failed: usize,
+}
+
+impl Delivery {
+    fn record(&mut self, delivered: bool) {
+        if delivered {
+            self.delivered += 1;
+        } else {
+            self.failed += 1;
+        }
+    }
+
+    fn to_object(&self, py: Python) -> PyObject {
+        [("delivered", self.delivered), ("failed", self.failed)]
+            .into_py_dict(py)
+            .into()
+    }
+}
+
+/// How often the idle connections are looked for
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often a closing connection checks whether its mailbox was drained
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The number of web socket connections that are alive or being upgraded in this process
+static ACTIVE_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// The upgrade is refused with a 503 once this many connections are alive, unset means no limit
+static MAX_CONNECTIONS: OnceLock<usize> = OnceLock::new();
+
+pub fn set_max_connections(max_connections: usize) {
+    if MAX_CONNECTIONS.set(max_connections).is_err() {
+        debug!("The maximum number of web socket connections is already set");
+    }
+}
+
+/// One of the `MAX_CONNECTIONS`, taken when the upgrade starts so that concurrent upgrades
+/// can't all get in below the limit, and given back when it is dropped
+struct ConnectionSlot;
+
+impl ConnectionSlot {
+    /// None when the limit is reached
+    fn reserve() -> Option<Self> {
+        let max_connections = MAX_CONNECTIONS.get().copied().unwrap_or(usize::MAX);
+        ACTIVE_CONNECTIONS
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                (active < max_connections).then_some(active + 1)
+            })
+            .ok()
+            .map(|_| Self)
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// All the web socket connections that are alive in this process
+fn connections() -> &'static DashMap<Uuid, Connection> {
+    static CONNECTIONS: OnceLock<DashMap<Uuid, Connection>> = OnceLock::new();
+    CONNECTIONS.get_or_init(DashMap::new)
+}
+
+/// The ids of the connections that joined each room
+fn rooms() -> &'static DashMap<String, HashSet<Uuid>> {
+    static ROOMS: OnceLock<DashMap<String, HashSet<Uuid>>> = OnceLock::new();
+    ROOMS.get_or_init(DashMap::new)
+}
+
+/// The labels attached to each connection, e.g. `plan=pro`, to broadcast to the ones that match
+fn connection_tags() -> &'static DashMap<Uuid, HashMap<String, String>> {
+    static CONNECTION_TAGS: OnceLock<DashMap<Uuid, HashMap<String, String>>> = OnceLock::new();
+    CONNECTION_TAGS.get_or_init(DashMap::new)
+}
+
+/// The connections that the application gave its own id to, with `id_header` or from the
+/// "connect" handler, by that id
+fn client_ids() -> &'static DashMap<String, Uuid> {
+    static CLIENT_IDS: OnceLock<DashMap<String, Uuid>> = OnceLock::new();
+    CLIENT_IDS.get_or_init(DashMap::new)
+}
+
+/// The query param and the header that a reconnecting client passes its token in,
+/// the header is also the one of the handshake response that carries the new token
+const RECONNECT_TOKEN_PARAM: &str = "reconnect_token";
+const RECONNECT_TOKEN_HEADER: &str = "x-reconnect-token";
+
+/// The reconnection token issued to each connection of the routes with a `reconnect_ttl`
+fn reconnect_tokens() -> &'static DashMap<Uuid, String> {
+    static RECONNECT_TOKENS: OnceLock<DashMap<Uuid, String>> = OnceLock::new();
+    RECONNECT_TOKENS.get_or_init(DashMap::new)
+}
+
+/// The id that the application gave to each connection, the other way around
+fn connection_client_ids() -> &'static DashMap<Uuid, String> {
+    static CONNECTION_CLIENT_IDS: OnceLock<DashMap<Uuid, String>> = OnceLock::new();
+    CONNECTION_CLIENT_IDS.get_or_init(DashMap::new)
+}
+
+/// Gives the id of the application to the connection, in place of the one it had before if any.
+/// Returns false when the id is taken by another connection and the ids are unique
+fn register_client_id(id: Uuid, client_id: String, unique: bool) -> bool {
+    match client_ids().entry(client_id.clone()) {
+        Entry::Occupied(entry) if *entry.get() != id && unique => return false,
+        Entry::Occupied(mut entry) => {
+            entry.insert(id);
+        }
+        Entry::Vacant(entry) => {
+            entry.insert(id);
+        }
+    }
+    if let Some(previous) = connection_client_ids().insert(id, client_id.clone()) {
+        if previous != client_id {
+            client_ids().remove_if(&previous, |_, owner| *owner == id);
+        }
+    }
+    true
+}
+
+/// The id that is passed to the handlers, the one given by the application or the UUID
+fn websocket_id(id: &Uuid) -> String {
+    connection_client_ids()
+        .get(id)
+        .map_or_else(|| id.to_string(), |client_id| client_id.clone())
+}
+
+/// The connections whose handlers and frames are logged, to debug a single client
+fn verbose_connections() -> &'static DashSet<Uuid> {
+    static VERBOSE_CONNECTIONS: OnceLock<DashSet<Uuid>> = OnceLock::new();
+    VERBOSE_CONNECTIONS.get_or_init(DashSet::new)
+}
+
+/// Removes the connection from the registry, from all of its rooms and drops its tags
+fn forget_connection(id: &Uuid) {
+    connections().remove(id);
+    verbose_connections().remove(id);
+    connection_tags().remove(id);
+    reconnect_tokens().remove(id);
+    if let Some((_, client_id)) = connection_client_ids().remove(id) {
+        // a newer connection can have taken the id over
+        client_ids().remove_if(&client_id, |_, owner| owner == id);
+    }
+    rooms().retain(|_, members| {
+        members.remove(id);
+        !members.is_empty()
+    });
+}
+
+/// Serializes the close reason that is passed to the "close" handler
+/// e.g. `{"code": 1001, "reason": "going away"}`
+fn close_reason_to_json(close_reason: &CloseReason) -> String {
+    json!({
+        "code": u16::from(close_reason.code),
+        "reason": close_reason.description.clone().unwrap_or_default(),
+    })
+    .to_string()
+}
+
+/// The message that is passed to the handler as its second param
+/// or the frame that is sent back from the handler's return value
+#[derive(Clone, Debug, Message)]
+#[rtype(result = "()")]
+enum WsMessage {
+    Text(String),
+    Binary(Bytes),
+    /// a text message that was parsed in JSON mode, handlers receive the parsed value
+    Json(Value),
+    /// a binary message that was decoded in msgpack or CBOR mode
+    Decoded(PyObject, BinaryCodec),
+    /// handlers receive the close reason as a JSON string
+    Close(CloseReason),
+    /// a handler returned a list, every element is sent as its own frame
+    Batch(Vec<WsMessage>),
+}
+
+impl ToPyObject for WsMessage {
+    fn to_object(&self, py: Python) -> PyObject {
+        match self {
+            WsMessage::Text(text) => text.to_object(py),
+            WsMessage::Binary(bin) => PyBytes::new(py, bin).into(),
+            // every JSON value has a python equivalent
+            WsMessage::Json(value) => pythonize(py, value).unwrap_or_else(|_| py.None()),
+            WsMessage::Decoded(value, _) => value.clone_ref(py),
+            WsMessage::Close(close_reason) => close_reason_to_json(close_reason).to_object(py),
+            WsMessage::Batch(messages) => messages.to_object(py),
+        }
+    }
+}
+
+impl WsMessage {
+    fn send(self, ws: &MyWs, ctx: &mut ws::WebsocketContext<MyWs>) {
+        if ws.is_verbose() && !matches!(self, WsMessage::Batch(_)) {
+            info!("Web socket {} sends {:?}", ws.log_context(), self);
+        }
+        match self {
+            WsMessage::Text(text) => ws.send_text(text, ctx),
+            WsMessage::Binary(bin) => ws.send_binary(bin, ctx),
+            WsMessage::Json(value) => ws.send_text(value.to_string(), ctx),
+            WsMessage::Decoded(value, codec) => {
+                match Python::with_gil(|py| codec.encode(value.as_ref(py))) {
+                    Ok(bin) => ws.send_binary(bin.into(), ctx),
+                    Err(e) => error!(
+                        "Cannot encode web socket message for {} - {}",
+                        ws.log_context(),
+                        get_traceback(&e)
+                    ),
+                }
             }
-            Ok(ws::Message::Binary(bin)) => ctx.binary(bin),
-            Ok(ws::Message::Close(_close_reason)) => {
-                debug!("Socket was closed");
-                let function = self.router.get("close").unwrap();
-                execute_ws_function(function, None, &self.task_locals, ctx, self);
+            // the "close" handler is executed once the actor has stopped
+            WsMessage::Close(close_reason) => ws.close(close_reason, ctx),
+            WsMessage::Batch(messages) => {
+                for message in messages {
+                    message.send(ws, ctx);
+                }
             }
-            _ => (),
         }
     }
 }
 
-pub async fn start_web_socket(
-    req: HttpRequest,
-    stream: web::Payload,
-    router: HashMap<String, FunctionInfo>,
-    task_locals: TaskLocals,
-) -> Result<HttpResponse, Error> {
-    ws::start(
-        MyWs {
-            router,
-            task_locals,
-            id: Uuid::new_v4(),
-        },
-        &req,
-        stream,
-    )
+/// Converts the return value of a handler to the frame that is sent to the client
+/// bytes are sent as a binary frame, strings as a text frame, a `CloseConnection`
+/// closes the connection and None sends nothing.
+/// The elements of a list or a tuple are sent as separate frames, in order.
+/// Any other value is sent as JSON, or as a binary frame encoded with the codec of the route
+fn extract_output(
+    output: &PyAny,
+    binary_codec: Option<BinaryCodec>,
+) -> PyResult<Option<WsMessage>> {
+    if output.is_instance_of::<PyList>() || output.is_instance_of::<PyTuple>() {
+        let mut messages = Vec::new();
+        for item in output.iter()? {
+            if let Some(message) = extract_output(item?, binary_codec)? {
+                messages.push(message);
+            }
+        }
+        return Ok(Some(WsMessage::Batch(messages)));
+    }
+    if let Ok(bin) = output.downcast::<PyBytes>() {
+        return Ok(Some(WsMessage::Binary(Bytes::copy_from_slice(
+            bin.as_bytes(),
+        ))));
+    }
+    if let Ok(close) = output.extract::<CloseConnection>() {
+        return Ok(Some(WsMessage::Close(CloseReason {
+            code: close.code.into(),
+            description: close.reason,
+        })));
+    }
+    if output.is_none() {
+        return Ok(None);
+    }
+    if let Ok(text) = output.extract::<&str>() {
+        return Ok(Some(WsMessage::Text(text.to_string())));
+    }
+    if let Some(codec) = binary_codec {
+        return Ok(Some(WsMessage::Binary(codec.encode(output)?.into())));
+    }
+    // e.g. a dict, sent as a JSON text frame
+    let value: Value = depythonize(output).map_err(|e| {
+        PyValueError::new_err(format!(
+            "Cannot send {} over a web socket - {}",
+            output.get_type().name().unwrap_or("the value"),
+            e
+        ))
+    })?;
+    Ok(Some(WsMessage::Json(value)))
+}
+
+/// The params passed to the web socket handlers, in order.
+/// A handler that declares `n` params receives the first `n` of them
+const HANDLER_PARAMS: [&str; 13] = [
+    "websocket_id",
+    "msg",
+    "query_params",
+    "headers",
+    "state",
+    "remote_ip",
+    "cookies",
+    "path_params",
+    "connected_at",
+    "subprotocol",
+    "route",
+    "queue_depth",
+    "extensions",
+];
+
+/// Explains the errors raised while python binds the arguments to the params of a handler.
+/// Those are raised before the handler starts running, so unlike the errors raised by the
+/// handler itself they have no traceback
+fn signature_error(function: &FunctionInfo, error: PyErr, py: Python) -> PyErr {
+    if !error.is_instance_of::<PyTypeError>(py) || error.traceback(py).is_some() {
+        return error;
+    }
+    let passed = HANDLER_PARAMS[..function.number_of_params as usize].join(", ");
+    PyTypeError::new_err(format!(
+        "The web socket handler {} declares {} params and was called with ({}) - {}",
+        handler_name(function, py),
+        function.number_of_params,
+        passed,
+        error.value(py)
+    ))
+}
+
+fn handler_name(function: &FunctionInfo, py: Python) -> String {
+    let handler = function.handler.as_ref(py);
+    handler
+        .getattr("__qualname__")
+        .map_or_else(|_| handler.to_string(), |name| name.to_string())
+}
+
+fn get_function_output<'a>(
+    function: &'a FunctionInfo,
+    mut fn_msg: Option<WsMessage>,
+    py: Python<'a>,
+    ws: &MyWs,
+) -> Result<&'a PyAny, PyErr> {
+    let handler = function.handler.as_ref(py);
+    if function.number_of_params as usize > HANDLER_PARAMS.len() {
+        return Err(PyTypeError::new_err(format!(
+            "The web socket handler {} declares {} params but at most {} are passed ({})",
+            handler_name(function, py),
+            function.number_of_params,
+            HANDLER_PARAMS.len(),
+            HANDLER_PARAMS.join(", ")
+        )));
+    }
+
+    let websocket_id = websocket_id(&ws.id);
+    // this makes the request object accessible across every route.
+    // Only the params that the handler declares are built, in the order of `HANDLER_PARAMS`.
+    // `WebSocketRequest` in robyn/ws.py bundles these params, keep them in sync
+    let args: Vec<PyObject> = (0..function.number_of_params as usize)
+        .map(|param| match param {
+            0 => websocket_id.to_object(py),
+            1 => fn_msg
+                .take()
+                .map_or_else(|| "".to_object(py), |msg| msg.to_object(py)),
+            2 => ws.query_params.to_object(py),
+            3 => ws.headers.to_object(py),
+            4 => ws.state.to_object(py),
+            5 => ws.remote_ip.to_object(py),
+            6 => ws.cookies.to_object(py),
+            7 => ws.path_params.to_object(py),
+            8 => ws.connected_at_epoch.to_object(py),
+            9 => ws.subprotocol.to_object(py),
+            10 => ws.route.to_object(py),
+            // read at every call, so that a handler sending a lot can slow down
+            11 => ws.queue.len().to_object(py),
+            _ => ws.extensions.to_object(py),
+        })
+        .collect();
+    let output = handler.call1(PyTuple::new(py, args));
+    output.map_err(|e| signature_error(function, e, py))
+}
+
+/// Sends every value yielded by an async generator handler as its own frame.
+/// The task is dropped with the actor, so a closed connection stops the iteration
+fn stream_async_generator(
+    generator: PyObject,
+    task_locals: &TaskLocals,
+    ctx: &mut ws::WebsocketContext<MyWs>,
+    ws: &MyWs,
+) {
+    let task_locals = task_locals.clone();
+    let connection = Connection {
+        addr: ctx.address(),
+        metrics: ws.metrics.clone(),
+        connected_at: ws.connected_at,
+        queue: ws.queue.clone(),
+        closing: ws.closing.clone(),
+        ack_field: ws.config.ack_field.clone(),
+    };
+    let id = ws.id;
+    let binary_codec = ws.config.binary_codec;
+    let log_context = ws.log_context();
+    let f = async move {
+        loop {
+            let next = Python::with_gil(|py| {
+                pyo3_asyncio::into_future_with_locals(
+                    &task_locals,
+                    generator.as_ref(py).call_method0("__anext__")?,
+                )
+            })?;
+            let item = match next.await {
+                Ok(item) => item,
+                Err(e) if Python::with_gil(|py| e.is_instance_of::<PyStopAsyncIteration>(py)) => {
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+            if let Some(message) =
+                Python::with_gil(|py| extract_output(item.as_ref(py), binary_codec))?
+            {
+                // the frames go through the mailbox to keep their order
+                connection.send(&id, message);
+            }
+        }
+    }
+    .into_actor(ws)
+    .map(move |res: PyResult<()>, act, ctx| {
+        if let Err(e) = res {
+            error!(
+                "Error while streaming to web socket {}: {}",
+                log_context,
+                get_traceback(&e)
+            );
+            act.send_handler_error(&e, ctx);
+        }
+    });
+    ws.spawn_handler(f, ctx);
+}
+
+/// Logs the error of the background task of a connection once it is done.
+/// Its own frames are sent with `send_to`, so there is no output to send
+#[pyclass]
+struct BackgroundDone {
+    log_context: String,
+}
+
+#[pymethods]
+impl BackgroundDone {
+    fn __call__(&self, future: &PyAny) -> PyResult<()> {
+        if future.call_method0("cancelled")?.is_true()? {
+            return Ok(());
+        }
+        let exception = future.call_method0("exception")?;
+        if !exception.is_none() {
+            error!(
+                "The background task of web socket {} raised an error: {}",
+                self.log_context,
+                get_traceback(&PyErr::from_value(exception))
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Replaces the `{type}` and `{message}` placeholders in the strings of the template
+fn fill_error_frame(template: Value, error_type: &str, message: &str) -> Value {
+    match template {
+        Value::String(text) => Value::String(
+            text.replace("{type}", error_type)
+                .replace("{message}", message),
+        ),
+        Value::Array(items) => Value::Array(
+            items
+                .into_iter()
+                .map(|item| fill_error_frame(item, error_type, message))
+                .collect(),
+        ),
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key, fill_error_frame(value, error_type, message)))
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+/// Calls an async handler and sends its output once it is done.
+/// The coroutine is scheduled on the event loop right away, the returned future only waits for it
+fn call_async_function(
+    function: &FunctionInfo,
+    text: Option<WsMessage>,
+    task_locals: &TaskLocals,
+    ws: &MyWs,
+) -> impl ActorFuture<MyWs, Output = ()> {
+    let fut = Python::with_gil(|py| {
+        let mut coroutine = get_function_output(function, text, py, ws)?;
+        if let Some(timeout) = ws.config.handler_timeout {
+            // dropping the rust future would leave the coroutine running, wait_for cancels it
+            coroutine = py
+                .import("asyncio")?
+                .call_method1("wait_for", (coroutine, timeout.seconds))?;
+        }
+        pyo3_asyncio::into_future_with_locals(task_locals, coroutine)
+    });
+    let binary_codec = ws.config.binary_codec;
+    async move {
+        let output = fut?.await?;
+        Python::with_gil(|py| extract_output(output.as_ref(py), binary_codec))
+    }
+    .into_actor(ws)
+    .map(|res, act, ctx| match res {
+        Ok(Some(output)) => output.send(act, ctx),
+        Ok(None) => (),
+        Err(e) if act.config.handler_timeout.is_some() && is_timeout_error(&e) => {
+            act.handler_timed_out(&e, ctx)
+        }
+        // the connection is kept alive when the handler raises
+        Err(e) => {
+            error!(
+                "Error while executing web socket function for {}: {}",
+                act.log_context(),
+                get_traceback(&e)
+            );
+            act.send_handler_error(&e, ctx);
+        }
+    })
+}
+
+fn is_timeout_error(error: &PyErr) -> bool {
+    Python::with_gil(|py| {
+        py.import("asyncio")
+            .and_then(|asyncio| asyncio.getattr("TimeoutError"))
+            .is_ok_and(|timeout_error| error.is_instance(py, timeout_error))
+    })
+}
+
+fn execute_ws_function(
+    function: &FunctionInfo,
+    text: Option<WsMessage>,
+    task_locals: &TaskLocals,
+    ctx: &mut ws::WebsocketContext<MyWs>,
+    ws: &MyWs,
+    // add number of params here
+) {
+    if ws.is_verbose() {
+        Python::with_gil(|py| {
+            info!(
+                "Web socket {} calls the handler {} with {:?}",
+                ws.log_context(),
+                handler_name(function, py),
+                text
+            )
+        });
+    }
+    if function.is_async && ws.keeps_order(ctx) {
+        // the handler is only called once the handlers of the previous messages are done
+        let function = function.clone();
+        let task_locals = task_locals.clone();
+        let f = fut::ready(()).then(move |_, act: &mut MyWs, _| {
+            call_async_function(&function, text, &task_locals, act)
+        });
+        ws.spawn_handler(f, ctx);
+    } else if function.is_async {
+        ctx.spawn(call_async_function(function, text, task_locals, ws));
+    } else {
+        // a panic while running the handler must not take down the worker with it
+        let output = panic::catch_unwind(AssertUnwindSafe(|| {
+            Python::with_gil(|py| {
+                let output = get_function_output(function, text, py, ws)?;
+                // calling an async generator function returns the generator right away
+                if output.hasattr("__anext__")? {
+                    stream_async_generator(output.into(), task_locals, ctx, ws);
+                    return Ok(None);
+                }
+                extract_output(output, ws.config.binary_codec)
+            })
+        }));
+        match output {
+            Ok(Ok(Some(output))) => output.send(ws, ctx),
+            Ok(Ok(None)) => (),
+            // the connection is kept alive when the handler raises
+            Ok(Err(e)) => {
+                error!(
+                    "Error while executing web socket function for {}: {}",
+                    ws.log_context(),
+                    get_traceback(&e)
+                );
+                ws.send_handler_error(&e, ctx);
+            }
+            Err(_) => error!(
+                "Panic while executing web socket function for {}",
+                ws.log_context()
+            ),
+        }
+    }
+}
+
+/// What the "connect" handler decided for the connection
+enum ConnectResult {
+    /// the connection is upgraded and the output, if any, is sent as the first frame
+    Accept(Option<WsMessage>),
+    /// the handler returned an `AcceptConnection`, that can pick the subprotocol and the id
+    /// of the connection
+    AcceptConnection {
+        subprotocol: Option<String>,
+        id: Option<String>,
+        output: Option<WsMessage>,
+        background: Option<PyObject>,
+        headers: Vec<(header::HeaderName, header::HeaderValue)>,
+    },
+    /// the handler returned `False`, the upgrade is refused
+    Reject,
+    /// the handler returned a `CloseConnection`, the connection is upgraded
+    /// and closed right away with its code, for the clients that only look at the close code
+    Close(CloseReason),
+}
+
+/// The "connect" handler runs before the connection is upgraded
+/// so that it can refuse the connection
+async fn execute_connect_function(function: &FunctionInfo, ws: &MyWs) -> PyResult<ConnectResult> {
+    let output: Py<PyAny> = if function.is_async {
+        Python::with_gil(|py| {
+            pyo3_asyncio::into_future_with_locals(
+                &ws.task_locals,
+                get_function_output(function, None, py, ws)?,
+            )
+        })?
+        .await?
+    } else {
+        Python::with_gil(|py| get_function_output(function, None, py, ws).map(Into::into))?
+    };
+
+    Python::with_gil(|py| connect_result(output.as_ref(py), ws.config.binary_codec))
+}
+
+/// What the return value of the "connect" handler asks for
+fn connect_result(output: &PyAny, binary_codec: Option<BinaryCodec>) -> PyResult<ConnectResult> {
+    if let Ok(accepted) = output.downcast::<PyBool>() {
+        if accepted.is_true() {
+            return Ok(ConnectResult::Accept(None));
+        }
+        return Ok(ConnectResult::Reject);
+    }
+    if let Ok(accept) = output.extract::<AcceptConnection>() {
+        let message = match &accept.message {
+            Some(message) => extract_output(message.as_ref(output.py()), binary_codec)?,
+            None => None,
+        };
+        let headers = match accept.headers {
+            Some(headers) => handshake_headers(headers)?,
+            None => Vec::new(),
+        };
+        return Ok(ConnectResult::AcceptConnection {
+            subprotocol: accept.subprotocol,
+            id: accept.id,
+            output: message,
+            background: accept.background,
+            headers,
+        });
+    }
+    // the other shapes are converted like the return value of any handler
+    match extract_output(output, binary_codec)? {
+        Some(WsMessage::Close(reason)) => Ok(ConnectResult::Close(reason)),
+        output => Ok(ConnectResult::Accept(output)),
+    }
+}
+
+/// The headers of the handshake response that actix or robyn set, which the "connect" handler
+/// can't replace without breaking the upgrade
+const PROTECTED_HANDSHAKE_HEADERS: [&str; 9] = [
+    "connection",
+    "upgrade",
+    "sec-websocket-accept",
+    "sec-websocket-protocol",
+    "sec-websocket-extensions",
+    "sec-websocket-version",
+    "content-length",
+    "transfer-encoding",
+    RECONNECT_TOKEN_HEADER,
+];
+
+/// Checks the headers that the "connect" handler adds to the handshake response
+fn handshake_headers(
+    headers: HashMap<String, String>,
+) -> PyResult<Vec<(header::HeaderName, header::HeaderValue)>> {
+    headers
+        .into_iter()
+        .map(|(name, value)| {
+            let header_name = header::HeaderName::from_bytes(name.as_bytes()).map_err(|_| {
+                PyValueError::new_err(format!("{name:?} is not a valid header name"))
+            })?;
+            if PROTECTED_HANDSHAKE_HEADERS.contains(&header_name.as_str()) {
+                return Err(PyValueError::new_err(format!(
+                    "The connect handler can't set the {name} header of the handshake response"
+                )));
+            }
+            let header_value = header::HeaderValue::from_str(&value).map_err(|_| {
+                PyValueError::new_err(format!("{value:?} is not a valid value for {name}"))
+            })?;
+            Ok((header_name, header_value))
+        })
+        .collect()
+}
+
+impl Actor for MyWs {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        // actix defaults the mailbox capacity to 16 messages
+        ctx.set_mailbox_capacity(self.config.mailbox_capacity);
+
+        if let Some(heartbeat_interval) = self.config.heartbeat_interval.map(Duration::from_secs) {
+            ctx.run_interval(heartbeat_interval, |act, ctx| {
+                if act.close_sent {
+                    return;
+                }
+                ctx.ping(b"");
+                // the timeout runs from the ping, however it compares to the interval
+                if act.unanswered_ping.is_some() {
+                    return;
+                }
+                let sent_at = Instant::now();
+                act.unanswered_ping = Some(sent_at);
+                let heartbeat_timeout = Duration::from_secs(act.config.heartbeat_timeout);
+                ctx.run_later(heartbeat_timeout, move |act, ctx| {
+                    if act.unanswered_ping == Some(sent_at) {
+                        debug!("Web socket {} missed its heartbeat, disconnecting", act.id);
+                        ctx.stop();
+                    }
+                });
+            });
+        }
+
+        if let Some(app_heartbeat) = &self.config.app_heartbeat {
+            ctx.run_interval(Duration::from_secs(app_heartbeat.interval), |act, ctx| {
+                let Some(app_heartbeat) = &act.config.app_heartbeat else {
+                    return;
+                };
+                act.send_text(app_heartbeat.frame.clone(), ctx);
+                // like the pings, the timeout runs from the heartbeat
+                if app_heartbeat.pong.is_none() || act.unanswered_app_ping.is_some() {
+                    return;
+                }
+                let sent_at = Instant::now();
+                act.unanswered_app_ping = Some(sent_at);
+                let heartbeat_timeout = Duration::from_secs(act.config.heartbeat_timeout);
+                ctx.run_later(heartbeat_timeout, move |act, ctx| {
+                    if act.unanswered_app_ping == Some(sent_at) {
+                        debug!(
+                            "Web socket {} missed its app heartbeat, disconnecting",
+                            act.id
+                        );
+                        ctx.stop();
+                    }
+                });
+            });
+        }
+
+        if let Some(idle_timeout) = self.config.idle_timeout.map(Duration::from_secs) {
+            self.last_activity = Instant::now();
+            ctx.run_interval(IDLE_CHECK_INTERVAL.min(idle_timeout), move |act, ctx| {
+                if Instant::now().duration_since(act.last_activity) > idle_timeout {
+                    debug!("Web socket {} was idle for too long, disconnecting", act.id);
+                    let close_reason: CloseReason = CloseCode::Away.into();
+                    act.close_reason = Some(close_reason.clone());
+                    act.close(close_reason, ctx);
+                }
+            });
+        }
+
+        if let (Some(slow_consumer_timeout), Some(high_water_mark)) = (
+            self.config.slow_consumer_timeout.map(Duration::from_secs),
+            self.config.high_water_mark,
+        ) {
+            ctx.run_interval(
+                IDLE_CHECK_INTERVAL.min(slow_consumer_timeout),
+                move |act, ctx| {
+                    if act.queue.len() < high_water_mark {
+                        act.slow_since = None;
+                        return;
+                    }
+                    let slow_since = *act.slow_since.get_or_insert_with(Instant::now);
+                    if Instant::now().duration_since(slow_since) > slow_consumer_timeout {
+                        debug!(
+                            "Web socket {} can't keep up with its frames, disconnecting",
+                            act.id
+                        );
+                        let close_reason: CloseReason = CloseCode::Again.into();
+                        act.close_reason = Some(close_reason.clone());
+                        // the client wouldn't read the frames that wait any faster
+                        act.close_draining(close_reason, None, ctx);
+                    }
+                },
+            );
+        }
+
+        let connection = Connection {
+            addr: ctx.address(),
+            metrics: self.metrics.clone(),
+            connected_at: self.connected_at,
+            queue: self.queue.clone(),
+            closing: self.closing.clone(),
+            ack_field: self.config.ack_field.clone(),
+        };
+        connections().insert(self.id, connection.clone());
+        if let Some(upgrade) = self.upgrade.take() {
+            upgrade.started(&connection);
+        }
+        lifecycle::emit(
+            EventKind::Connected,
+            websocket_id(&self.id),
+            &self.route,
+            || None,
+        );
+
+        // the "connect" handler has already been executed during the handshake
+        if let Some(output) = self.connect_output.take() {
+            output.send(self, ctx);
+        }
+        if let Some(coroutine) = self.background.take() {
+            self.background_task = self.start_background(coroutine);
+        }
+
+        debug!("Web socket {} is alive", self.log_context());
+    }
+
+    fn stopping(&mut self, ctx: &mut Self::Context) -> Running {
+        self.closing.store(true, Ordering::Relaxed);
+        // e.g. the client answered the close frame or went away in the meantime
+        if self.close_running {
+            return Running::Continue;
+        }
+        // the actor stops again once an async "before_close" handler is done
+        if self.execute_before_close(ctx) {
+            return Running::Continue;
+        }
+        self.send_close_frame(ctx);
+        // nor would an async "close" handler run once the actor stopped
+        if self.execute_async_close(ctx) {
+            return Running::Continue;
+        }
+        Running::Stop
+    }
+
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        // stopped runs however the connection ended, so the count can't leak
+        self.slot.take();
+        self.save_for_reconnect();
+        let code = self
+            .close_reason
+            .as_ref()
+            .map_or(1006, |close_reason| close_reason.code.into());
+        lifecycle::emit(
+            EventKind::Disconnected,
+            websocket_id(&self.id),
+            &self.route,
+            || Some(code.to_string()),
+        );
+        forget_connection(&self.id);
+        if let Some(task) = self.background_task.take() {
+            // the task is cancelled on its event loop, at its next await
+            if let Err(e) = Python::with_gil(|py| task.call_method0(py, "cancel")) {
+                error!(
+                    "Couldn't cancel the background task of web socket {}: {}",
+                    self.log_context(),
+                    get_traceback(&e)
+                );
+            }
+        }
+
+        // the connection can be dropped without the client ever sending a close frame
+        // in that case we report an abnormal closure
+        let close_reason = self
+            .close_reason
+            .clone()
+            .unwrap_or_else(|| CloseCode::Abnormal.into());
+        // an async handler already ran while the actor was stopping
+        let function = if self.close_executed {
+            None
+        } else {
+            self.handler("close")
+        };
+        if let Some(function) = function {
+            execute_ws_function(
+                function,
+                Some(WsMessage::Close(close_reason)),
+                &self.task_locals,
+                ctx,
+                self,
+            );
+        }
+
+        // drop everything the handlers stored so that it doesn't outlive the connection
+        Python::with_gil(|py| self.state.as_ref(py).clear());
+
+        debug!("Web socket {} is dead", self.log_context());
+    }
+}
+
+/// A text or binary message pushed to a connection from outside of its handlers,
+/// `broadcast`, `send_to` and `broadcast_to_room` all go through it
+#[derive(Message)]
+#[rtype(result = "Result<(), ()>")]
+struct CommandRunner(Payload);
+
+/// The message of a `CommandRunner`, converted from python once however many connections
+/// it is sent to, cloning it only bumps a reference count
+#[derive(Debug, Clone)]
+pub enum Payload {
+    Text(ByteString),
+    Binary(Bytes),
+}
+
+impl<'source> FromPyObject<'source> for Payload {
+    fn extract(message: &'source PyAny) -> PyResult<Self> {
+        if let Ok(text) = message.downcast::<PyString>() {
+            Ok(Self::Text(text.to_str()?.into()))
+        } else if let Ok(bin) = message.downcast::<PyBytes>() {
+            Ok(Self::Binary(Bytes::copy_from_slice(bin.as_bytes())))
+        } else {
+            Err(PyTypeError::new_err(format!(
+                "Expected a str or bytes message, got {}",
+                message.get_type().name()?
+            )))
+        }
+    }
+}
+
+/// Sends a frame that was produced outside of the handler call, e.g. by an async generator
+impl Handler<WsMessage> for MyWs {
+    type Result = ();
+
+    fn handle(&mut self, msg: WsMessage, ctx: &mut Self::Context) -> Self::Result {
+        if self.queue.pop(&self.id) {
+            msg.send(self, ctx);
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct SendPing(Bytes);
+
+/// Pings the client, the pong is passed to the "pong" handler
+impl Handler<SendPing> for MyWs {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendPing, ctx: &mut Self::Context) -> Self::Result {
+        ctx.ping(&msg.0);
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct SendPong(Bytes);
+
+/// Answers a ping of the client, for the routes that don't do it automatically
+impl Handler<SendPong> for MyWs {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendPong, ctx: &mut Self::Context) -> Self::Result {
+        ctx.pong(&msg.0);
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Kick(CloseReason);
+
+/// Closes the connection from outside of its handlers, the "close" handler receives the reason
+impl Handler<Kick> for MyWs {
+    type Result = ();
+
+    fn handle(&mut self, msg: Kick, ctx: &mut Self::Context) -> Self::Result {
+        debug!("Web socket {} was kicked {:?}", self.log_context(), msg.0);
+        self.close_reason = Some(msg.0.clone());
+        self.close(msg.0, ctx);
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Pause;
+
+/// Holds the messages of the client until the connection is resumed.
+/// The control frames are still handled, so the heartbeats keep the connection alive
+impl Handler<Pause> for MyWs {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Pause, _ctx: &mut Self::Context) -> Self::Result {
+        if self.paused.is_none() {
+            debug!("Web socket {} is paused", self.id);
+            self.paused = Some(VecDeque::new());
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Resume;
+
+/// Passes the messages held while the connection was paused to the handlers, in order
+impl Handler<Resume> for MyWs {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Resume, ctx: &mut Self::Context) -> Self::Result {
+        let Some(held) = self.paused.take() else {
+            return;
+        };
+        debug!(
+            "Web socket {} is resumed with {} held messages",
+            self.id,
+            held.len()
+        );
+        for message in held {
+            self.dispatch(message, ctx);
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Shutdown;
+
+/// Closes the connection with 1001 (going away) as the server is shutting down
+impl Handler<Shutdown> for MyWs {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Shutdown, ctx: &mut Self::Context) -> Self::Result {
+        let close_reason = CloseReason {
+            code: CloseCode::Away,
+            description: Some("Server shutting down".to_string()),
+        };
+        self.close_reason = Some(close_reason.clone());
+        self.close(close_reason, ctx);
+    }
+}
+
+/// Sends the text of the command to the client
+impl Handler<CommandRunner> for MyWs {
+    type Result = Result<(), ()>;
+
+    fn handle(&mut self, msg: CommandRunner, ctx: &mut Self::Context) -> Self::Result {
+        if self.queue.pop(&self.id) {
+            match msg.0 {
+                Payload::Text(text) => self.send_text(text, ctx),
+                Payload::Binary(bin) => self.send_binary(bin, ctx),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MyWs {
+    fn send_text(&self, text: impl Into<ByteString>, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.close_sent {
+            return;
+        }
+        let text = text.into();
+        self.metrics.record_sent(text.len());
+        GLOBAL_METRICS.record_sent(text.len());
+        // the compressed text isn't UTF-8, it leaves as a binary frame until `OutboundFrames`
+        match self.compress(text.as_bytes(), true) {
+            Some(compressed) => ctx.binary(compressed),
+            None => ctx.text(text),
+        }
+    }
+
+    fn send_binary(&self, bin: Bytes, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.close_sent {
+            return;
+        }
+        self.metrics.record_sent(bin.len());
+        GLOBAL_METRICS.record_sent(bin.len());
+        ctx.binary(self.compress(&bin, false).unwrap_or(bin));
+    }
+
+    fn compress(&self, payload: &[u8], text: bool) -> Option<Bytes> {
+        let compressed = self.deflate.as_ref()?.compress(payload, text);
+        let bytes = compressed.as_ref().map(Bytes::len);
+        self.metrics.record_compression(bytes);
+        GLOBAL_METRICS.record_compression(bytes);
+        compressed
+    }
+
+    /// Called once an async handler was cancelled for running longer than the `handler_timeout`
+    fn handler_timed_out(&mut self, error: &PyErr, ctx: &mut ws::WebsocketContext<Self>) {
+        let Some(timeout) = self.config.handler_timeout else {
+            return;
+        };
+        error!(
+            "An async handler of web socket {} ran for more than {}s and was cancelled",
+            self.log_context(),
+            timeout.seconds
+        );
+        self.send_handler_error(error, ctx);
+        if timeout.close {
+            let close_reason = CloseReason {
+                code: CloseCode::Error,
+                description: Some("Handler timed out".to_string()),
+            };
+            self.close_reason = Some(close_reason.clone());
+            self.close(close_reason, ctx);
+        }
+    }
+
+    /// Tells the client that a handler raised, on the routes created with a `handler_error_frame`
+    fn send_handler_error(&self, error: &PyErr, ctx: &mut ws::WebsocketContext<Self>) {
+        lifecycle::emit(
+            EventKind::Error,
+            websocket_id(&self.id),
+            &self.route,
+            || Some(Python::with_gil(|py| error.value(py).to_string())),
+        );
+        let Some(template) = &self.config.handler_error_frame else {
+            return;
+        };
+        // the traceback is only logged, it stays on the server
+        let traceback = get_traceback(error);
+        let frame = fill_error_frame(template.clone(), &traceback.error_type, &traceback.message);
+        self.send_text(frame.to_string(), ctx);
+    }
+
+    /// Keeps the rooms, the tags and the state of the connection under its reconnection token
+    fn save_for_reconnect(&self) {
+        let Some(ttl) = self.config.reconnect_ttl else {
+            return;
+        };
+        let Some((_, token)) = reconnect_tokens().remove(&self.id) else {
+            return;
+        };
+        let rooms = rooms()
+            .iter()
+            .filter(|room| room.value().contains(&self.id))
+            .map(|room| room.key().clone())
+            .collect();
+        let tags = connection_tags()
+            .get(&self.id)
+            .map(|tags| tags.clone())
+            .unwrap_or_default();
+        let saved = Python::with_gil(|py| {
+            reconnect::save(
+                token,
+                rooms,
+                tags,
+                self.state.as_ref(py),
+                Duration::from_secs(ttl),
+            )
+        });
+        if let Err(e) = saved {
+            error!(
+                "Couldn't save the state of web socket {} for its reconnection: {}",
+                self.log_context(),
+                get_traceback(&e)
+            );
+        }
+    }
+
+    /// Closes the connection with 1009 (message too big) without running the handler
+    fn close_message_too_big(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        debug!(
+            "Web socket {} received a message bigger than {} bytes",
+            self.id, self.config.max_payload_size
+        );
+        let close_reason: CloseReason = CloseCode::Size.into();
+        self.close_reason = Some(close_reason.clone());
+        self.close(close_reason, ctx);
+    }
+
+    /// Stops the connection, the close frame is sent from `stopping`
+    /// so that the "before_close" handler can still send frames before it.
+    /// The frames already queued with `broadcast`, `send_to`, ... are sent first,
+    /// for up to the drain timeout of the route, while the new ones are dropped
+    fn close(&self, close_reason: CloseReason, ctx: &mut ws::WebsocketContext<Self>) {
+        self.close_draining(close_reason, self.config.drain_timeout, ctx);
+    }
+
+    fn close_draining(
+        &self,
+        mut close_reason: CloseReason,
+        drain_timeout: Option<Duration>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        // the connection is already closing, e.g. while an async "before_close" handler runs
+        if self.closing.load(Ordering::Relaxed) {
+            return;
+        }
+        // some clients fail on the codes that aren't allowed in a close frame
+        let code: u16 = close_reason.code.into();
+        if !is_valid_close_code(code) {
+            warn!(
+                "Web socket {} can't be closed with the code {}, closing it with 1000 instead",
+                self.id, code
+            );
+            close_reason.code = CloseCode::Normal;
+        }
+        // nor would they read a close frame longer than a control frame can be
+        if let Some(description) = close_reason.description.as_mut() {
+            if description.len() > MAX_CLOSE_REASON_LEN {
+                let mut len = MAX_CLOSE_REASON_LEN;
+                while !description.is_char_boundary(len) {
+                    len -= 1;
+                }
+                warn!(
+                    "The close reason of web socket {} is longer than {} bytes, truncating it",
+                    self.id, MAX_CLOSE_REASON_LEN
+                );
+                description.truncate(len);
+            }
+        }
+        *self.pending_close.borrow_mut() = Some(close_reason);
+        let Some(drain_timeout) = drain_timeout.filter(|_| self.queue.len() > 0) else {
+            ctx.stop();
+            return;
+        };
+        self.closing.store(true, Ordering::Relaxed);
+        let deadline = Instant::now() + drain_timeout;
+        ctx.run_interval(DRAIN_CHECK_INTERVAL, move |act, ctx| {
+            let pending = act.queue.len();
+            if pending == 0 {
+                ctx.stop();
+            } else if Instant::now() >= deadline {
+                debug!(
+                    "Web socket {} closed with {} frames that were not sent",
+                    act.id, pending
+                );
+                ctx.stop();
+            }
+        });
+    }
+
+    /// Runs the future of an async handler. On the routes that keep the order of the messages
+    /// it starts once the handlers of the previous messages are done
+    fn spawn_handler<F>(&self, f: F, ctx: &mut ws::WebsocketContext<Self>)
+    where
+        F: ActorFuture<Self, Output = ()> + 'static,
+    {
+        let Some(ordered) = self.ordered.as_ref().filter(|_| self.keeps_order(ctx)) else {
+            ctx.spawn(f);
+            return;
+        };
+        let f = Box::pin(f.map(|_, act: &mut Self, ctx| act.spawn_next_handler(ctx)));
+        let mut ordered = ordered.borrow_mut();
+        if ordered.running {
+            ordered.waiting.push_back(f);
+        } else {
+            ordered.running = true;
+            ctx.spawn(f);
+        }
+    }
+
+    /// Whether the handlers wait for the previous ones. Once the connection is stopping,
+    /// the futures that wait would never run, so the last handlers run right away
+    fn keeps_order(&self, ctx: &ws::WebsocketContext<Self>) -> bool {
+        self.ordered.is_some() && ctx.state() == ActorState::Running
+    }
+
+    fn spawn_next_handler(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let Some(ordered) = &self.ordered else {
+            return;
+        };
+        let mut ordered = ordered.borrow_mut();
+        match ordered.waiting.pop_front() {
+            Some(f) => {
+                ctx.spawn(f);
+            }
+            None => ordered.running = false,
+        }
+    }
+
+    /// Sends the close frame of the server, or the answer to the one of the client
+    fn send_close_frame(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        if let Some(close_reason) = self.pending_close.borrow_mut().take() {
+            // a client closed by the server can't come back with its state
+            reconnect_tokens().remove(&self.id);
+            // the echo of the client isn't read once the actor stops, so the "close" handler
+            // receives the code that the server closed with, e.g. the one of a `CloseConnection`
+            self.close_reason
+                .get_or_insert_with(|| close_reason.clone());
+            ctx.close(Some(close_reason));
+            self.close_sent = true;
+        } else if let Some(close_echo) = self.close_echo.take() {
+            // a close frame without a status code is answered with one without a status code
+            let code: u16 = close_echo.code.into();
+            ctx.close(Some(close_echo).filter(|_| code != 1005));
+            self.close_sent = true;
+        }
+    }
+
+    /// Runs an async "close" handler while the actor can still run its future, it stops once
+    /// the handler is done. Returns false when there is nothing to wait for, a sync handler
+    /// runs once the actor stopped
+    fn execute_async_close(&mut self, ctx: &mut ws::WebsocketContext<Self>) -> bool {
+        if self.close_executed {
+            return false;
+        }
+        let Some(function) = self
+            .router
+            .get("close")
+            .filter(|function| function.is_async)
+        else {
+            return false;
+        };
+        self.close_executed = true;
+        self.close_running = true;
+        let close_reason = self
+            .close_reason
+            .clone()
+            .unwrap_or_else(|| CloseCode::Abnormal.into());
+        let f = call_async_function(
+            function,
+            Some(WsMessage::Close(close_reason)),
+            &self.task_locals,
+            self,
+        )
+        .map(|_, act: &mut Self, ctx: &mut ws::WebsocketContext<Self>| {
+            act.close_running = false;
+            ctx.stop();
+        });
+        ctx.wait(f);
+        true
+    }
+
+    /// Runs the "before_close" handler once, while the connection can still be written to.
+    /// Returns true when the handler is async and the actor has to wait for it
+    fn execute_before_close(&mut self, ctx: &mut ws::WebsocketContext<Self>) -> bool {
+        if self.before_close_executed {
+            return false;
+        }
+        self.before_close_executed = true;
+        let Some(function) = self.router.get("before_close") else {
+            return false;
+        };
+        let close_reason = self
+            .pending_close
+            .borrow()
+            .clone()
+            .or_else(|| self.close_reason.clone())
+            .unwrap_or_else(|| CloseCode::Abnormal.into());
+        let message = Some(WsMessage::Close(close_reason));
+        if !function.is_async {
+            execute_ws_function(function, message, &self.task_locals, ctx, self);
+            return false;
+        }
+
+        let fut = Python::with_gil(|py| {
+            pyo3_asyncio::into_future_with_locals(
+                &self.task_locals,
+                get_function_output(function, message, py, self)?,
+            )
+        });
+        let fut = match fut {
+            Ok(fut) => fut,
+            Err(e) => {
+                error!(
+                    "Error while executing web socket before_close function for {}: {}",
+                    self.log_context(),
+                    get_traceback(&e)
+                );
+                return false;
+            }
+        };
+        let binary_codec = self.config.binary_codec;
+        let f = async move {
+            let output = fut.await?;
+            Python::with_gil(|py| extract_output(output.as_ref(py), binary_codec))
+        }
+        .into_actor(self)
+        .map(|res, act, ctx| {
+            match res {
+                Ok(Some(output)) => output.send(act, ctx),
+                Ok(None) => (),
+                Err(e) => error!(
+                    "Error while executing web socket before_close function for {}: {}",
+                    act.log_context(),
+                    get_traceback(&e)
+                ),
+            }
+            ctx.stop();
+        });
+        ctx.wait(f);
+        true
+    }
+
+    /// Whether `set_verbose` was called for the connection
+    fn is_verbose(&self) -> bool {
+        verbose_connections().contains(&self.id)
+    }
+
+    /// The handler of an event that every route is expected to have,
+    /// the events of a route that doesn't register one are logged and ignored
+    fn handler(&self, event: &str) -> Option<&FunctionInfo> {
+        let function = self.router.get(event);
+        if function.is_none() {
+            warn!(
+                "No handler registered for event '{}' of web socket {}, ignoring",
+                event,
+                self.log_context()
+            );
+        }
+        function
+    }
+
+    /// Identifies the connection in the logs, e.g. `<id> on /ws/:room from 127.0.0.1`
+    fn log_context(&self) -> String {
+        format!(
+            "{} on {} from {}",
+            self.id,
+            self.route,
+            self.remote_ip.as_deref().unwrap_or("an unknown address")
+        )
+    }
+
+    /// Accounts for a text or binary message, returns false when the message must be dropped
+    fn receive(&mut self, bytes: usize, ctx: &mut ws::WebsocketContext<Self>) -> bool {
+        self.last_activity = Instant::now();
+        self.metrics.record_received(bytes);
+        GLOBAL_METRICS.record_received(bytes);
+        self.allow_message(ctx)
+    }
+
+    /// Consumes the answers to the app heartbeats, they are not passed to the handlers
+    fn is_app_pong(&mut self, text: &str) -> bool {
+        if !self
+            .config
+            .app_heartbeat
+            .as_ref()
+            .is_some_and(|app_heartbeat| app_heartbeat.is_pong(text))
+        {
+            return false;
+        }
+        self.unanswered_app_ping = None;
+        true
+    }
+
+    /// Whether the message acks a frame of `broadcast_with_ack`, the acks don't reach the handlers.
+    /// The messages with an unknown or expired id in the `ack_field` are passed on
+    fn is_ack(&self, text: &str) -> bool {
+        let Some(ack_field) = &self.config.ack_field else {
+            return false;
+        };
+        if !text.trim_start().starts_with('{') {
+            return false;
+        }
+        match serde_json::from_str::<Value>(text) {
+            Ok(value) => value
+                .get(ack_field)
+                .and_then(Value::as_str)
+                .is_some_and(|ack_id| acks::acknowledge(ack_id, &self.id)),
+            Err(_) => false,
+        }
+    }
+
+    /// Runs the background coroutine of the "connect" handler on the event loop of the route.
+    /// Returns the future of its task, that cancels the task when it is cancelled
+    fn start_background(&self, coroutine: PyObject) -> Option<PyObject> {
+        let task = Python::with_gil(|py| {
+            let task = py.import("asyncio")?.call_method1(
+                "run_coroutine_threadsafe",
+                (coroutine, self.task_locals.event_loop(py)),
+            )?;
+            task.call_method1(
+                "add_done_callback",
+                (BackgroundDone {
+                    log_context: self.log_context(),
+                },),
+            )?;
+            Ok::<PyObject, PyErr>(task.into())
+        });
+        match task {
+            Ok(task) => Some(task),
+            Err(e) => {
+                error!(
+                    "Couldn't start the background task of web socket {}: {}",
+                    self.log_context(),
+                    get_traceback(&e)
+                );
+                None
+            }
+        }
+    }
+
+    /// Passes a message of the client to its handler, or holds it while the connection is paused.
+    /// The connection is closed with 1013 (try again later) once more than `mailbox_capacity`
+    /// messages are held, the client has to send them again later
+    fn dispatch(&mut self, message: Inbound, ctx: &mut ws::WebsocketContext<Self>) {
+        if let Some(held) = self.paused.as_mut() {
+            if held.len() < self.config.mailbox_capacity {
+                held.push_back(message);
+                return;
+            }
+            warn!(
+                "Web socket {} sent more than {} messages while it was paused",
+                self.log_context(),
+                self.config.mailbox_capacity
+            );
+            self.paused = None;
+            let close_reason = CloseReason {
+                code: CloseCode::Again,
+                description: Some("Too many messages while paused".to_string()),
+            };
+            self.close_reason = Some(close_reason.clone());
+            return self.close(close_reason, ctx);
+        }
+        match message {
+            Inbound::Text(text) => self.handle_text(&text, ctx),
+            Inbound::RawText(text) => self.handle_raw_text(text, ctx),
+            Inbound::Binary(bin) => self.handle_binary(bin, ctx),
+        }
+    }
+
+    /// Passes the messages that the client sent again within the de-duplication window to the
+    /// "duplicate" handler instead of the usual one, they are dropped when it isn't registered
+    fn is_duplicate(
+        &mut self,
+        message: &[u8],
+        duplicate: impl FnOnce() -> WsMessage,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) -> bool {
+        let Some(seen_ids) = self.seen_ids.as_mut() else {
+            return false;
+        };
+        let Some(id) = message_id(self.config.dedup_key.as_deref(), message) else {
+            return false;
+        };
+        if seen_ids.insert(id) {
+            return false;
+        }
+        debug!("Web socket {} received a duplicate message", self.id);
+        if let Some(function) = self.router.get("duplicate") {
+            execute_ws_function(function, Some(duplicate()), &self.task_locals, ctx, self);
+        }
+        true
+    }
+
+    fn handle_text(&mut self, text: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.is_app_pong(text) || self.is_ack(text) {
+            return;
+        }
+        if self.is_duplicate(text.as_bytes(), || WsMessage::Text(text.to_string()), ctx) {
+            return;
+        }
+        let message = if self.config.json_mode {
+            match serde_json::from_str(text) {
+                Ok(value) => WsMessage::Json(value),
+                // the message isn't passed to the handler
+                Err(e) => {
+                    debug!("Web socket {} received invalid JSON - {}", self.id, e);
+                    if let Some(template) = &self.config.invalid_json_frame {
+                        let frame =
+                            fill_error_frame(template.clone(), "invalid_json", &e.to_string());
+                        self.send_text(frame.to_string(), ctx);
+                    }
+                    return;
+                }
+            }
+        } else {
+            WsMessage::Text(text.to_string())
+        };
+        if let Some(function) = self.handler("message") {
+            execute_ws_function(function, Some(message), &self.task_locals, ctx, self);
+        }
+    }
+
+    /// Whether the next binary message is a text message of a route that receives them as bytes
+    fn next_is_raw_text(&self) -> bool {
+        self.raw_text_frames
+            .as_ref()
+            .is_some_and(|kinds| kinds.borrow_mut().pop_front().unwrap_or(false))
+    }
+
+    fn handle_raw_text(&mut self, text: Bytes, ctx: &mut ws::WebsocketContext<Self>) {
+        if std::str::from_utf8(&text).is_ok_and(|text| self.is_app_pong(text)) {
+            return;
+        }
+        if self.is_duplicate(&text, || WsMessage::Binary(text.clone()), ctx) {
+            return;
+        }
+        if let Some(function) = self.handler("message") {
+            execute_ws_function(
+                function,
+                Some(WsMessage::Binary(text)),
+                &self.task_locals,
+                ctx,
+                self,
+            );
+        }
+    }
+
+    fn handle_binary(&mut self, bin: Bytes, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.is_duplicate(&bin, || WsMessage::Binary(bin.clone()), ctx) {
+            return;
+        }
+        match (self.router.get("binary"), self.config.binary_codec) {
+            (Some(function), Some(codec)) => match Python::with_gil(|py| codec.decode(py, &bin)) {
+                Ok(value) => execute_ws_function(
+                    function,
+                    Some(WsMessage::Decoded(value, codec)),
+                    &self.task_locals,
+                    ctx,
+                    self,
+                ),
+                Err(e) => {
+                    debug!(
+                        "Web socket {} received invalid {} - {}",
+                        self.id,
+                        codec.name(),
+                        e
+                    );
+                    if let Some(function) = self.router.get("error") {
+                        execute_ws_function(
+                            function,
+                            Some(WsMessage::Text(format!("Invalid {} - {e}", codec.name()))),
+                            &self.task_locals,
+                            ctx,
+                            self,
+                        );
+                    }
+                }
+            },
+            (Some(function), None) => execute_ws_function(
+                function,
+                Some(WsMessage::Binary(bin)),
+                &self.task_locals,
+                ctx,
+                self,
+            ),
+            // echo the frame back when no binary handler is registered
+            (None, _) => self.send_binary(bin, ctx),
+        }
+    }
+
+    /// Buffers the frames of a fragmented message until the last one is received.
+    /// The whole message counts against `max_payload_size`, so a client can't make it grow unbounded
+    fn handle_continuation(
+        &mut self,
+        item: Item,
+        raw_text: bool,
+        compressed: Option<bool>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        self.last_activity = Instant::now();
+        let (chunk, last) = match item {
+            Item::FirstText(chunk) => {
+                self.fragments = Some(Fragments::new(true, false));
+                (chunk, false)
+            }
+            Item::FirstBinary(chunk) => {
+                self.fragments = Some(match compressed {
+                    Some(text) => Fragments::new(text, true),
+                    None => Fragments::new(raw_text, false),
+                });
+                (chunk, false)
+            }
+            Item::Continue(chunk) => (chunk, false),
+            Item::Last(chunk) => (chunk, true),
+        };
+        // the codec already refuses the frames that come out of order
+        let Some(fragments) = self.fragments.as_mut() else {
+            return self.fail_connection(ws::ProtocolError::ContinuationNotStarted, ctx);
+        };
+        if fragments.data.len() + chunk.len() > self.config.max_payload_size {
+            self.fragments = None;
+            return self.close_message_too_big(ctx);
+        }
+        fragments.data.extend_from_slice(&chunk);
+        if !last {
+            return;
+        }
+
+        let Fragments {
+            text,
+            compressed,
+            data,
+        } = self.fragments.take().unwrap();
+        if !self.receive(data.len(), ctx) {
+            return;
+        }
+        if compressed {
+            self.handle_compressed(data.freeze(), text, ctx);
+        } else {
+            self.dispatch_data(data.freeze(), text, ctx);
+        }
+    }
+
+    /// Passes a whole message of the client on, once it was accounted for
+    fn dispatch_data(&mut self, data: Bytes, text: bool, ctx: &mut ws::WebsocketContext<Self>) {
+        if text && self.raw_text_frames.is_some() {
+            self.dispatch(Inbound::RawText(data), ctx);
+        } else if text {
+            match String::from_utf8(data.to_vec()) {
+                Ok(text) => self.dispatch(Inbound::Text(text), ctx),
+                // reported like the invalid text frames that actix decodes
+                Err(e) => self
+                    .fail_connection(ws::ProtocolError::Io(io::Error::other(e.to_string())), ctx),
+            }
+        } else {
+            self.dispatch(Inbound::Binary(data), ctx);
+        }
+    }
+
+    /// Decompresses a message of the client, it can't grow past the `max_payload_size`
+    fn handle_compressed(&mut self, data: Bytes, text: bool, ctx: &mut ws::WebsocketContext<Self>) {
+        let Some(deflate) = &self.deflate else {
+            return;
+        };
+        match deflate.decompress(&data, self.config.max_payload_size) {
+            Ok(data) => self.dispatch_data(data, text, ctx),
+            Err(InflateError::TooBig) => self.close_message_too_big(ctx),
+            Err(InflateError::Corrupt(e)) => {
+                self.fail_connection(ws::ProtocolError::Io(io::Error::other(e)), ctx)
+            }
+        }
+    }
+
+    /// Checks the message against the rate limit of the route, the messages above the limit are dropped.
+    /// Closes the connection with 1008 (policy violation) once too many messages were dropped
+    fn allow_message(&mut self, ctx: &mut ws::WebsocketContext<Self>) -> bool {
+        let Some(bucket) = self.rate_limit.as_mut() else {
+            return true;
+        };
+        if bucket.try_take() {
+            return true;
+        }
+        let (violations, limit) = (bucket.violations, bucket.limit);
+        if violations == 1 {
+            warn!(
+                "Web socket {} is throttled, sending more than {} messages per second",
+                self.log_context(),
+                limit.rate
+            );
+        } else {
+            debug!("Web socket {} dropped a throttled message", self.id);
+        }
+        if limit.max_violations.is_some_and(|max| violations >= max) {
+            let close_reason = CloseReason {
+                code: CloseCode::Policy,
+                description: Some("Rate limit exceeded".to_string()),
+            };
+            self.close_reason = Some(close_reason.clone());
+            self.close(close_reason, ctx);
+        }
+        false
+    }
+
+    /// Passes the description of the error to the "error" handler.
+    /// The client sent a frame that can't be decoded, so the connection is closed
+    fn fail_connection(&mut self, e: ws::ProtocolError, ctx: &mut ws::WebsocketContext<Self>) {
+        debug!("Web socket {} protocol error - {}", self.id, e);
+        lifecycle::emit(
+            EventKind::Error,
+            websocket_id(&self.id),
+            &self.route,
+            || Some(e.to_string()),
+        );
+        if let Some(function) = self.router.get("error") {
+            execute_ws_function(
+                function,
+                Some(WsMessage::Text(e.to_string())),
+                &self.task_locals,
+                ctx,
+                self,
+            );
+        }
+
+        let code = match &e {
+            ws::ProtocolError::Overflow => CloseCode::Size,
+            // invalid UTF-8 in a text frame is reported as an I/O error by actix
+            ws::ProtocolError::Io(io_error) if io_error.to_string().contains("utf-8") => {
+                CloseCode::Invalid
+            }
+            _ => CloseCode::Protocol,
+        };
+        let close_reason: CloseReason = code.into();
+        self.close_reason = Some(close_reason.clone());
+        self.close(close_reason, ctx);
+    }
+}
+
+/// Handler for ws::Message message
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for MyWs {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        // once the close frame was sent, the messages that were already on their way are dropped
+        if self.close_sent && !matches!(msg, Ok(ws::Message::Close(_))) {
+            return;
+        }
+        // the text frames of a raw text route reach the actor as binary ones
+        let raw_text = matches!(
+            msg,
+            Ok(ws::Message::Binary(_)) | Ok(ws::Message::Continuation(Item::FirstBinary(_)))
+        ) && self.next_is_raw_text();
+        // whether the message of the client was compressed, and if so whether it is text
+        let compressed = match &msg {
+            Ok(ws::Message::Text(_))
+            | Ok(ws::Message::Binary(_))
+            | Ok(ws::Message::Continuation(Item::FirstText(_)))
+            | Ok(ws::Message::Continuation(Item::FirstBinary(_))) => {
+                self.deflate.as_ref().and_then(Deflate::next_received)
+            }
+            _ => None,
+        };
+
+        let received = match &msg {
+            Ok(ws::Message::Text(text)) => Some(text.len()),
+            Ok(ws::Message::Binary(bin)) => Some(bin.len()),
+            _ => None,
+        };
+        if let Some(bytes) = received {
+            if !self.receive(bytes, ctx) {
+                return;
+            }
+        }
+
+        match msg {
+            Ok(ws::Message::Ping(msg)) => {
+                debug!("Web socket {} ping message {:?}", self.id, msg);
+                if let Some(function) = self.router.get("ping") {
+                    execute_ws_function(
+                        function,
+                        Some(WsMessage::Binary(msg.clone())),
+                        &self.task_locals,
+                        ctx,
+                        self,
+                    );
+                }
+                if self.config.auto_pong {
+                    ctx.pong(&msg)
+                }
+            }
+            // pongs are never answered
+            Ok(ws::Message::Pong(msg)) => {
+                debug!("Web socket {} pong message {:?}", self.id, msg);
+                self.unanswered_ping = None;
+                if let Some(function) = self.router.get("pong") {
+                    execute_ws_function(
+                        function,
+                        Some(WsMessage::Binary(msg)),
+                        &self.task_locals,
+                        ctx,
+                        self,
+                    );
+                }
+            }
+            Ok(ws::Message::Text(text)) if text.len() > self.config.max_payload_size => {
+                self.close_message_too_big(ctx)
+            }
+            Ok(ws::Message::Binary(bin)) if bin.len() > self.config.max_payload_size => {
+                self.close_message_too_big(ctx)
+            }
+            Ok(ws::Message::Binary(bin)) if compressed.is_some() => {
+                self.handle_compressed(bin, compressed == Some(true), ctx)
+            }
+            Ok(ws::Message::Text(text)) => self.dispatch(Inbound::Text(text.to_string()), ctx),
+            Ok(ws::Message::Binary(text)) if raw_text => self.dispatch(Inbound::RawText(text), ctx),
+            Ok(ws::Message::Binary(bin)) => self.dispatch(Inbound::Binary(bin), ctx),
+            Ok(ws::Message::Close(close_reason)) => {
+                debug!("Web socket {} was closed {:?}", self.id, close_reason);
+                // a close frame without a status code is reported as 1005 (no status received)
+                let close_reason = close_reason.unwrap_or_else(|| CloseCode::Other(1005).into());
+                self.close_reason = Some(close_reason.clone());
+                // RFC 6455 5.5.1, the close frame is answered with one carrying the same code,
+                // unless the server already sent its own, and the connection ends
+                if !self.closing.swap(true, Ordering::Relaxed) {
+                    self.close_echo = Some(CloseReason {
+                        code: close_reason.code,
+                        description: None,
+                    });
+                }
+                // the "close" handler receives the code once the actor is stopping
+                ctx.stop();
+            }
+            Ok(ws::Message::Continuation(item)) => {
+                self.handle_continuation(item, raw_text, compressed, ctx)
+            }
+            Ok(ws::Message::Nop) => (),
+            Err(e) => self.fail_connection(e, ctx),
+        }
+    }
+}
+
+/// Collects the handshake headers, header names are lowercase.
+/// Repeated headers are joined into a single value as per RFC 7230
+/// and values that are not valid UTF-8 are converted lossily
+fn get_headers(req: &HttpRequest) -> HashMap<String, String> {
+    let mut headers: HashMap<String, String> = HashMap::new();
+    for (name, value) in req.headers().iter() {
+        let value = String::from_utf8_lossy(value.as_bytes());
+        headers
+            .entry(name.to_string())
+            .and_modify(|existing| {
+                // cookies are the exception as they are separated by a semicolon
+                let separator = if name == header::COOKIE { "; " } else { ", " };
+                existing.push_str(separator);
+                existing.push_str(&value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+    headers
+}
+
+/// The subprotocols requested by the client, in its order of preference
+fn requested_subprotocols(req: &HttpRequest) -> Vec<&str> {
+    // actix only reads the first `Sec-WebSocket-Protocol` header when it builds the response
+    req.headers()
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|value| value.to_str().ok())
+        .map_or_else(Vec::new, |value| value.split(',').map(str::trim).collect())
+}
+
+/// The params of the first permessage-deflate offer of the client that can be honoured
+fn negotiate_deflate(extensions: &[Extension]) -> Option<deflate::Params> {
+    extensions
+        .iter()
+        .filter(|extension| extension.name.eq_ignore_ascii_case(deflate::EXTENSION))
+        .find_map(|extension| deflate::Params::accept(&extension.params))
+}
+
+/// An extension requested in the `Sec-WebSocket-Extensions` headers, e.g.
+/// `permessage-deflate; client_max_window_bits`, the params without a value have None
+#[derive(Debug, Clone)]
+struct Extension {
+    name: String,
+    params: Vec<(String, Option<String>)>,
+}
+
+impl ToPyObject for Extension {
+    fn to_object(&self, py: Python) -> PyObject {
+        let params = PyDict::new(py);
+        for (name, value) in &self.params {
+            params.set_item(name, value).ok();
+        }
+        [("name", self.name.to_object(py)), ("params", params.into())]
+            .into_py_dict(py)
+            .into()
+    }
+}
+
+/// The extensions requested by the client, in its order of preference, from every
+/// `Sec-WebSocket-Extensions` header. The malformed ones are left out
+fn requested_extensions(req: &HttpRequest) -> Vec<Extension> {
+    req.headers()
+        .get_all(header::SEC_WEBSOCKET_EXTENSIONS)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| split_unquoted(value, ','))
+        .filter(|extension| !extension.trim().is_empty())
+        .filter_map(|extension| {
+            let parsed = parse_extension(extension);
+            if parsed.is_none() {
+                debug!("Ignoring the malformed web socket extension {extension:?}");
+            }
+            parsed
+        })
+        .collect()
+}
+
+/// `name; param; param=value; param="quoted value"`
+fn parse_extension(extension: &str) -> Option<Extension> {
+    let mut parts = split_unquoted(extension, ';').into_iter().map(str::trim);
+    let name = parts.next().filter(|name| is_token(name))?;
+    let params = parts
+        .map(|param| match param.split_once('=') {
+            Some((param, value)) => {
+                let (param, value) = (param.trim(), value.trim());
+                let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+                    Some(quoted) => {
+                        // a backslash escapes the next character of a quoted string
+                        let mut chars = quoted.chars();
+                        let mut value = String::new();
+                        while let Some(c) = chars.next() {
+                            value.push(if c == '\\' { chars.next()? } else { c });
+                        }
+                        value
+                    }
+                    None if is_token(value) => value.to_string(),
+                    None => return None,
+                };
+                is_token(param).then(|| (param.to_string(), Some(value)))
+            }
+            None => is_token(param).then(|| (param.to_string(), None)),
+        })
+        .collect::<Option<_>>()?;
+    Some(Extension {
+        name: name.to_string(),
+        params,
+    })
+}
+
+/// Splits the header value on the separators that are outside of the quoted strings
+fn split_unquoted(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                parts.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&value[start..]);
+    parts
+}
+
+/// Whether the text is an HTTP token, e.g. the name of an extension or of one of its params
+fn is_token(text: &str) -> bool {
+    !text.is_empty()
+        && text
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+}
+
+/// Picks the first subprotocol requested by the client that the route supports
+fn negotiate_subprotocol(req: &HttpRequest, subprotocols: &[String]) -> Option<String> {
+    requested_subprotocols(req)
+        .into_iter()
+        .find(|requested| subprotocols.iter().any(|supported| supported == requested))
+        .map(str::to_string)
+}
+
+/// Whether the `Origin` header of the handshake matches one of the allowed origins.
+/// A `*` in an allowed origin matches any part of the origin, e.g. `https://*.example.com`
+fn origin_allowed(origin: &str, allowed_origins: &[String]) -> bool {
+    let origin = origin.to_ascii_lowercase();
+    allowed_origins
+        .iter()
+        .any(|allowed| wildcard_match(allowed, &origin))
+}
+
+fn wildcard_match(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let Some(mut rest) = value.strip_prefix(parts.next().unwrap_or_default()) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    // without a `*`, the whole value has to match
+    let Some(last) = parts.pop() else {
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Parses the `Cookie` headers of the handshake into a name -> value map.
+/// The pairs that are not `name=value` are skipped, so a malformed header can't fail the upgrade
+fn get_cookies(req: &HttpRequest) -> HashMap<String, String> {
+    req.headers()
+        .get_all(header::COOKIE)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(name, value)| (name.trim(), value.trim().trim_matches('"')))
+        .filter(|(name, _)| !name.is_empty())
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+/// The ip of the client, the first address of `X-Forwarded-For` is the client
+/// when the route trusts the proxy in front of it
+fn get_remote_ip(req: &HttpRequest, trust_forwarded_for: bool) -> Option<String> {
+    if trust_forwarded_for {
+        let forwarded_for = req
+            .headers()
+            .get(header::X_FORWARDED_FOR)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .map(str::trim)
+            .filter(|ip| !ip.is_empty());
+        if let Some(ip) = forwarded_for {
+            return Some(ip.to_string());
+        }
+    }
+    req.peer_addr().map(|addr| addr.ip().to_string())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn start_web_socket(
+    req: HttpRequest,
+    stream: web::Payload,
+    route: String,
+    router: HashMap<String, FunctionInfo>,
+    task_locals: TaskLocals,
+    config: Arc<WebSocketConfig>,
+) -> Result<HttpResponse, Error> {
+    // the time it took to receive the request is bounded by the HTTP server, see `Server::start`
+    let handshake_deadline = config
+        .handshake_timeout
+        .map(|seconds| tokio::time::Instant::now() + Duration::from_secs(seconds));
+    let query_params = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .map(|query| query.into_inner())
+        .unwrap_or_default();
+
+    let headers = get_headers(&req);
+    let remote_ip = get_remote_ip(&req, config.trust_forwarded_for);
+    let failures = UpgradeFailures::new(
+        &router,
+        &task_locals,
+        &req,
+        &route,
+        remote_ip.as_deref(),
+        &headers,
+        &query_params,
+    );
+
+    // make sure that this is a valid upgrade request before running the "connect" handler
+    if let Err(e) = ws::handshake(&req) {
+        return Ok(failures.report("invalid_handshake", e.error_response()));
+    }
+
+    if let Some(allowed_origins) = &config.allowed_origins {
+        let origin = req
+            .headers()
+            .get(header::ORIGIN)
+            .and_then(|value| value.to_str().ok());
+        if !origin.is_some_and(|origin| origin_allowed(origin, allowed_origins)) {
+            warn!(
+                "Refusing web socket connection to {} from the origin {:?}",
+                route, origin
+            );
+            return Ok(failures.report("origin_not_allowed", HttpResponse::Forbidden().finish()));
+        }
+    }
+
+    // the slot is taken before anything awaits, and the failures below give it back
+    let Some(slot) = ConnectionSlot::reserve() else {
+        debug!(
+            "Refusing web socket connection, {} connections are alive",
+            ACTIVE_CONNECTIONS.load(Ordering::Relaxed)
+        );
+        return Ok(failures.report(
+            "too_many_connections",
+            HttpResponse::ServiceUnavailable().finish(),
+        ));
+    };
+
+    let subprotocol = negotiate_subprotocol(&req, &config.subprotocols);
+    if subprotocol.is_none() && config.subprotocol_required {
+        debug!(
+            "Web socket client requested none of the subprotocols {:?}",
+            config.subprotocols
+        );
+        return Ok(failures.report("subprotocol_required", HttpResponse::BadRequest().finish()));
+    }
+
+    let queue = Arc::new(OutboundQueue::new(
+        config.mailbox_capacity,
+        config.backpressure,
+        config.high_water_mark,
+        router
+            .get("backpressure")
+            .map(|function| (function.clone(), task_locals.clone())),
+        router
+            .get("drained")
+            .map(|function| (function.clone(), task_locals.clone())),
+    ));
+    let mut my_ws = MyWs {
+        route,
+        router,
+        task_locals,
+        config: config.clone(),
+        id: Uuid::new_v4(),
+        close_reason: None,
+        unanswered_ping: None,
+        unanswered_app_ping: None,
+        last_activity: Instant::now(),
+        slow_since: None,
+        raw_text_frames: config.raw_text.then(FrameKinds::default),
+        deflate: None,
+        ordered: config.ordered.then(Default::default),
+        rate_limit: config.rate_limit.map(TokenBucket::new),
+        seen_ids: config
+            .dedup_window
+            .map(|window| SeenIds::new(Duration::from_secs(window))),
+        paused: None,
+        fragments: None,
+        pending_close: RefCell::new(None),
+        close_echo: None,
+        close_sent: false,
+        close_running: false,
+        before_close_executed: false,
+        close_executed: false,
+        query_params,
+        headers,
+        remote_ip,
+        cookies: get_cookies(&req),
+        path_params: req
+            .match_info()
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+        state: Python::with_gil(|py| PyDict::new(py).into()),
+        connect_output: None,
+        background: None,
+        background_task: None,
+        metrics: Arc::new(Metrics::new()),
+        connected_at: Instant::now(),
+        connected_at_epoch: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |elapsed| elapsed.as_secs_f64()),
+        subprotocol,
+        extensions: requested_extensions(&req),
+        queue,
+        closing: Arc::new(AtomicBool::new(false)),
+        slot: Some(slot),
+        upgrade: None,
+    };
+    // the handle of the connection is available to the "connect" handler
+    my_ws.upgrade = Some(Upgrade::start(my_ws.id));
+
+    // the id from the header is already used by the "connect" handler
+    let client_id = config
+        .id_header
+        .as_ref()
+        .and_then(|name| req.headers().get(name))
+        .and_then(|value| value.to_str().ok())
+        .filter(|client_id| !client_id.is_empty());
+    if let Some(client_id) = client_id {
+        if !register_client_id(my_ws.id, client_id.to_string(), config.unique_ids) {
+            debug!("Refusing web socket connection, the id {client_id} is already used");
+            return Ok(failures.report("duplicate_id", HttpResponse::Conflict().finish()));
+        }
+    }
+
+    // the token of the dropped connection, its state is restored once the "connect" handler accepted
+    let previous_token = config
+        .reconnect_ttl
+        .and(
+            my_ws
+                .query_params
+                .get(RECONNECT_TOKEN_PARAM)
+                .map(String::as_str)
+                .or_else(|| {
+                    req.headers()
+                        .get(RECONNECT_TOKEN_HEADER)
+                        .and_then(|value| value.to_str().ok())
+                }),
+        )
+        .map(str::to_string);
+    let reconnect_token = config.reconnect_ttl.map(|_| {
+        let token = reconnect::issue();
+        reconnect_tokens().insert(my_ws.id, token.clone());
+        token
+    });
+
+    // without a "connect" handler every connection is accepted
+    let connect = async {
+        match my_ws.handler("connect") {
+            Some(function) => execute_connect_function(function, &my_ws).await,
+            None => Ok(ConnectResult::Accept(None)),
+        }
+    };
+    let result = match handshake_deadline {
+        Some(deadline) => {
+            match tokio::time::timeout_at(deadline, connect).await {
+                Ok(result) => result,
+                Err(_) => {
+                    // the coroutine of an async handler keeps running, only the handshake fails
+                    warn!(
+                        "The handshake of web socket {} didn't finish within {}s",
+                        my_ws.log_context(),
+                        config.handshake_timeout.unwrap_or_default()
+                    );
+                    forget_connection(&my_ws.id);
+                    return Ok(failures
+                        .report("handshake_timeout", HttpResponse::RequestTimeout().finish()));
+                }
+            }
+        }
+        None => connect.await,
+    };
+    let mut connect_headers = Vec::new();
+    let result = result.and_then(|result| match result {
+            // the client would fail the connection on a subprotocol that it didn't offer
+            ConnectResult::AcceptConnection {
+                subprotocol: Some(chosen),
+                ..
+            } if !requested_subprotocols(&req).contains(&chosen.as_str()) =>
+            {
+                Err(PyValueError::new_err(format!(
+                    "The connect handler chose the subprotocol {chosen}, which the client didn't request"
+                )))
+            }
+            result => Ok(result),
+        });
+    match result {
+        Ok(ConnectResult::Accept(output)) => my_ws.connect_output = output,
+        Ok(ConnectResult::AcceptConnection {
+            subprotocol,
+            id,
+            output,
+            background,
+            headers,
+        }) => {
+            if let Some(id) = id {
+                if !register_client_id(my_ws.id, id.clone(), config.unique_ids) {
+                    debug!("Refusing web socket connection, the id {id} is already used");
+                    forget_connection(&my_ws.id);
+                    // the coroutine is closed so that it isn't reported as never awaited
+                    if let Some(coroutine) = background {
+                        Python::with_gil(|py| coroutine.call_method0(py, "close")).ok();
+                    }
+                    return Ok(failures.report("duplicate_id", HttpResponse::Conflict().finish()));
+                }
+            }
+            if subprotocol.is_some() {
+                my_ws.subprotocol = subprotocol;
+            }
+            my_ws.connect_output = output;
+            my_ws.background = background;
+            connect_headers = headers;
+        }
+        Ok(ConnectResult::Reject) => {
+            debug!("Web socket connection {} was rejected", my_ws.id);
+            forget_connection(&my_ws.id);
+            return Ok(failures.report("rejected", HttpResponse::Forbidden().finish()));
+        }
+        Ok(ConnectResult::Close(close_reason)) => {
+            debug!(
+                "Web socket connection {} was closed by the connect handler {:?}",
+                my_ws.id, close_reason
+            );
+            // the close frame is the only frame sent and the "close" handler receives its code
+            my_ws.close_reason = Some(close_reason.clone());
+            my_ws.connect_output = Some(WsMessage::Close(close_reason));
+        }
+        Err(e) => {
+            error!(
+                "Error while executing web socket connect function for {}: {}",
+                my_ws.log_context(),
+                get_traceback(&e)
+            );
+            forget_connection(&my_ws.id);
+            return Ok(failures.report(
+                "connect_error",
+                HttpResponse::InternalServerError().finish(),
+            ));
+        }
+    }
+
+    // a connection that the "connect" handler closed right away doesn't use up the token
+    let closed = matches!(my_ws.connect_output, Some(WsMessage::Close(_)));
+    if let Some(snapshot) = previous_token
+        .filter(|_| !closed)
+        .and_then(|token| reconnect::restore(&token))
+    {
+        debug!(
+            "Web socket {} restored the state of a dropped connection",
+            my_ws.id
+        );
+        my_ws.state = snapshot.state;
+        for room in snapshot.rooms {
+            rooms().entry(room).or_default().insert(my_ws.id);
+        }
+        if !snapshot.tags.is_empty() {
+            connection_tags().insert(my_ws.id, snapshot.tags);
+        }
+    }
+
+    // the clients that don't offer the extension get uncompressed frames
+    if config.compression {
+        my_ws.deflate = negotiate_deflate(&my_ws.extensions).map(|params| {
+            Deflate::new(
+                params,
+                config.compression_threshold,
+                config.compression_dictionary.clone(),
+            )
+        });
+    }
+
+    // only the negotiated subprotocol is handed to actix so that it is the one echoed back
+    let subprotocol = my_ws.subprotocol.clone();
+    let protocols: Vec<&str> = subprotocol.iter().map(String::as_str).collect();
+    // the deflate frames read the socket first, the compressed text reaches the raw text frames
+    // as binary frames
+    let mut frames: BoxedPayloadStream = match &my_ws.deflate {
+        Some(deflate) => Box::pin(InboundFrames::new(stream, deflate.received())),
+        None => Box::pin(stream),
+    };
+    if let Some(kinds) = my_ws.raw_text_frames.clone() {
+        frames = Box::pin(RawTextFrames::new(frames, kinds));
+    }
+    let outbound = my_ws
+        .deflate
+        .as_ref()
+        .map(|deflate| (deflate.params().response_header(), deflate.sent()));
+    let mut response = ws::WsResponseBuilder::new(my_ws, &req, frames)
+        .frame_size(config.max_payload_size)
+        .protocols(&protocols)
+        .start()?;
+    if let Some((extension, sent)) = outbound {
+        let (head, body) = response.into_parts();
+        response = head
+            .set_body(OutboundFrames::new(body, sent))
+            .map_into_boxed_body();
+        // the value is a token followed by params made of tokens
+        response.headers_mut().insert(
+            header::SEC_WEBSOCKET_EXTENSIONS,
+            header::HeaderValue::from_str(&extension).unwrap(),
+        );
+    }
+    for (name, value) in connect_headers {
+        response.headers_mut().append(name, value);
+    }
+    if let Some(token) = reconnect_token {
+        // the token is made of hex digits, so it is always a valid header value
+        response.headers_mut().insert(
+            header::HeaderName::from_static(RECONNECT_TOKEN_HEADER),
+            header::HeaderValue::from_str(&token).unwrap(),
+        );
+    }
+    Ok(response)
+}
+
+/// Closes every web socket connection of this process and returns how many were closed
+pub fn close_all_connections() -> usize {
+    for connection in connections().iter() {
+        connection.addr.do_send(Shutdown);
+    }
+    connections().len()
+}
+
+#[pyfunction]
+/// Whether the web socket connection with the given id is open.
+/// Returns false once the client or the server started closing it
+pub fn is_open(id: &str) -> PyResult<bool> {
+    let id = parse_id(id)?;
+    Ok(connections()
+        .get(&id)
+        .is_some_and(|connection| connection.is_open()))
+}
+
+#[pyfunction]
+#[pyo3(signature = (limit=None, offset=0, details=false))]
+/// The ids of the open web socket connections of this process, the oldest first, to page through
+/// them with the limit and the offset. With details, a dict per connection with its id, its tags
+/// and its rooms instead
+pub fn list_connections(
+    py: Python,
+    limit: Option<usize>,
+    offset: usize,
+    details: bool,
+) -> PyResult<PyObject> {
+    let mut open: Vec<(Instant, Uuid)> = connections()
+        .iter()
+        .filter(|connection| connection.is_open())
+        .map(|connection| (connection.connected_at, *connection.key()))
+        .collect();
+    open.sort();
+    let page: Vec<Uuid> = open
+        .into_iter()
+        .skip(offset)
+        .take(limit.unwrap_or(usize::MAX))
+        .map(|(_, id)| id)
+        .collect();
+    if !details {
+        let ids: Vec<String> = page.iter().map(websocket_id).collect();
+        return Ok(ids.to_object(py));
+    }
+
+    let mut memberships: HashMap<Uuid, Vec<String>> = HashMap::new();
+    for room in rooms().iter() {
+        for id in page.iter().filter(|id| room.value().contains(id)) {
+            memberships.entry(*id).or_default().push(room.key().clone());
+        }
+    }
+    let connections: Vec<Value> = page
+        .iter()
+        .map(|id| {
+            let mut rooms = memberships.remove(id).unwrap_or_default();
+            rooms.sort();
+            json!({
+                "id": websocket_id(id),
+                "tags": connection_tags().get(id).map(|tags| tags.clone()).unwrap_or_default(),
+                "rooms": rooms,
+            })
+        })
+        .collect();
+    pythonize(py, &connections).map_err(|e| PyValueError::new_err(e.to_string()))
+}
+
+#[pyfunction]
+/// The number of web socket connections that are alive in this process,
+/// counting the ones whose upgrade is in progress
+pub fn active_connection_count() -> usize {
+    ACTIVE_CONNECTIONS.load(Ordering::Relaxed)
+}
+
+#[pyfunction]
+/// Sends the text or binary message to every web socket connection of this process.
+/// Returns the number of connections that the message was queued for and dropped for
+pub fn broadcast(py: Python, message: Payload) -> PyObject {
+    // the recipients share the converted message, the GIL isn't needed to queue it
+    let delivery = py.allow_threads(|| {
+        let mut delivery = Delivery::default();
+        for connection in connections().iter() {
+            delivery.record(
+                connection
+                    .value()
+                    .send(connection.key(), CommandRunner(message.clone())),
+            );
+        }
+        delivery
+    });
+    delivery.to_object(py)
+}
+
+#[pyfunction]
+#[pyo3(signature = (message, timeout=30.0, room=None))]
+/// Sends the JSON object to the open web socket connections of the routes with an `ack_field`,
+/// or to the ones in the room, with a random id in that field. Returns the id, that `ack_status`
+/// tells which connections acked within the timeout
+pub fn broadcast_with_ack(
+    py: Python,
+    message: &PyDict,
+    timeout: f64,
+    room: Option<&str>,
+) -> PyResult<String> {
+    let timeout = Duration::try_from_secs_f64(timeout)
+        .map_err(|_| PyValueError::new_err(format!("Invalid ack timeout {timeout}")))?;
+    let Value::Object(fields) = depythonize::<Value>(message)
+        .map_err(|e| PyValueError::new_err(format!("Cannot send the message as JSON - {e}")))?
+    else {
+        unreachable!("a dict is converted to a JSON object");
+    };
+    Ok(py.allow_threads(|| {
+        let recipients: Vec<Uuid> = match room {
+            // the members are copied out so that the room isn't locked while the frames are queued
+            Some(room) => rooms()
+                .get(room)
+                .map(|members| members.iter().copied().collect())
+                .unwrap_or_default(),
+            None => connections()
+                .iter()
+                .map(|connection| *connection.key())
+                .collect(),
+        };
+        let recipients: Vec<(Uuid, String)> = recipients
+            .into_iter()
+            .filter_map(|id| {
+                let connection = connections().get(&id)?;
+                let ack_field = connection.ack_field.clone()?;
+                connection.is_open().then_some((id, ack_field))
+            })
+            .collect();
+        let ack_id = acks::track(
+            recipients
+                .iter()
+                .map(|(id, _)| (*id, websocket_id(id)))
+                .collect(),
+            timeout,
+        );
+        for (id, ack_field) in recipients {
+            let mut fields = fields.clone();
+            fields.insert(ack_field, Value::String(ack_id.clone()));
+            let frame = Payload::Text(Value::Object(fields).to_string().into());
+            let queued = connections()
+                .get(&id)
+                .is_some_and(|connection| connection.send(&id, CommandRunner(frame)));
+            if !queued {
+                acks::forget(&ack_id, &id);
+            }
+        }
+        ack_id
+    }))
+}
+
+#[pyfunction]
+/// The ids of the connections that acked the message sent with `broadcast_with_ack`, and of the
+/// ones that didn't yet. None for an unknown id, or once it expired a minute ago
+pub fn ack_status(py: Python, ack_id: &str) -> PyResult<Option<PyObject>> {
+    let Some(status) = acks::status(ack_id) else {
+        return Ok(None);
+    };
+    let dict = PyDict::new(py);
+    dict.set_item("acked", status.acked)?;
+    dict.set_item("pending", status.pending)?;
+    dict.set_item("expired", status.expired)?;
+    Ok(Some(dict.into()))
+}
+
+/// Finds the connection from the id given by the application or from its UUID
+fn parse_id(id: &str) -> PyResult<Uuid> {
+    if let Some(uuid) = client_ids().get(id) {
+        return Ok(*uuid);
+    }
+    Uuid::parse_str(id)
+        .map_err(|e| PyValueError::new_err(format!("Invalid web socket id {id} - {e}")))
+}
+
+/// The open connection with the given id
+fn get_open_connection(id: &str) -> PyResult<dashmap::mapref::one::Ref<'static, Uuid, Connection>> {
+    let id = parse_id(id)?;
+    let connection = connections()
+        .get(&id)
+        .ok_or_else(|| PyValueError::new_err(format!("No web socket connection with id {id}")))?;
+    if !connection.addr.connected() {
+        return Err(PyValueError::new_err(format!(
+            "Web socket connection {id} is closed"
+        )));
+    }
+    Ok(connection)
+}
+
+#[pyfunction]
+/// Sends the text or binary message to the web socket connection with the given id.
+/// Returns false when the backpressure policy of the connection dropped it
+pub fn send_to(id: &str, message: Payload) -> PyResult<bool> {
+    let connection = get_open_connection(id)?;
+    Ok(connection.send(connection.key(), CommandRunner(message)))
+}
+
+#[pyfunction]
+/// A handle on the web socket connection with the given id, to call its methods
+/// instead of passing the id to `send_to`, `kick`, ... Returns None when it isn't open
+pub fn get_connection(id: &str) -> PyResult<Option<WebSocketConnection>> {
+    let id = parse_id(id)?;
+    if let Some(state) = upgrades().get(&id) {
+        return Ok(Some(WebSocketConnection {
+            id,
+            state: state.clone(),
+        }));
+    }
+    Ok(connections()
+        .get(&id)
+        .filter(|connection| connection.is_open())
+        .map(|connection| WebSocketConnection {
+            id,
+            state: Arc::new(Mutex::new(UpgradeState::Started(connection.clone()))),
+        }))
+}
+
+/// A handle on a single web socket connection, it holds the address of its actor.
+/// Once the connection is closed, its methods return False instead of raising
+#[pyclass]
+pub struct WebSocketConnection {
+    id: Uuid,
+    state: Arc<Mutex<UpgradeState>>,
+}
+
+impl WebSocketConnection {
+    /// Runs the command, or holds it while the "connect" handler of the connection runs
+    fn run(&self, command: HeldCommand) -> bool {
+        let connection = match &mut *self.state.lock().unwrap() {
+            UpgradeState::Running(held) => {
+                held.push(command);
+                return true;
+            }
+            UpgradeState::Started(connection) => connection.clone(),
+            UpgradeState::Failed => return false,
+        };
+        connection.run(&self.id, command)
+    }
+}
+
+#[pymethods]
+impl WebSocketConnection {
+    #[getter]
+    fn id(&self) -> String {
+        websocket_id(&self.id)
+    }
+
+    /// Sends the text or binary message to the client.
+    /// Returns false when it was dropped, as the connection is closing or the client is too slow
+    fn send(&self, message: Payload) -> bool {
+        self.run(HeldCommand::Send(message))
+    }
+
+    #[pyo3(signature = (payload=None))]
+    /// Pings the client, the pong is passed to the "pong" handler
+    fn ping(&self, payload: Option<&[u8]>) -> PyResult<bool> {
+        let payload = control_payload("ping", payload)?;
+        Ok(self.run(HeldCommand::Ping(payload)))
+    }
+
+    #[pyo3(signature = (code=1000, reason=None))]
+    /// Closes the connection like `kick`
+    fn close(&self, code: u16, reason: Option<String>) -> PyResult<bool> {
+        Ok(self.run(HeldCommand::Close(CloseReason {
+            code: validate_close_code(code)?.into(),
+            description: validate_close_reason(reason)?,
+        })))
+    }
+
+    /// Whether the connection is still open, like `is_open`.
+    /// It is while the "connect" handler runs
+    fn is_alive(&self) -> bool {
+        match &*self.state.lock().unwrap() {
+            UpgradeState::Running(_) => true,
+            UpgradeState::Started(connection) => connection.is_open(),
+            UpgradeState::Failed => false,
+        }
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "WebSocketConnection(id={:?}, alive={})",
+            self.id(),
+            self.is_alive()
+        )
+    }
+}
+
+#[pyfunction]
+#[pyo3(signature = (id, code=1000, reason=None))]
+/// Closes the web socket connection with the given id, e.g. to disconnect a banned client.
+/// Returns false when there is no open connection with this id
+pub fn kick(id: &str, code: u16, reason: Option<String>) -> PyResult<bool> {
+    let code = validate_close_code(code)?;
+    let reason = validate_close_reason(reason)?;
+    let id = parse_id(id)?;
+    let Some(connection) = connections().get(&id) else {
+        return Ok(false);
+    };
+    Ok(connection.kick(CloseReason {
+        code: code.into(),
+        description: reason,
+    }))
+}
+
+#[pyfunction]
+/// Stops passing the messages of the web socket connection with the given id to its handlers,
+/// e.g. while a long operation runs for it. They are held until the connection is resumed.
+/// Returns false when there is no open connection with this id
+pub fn pause(id: &str) -> PyResult<bool> {
+    let id = parse_id(id)?;
+    Ok(connections()
+        .get(&id)
+        .is_some_and(|connection| connection.pause()))
+}
+
+#[pyfunction]
+/// Passes the messages held while the web socket connection with the given id was paused
+/// to its handlers, in order, and the next ones as they come.
+/// Returns false when there is no open connection with this id
+pub fn resume(id: &str) -> PyResult<bool> {
+    let id = parse_id(id)?;
+    Ok(connections()
+        .get(&id)
+        .is_some_and(|connection| connection.resume()))
+}
+
+#[pyfunction]
+#[pyo3(signature = (id, verbose=true))]
+/// Logs the handlers called for the web socket connection with the given id and the frames
+/// sent to it, with the info level, or stops logging them
+pub fn set_verbose(id: &str, verbose: bool) -> PyResult<()> {
+    let connection = get_open_connection(id)?;
+    if verbose {
+        verbose_connections().insert(*connection.key());
+    } else {
+        verbose_connections().remove(connection.key());
+    }
+    Ok(())
+}
+
+#[pyfunction]
+/// The token that the client of the web socket connection with the given id can reconnect with,
+/// also sent in the `X-Reconnect-Token` header of the handshake response.
+/// None when its route has no `reconnect_ttl`
+pub fn reconnect_token(id: &str) -> PyResult<Option<String>> {
+    let id = parse_id(id)?;
+    Ok(reconnect_tokens().get(&id).map(|token| token.clone()))
+}
+
+#[pyfunction]
+/// The number of frames sent with `broadcast`, `send_to`, ... that are waiting to be sent
+/// to the web socket connection with the given id
+pub fn queue_depth(id: &str) -> PyResult<usize> {
+    Ok(get_open_connection(id)?.queue.len())
+}
+
+#[pyfunction]
+#[pyo3(signature = (id, payload=None))]
+/// Sends a ping to the web socket connection with the given id,
+/// the client answers with a pong that carries the same payload
+pub fn send_ping(id: &str, payload: Option<&[u8]>) -> PyResult<()> {
+    let payload = control_payload("ping", payload)?;
+    get_open_connection(id)?.addr.do_send(SendPing(payload));
+    Ok(())
+}
+
+/// The payload of a ping or a pong, a control frame carries at most 125 bytes
+fn control_payload(frame: &str, payload: Option<&[u8]>) -> PyResult<Bytes> {
+    let payload = payload.unwrap_or_default();
+    if payload.len() > 125 {
+        return Err(PyValueError::new_err(format!(
+            "The payload of a {frame} can't be longer than 125 bytes"
+        )));
+    }
+    Ok(Bytes::copy_from_slice(payload))
+}
+
+#[pyfunction]
+#[pyo3(signature = (id, payload=None))]
+/// Sends a pong to the web socket connection with the given id,
+/// for the routes created with `auto_pong=False`
+pub fn send_pong(id: &str, payload: Option<&[u8]>) -> PyResult<()> {
+    let payload = control_payload("pong", payload)?;
+    get_open_connection(id)?.addr.do_send(SendPong(payload));
+    Ok(())
+}
+
+#[pyfunction]
+/// Adds the web socket connection with the given id to the room
+/// this can be called from the "connect" handler, before the connection is registered
+pub fn join_room(id: &str, room: &str) -> PyResult<()> {
+    let id = parse_id(id)?;
+    rooms().entry(room.to_string()).or_default().insert(id);
+    Ok(())
+}
+
+#[pyfunction]
+/// Removes the web socket connection with the given id from the room
+pub fn leave_room(id: &str, room: &str) -> PyResult<()> {
+    let id = parse_id(id)?;
+    rooms().remove_if_mut(room, |_, members| {
+        members.remove(&id);
+        members.is_empty()
+    });
+    Ok(())
+}
+
+#[pyfunction]
+/// The number of web socket connections in the room, 0 for a room that doesn't exist
+pub fn room_member_count(room: &str) -> usize {
+    rooms().get(room).map_or(0, |members| members.len())
+}
+
+#[pyfunction]
+/// Sends the text or binary message to every web socket connection in the room.
+/// Returns the number of members that the message was queued for and dropped for
+pub fn broadcast_to_room(py: Python, room: &str, message: Payload) -> PyObject {
+    send_to_room(py, room, None, message)
+}
+
+#[pyfunction]
+/// Sends the text or binary message to every web socket connection in the room but one,
+/// e.g. to relay the message of a member to the others without echoing it back.
+/// Returns the number of members that the message was queued for and dropped for
+pub fn broadcast_to_room_except(
+    py: Python,
+    room: &str,
+    except_id: &str,
+    message: Payload,
+) -> PyResult<PyObject> {
+    let except_id = parse_id(except_id)?;
+    Ok(send_to_room(py, room, Some(except_id), message))
+}
+
+#[pyfunction]
+/// Sends the text or binary message to a random sample of the web socket connections in the room,
+/// e.g. to roll out a new message format gradually. A float from 0 to 1 is the fraction of the
+/// members to pick, rounded to the nearest member, an int is the number of members to pick.
+/// Returns the number of members that the message was queued for and dropped for
+pub fn broadcast_sample(
+    py: Python,
+    room: &str,
+    fraction_or_count: &PyAny,
+    message: Payload,
+) -> PyResult<PyObject> {
+    let sample = if fraction_or_count.is_instance_of::<PyFloat>() {
+        let fraction: f64 = fraction_or_count.extract()?;
+        if !(0.0..=1.0).contains(&fraction) {
+            return Err(PyValueError::new_err(format!(
+                "The fraction of the room to send to must be from 0 to 1, got {fraction}"
+            )));
+        }
+        Sample::Fraction(fraction)
+    } else if fraction_or_count.is_instance_of::<PyBool>() {
+        return Err(PyTypeError::new_err(
+            "The sample of the room must be a fraction or a number of connections",
+        ));
+    } else {
+        let count: i64 = fraction_or_count.extract()?;
+        let count = usize::try_from(count).map_err(|_| {
+            PyValueError::new_err(format!(
+                "The number of connections to send to can't be negative, got {count}"
+            ))
+        })?;
+        Sample::Count(count)
+    };
+    let delivery = py.allow_threads(|| {
+        // the members are copied out so that the room isn't locked while the frames are queued
+        let members: Vec<Uuid> = match rooms().get(room) {
+            Some(members) => members.iter().copied().collect(),
+            None => Vec::new(),
+        };
+        let amount = match sample {
+            Sample::Fraction(fraction) => (members.len() as f64 * fraction).round() as usize,
+            Sample::Count(count) => count,
+        };
+        let mut delivery = Delivery::default();
+        for id in members.choose_multiple(&mut rand::thread_rng(), amount) {
+            if let Some(connection) = connections().get(id) {
+                delivery.record(connection.send(id, CommandRunner(message.clone())));
+            }
+        }
+        delivery
+    });
+    Ok(delivery.to_object(py))
+}
+
+/// How many members of a room `broadcast_sample` picks
+#[derive(Clone, Copy)]
+enum Sample {
+    Fraction(f64),
+    Count(usize),
+}
+
+fn send_to_room(py: Python, room: &str, except_id: Option<Uuid>, message: Payload) -> PyObject {
+    let delivery = py.allow_threads(|| {
+        let mut delivery = Delivery::default();
+        if let Some(members) = rooms().get(room) {
+            for id in members.iter().filter(|id| Some(**id) != except_id) {
+                if let Some(connection) = connections().get(id) {
+                    delivery.record(connection.send(id, CommandRunner(message.clone())));
+                }
+            }
+        }
+        delivery
+    });
+    delivery.to_object(py)
+}
+
+#[pyfunction]
+/// Attaches the label to the web socket connection with the given id, replacing its value if any.
+/// This can be called from the "connect" handler, before the connection is registered
+pub fn set_tag(id: &str, label: &str, value: &str) -> PyResult<()> {
+    let id = parse_id(id)?;
+    connection_tags()
+        .entry(id)
+        .or_default()
+        .insert(label.to_string(), value.to_string());
+    Ok(())
+}
+
+#[pyfunction]
+/// Removes the label from the web socket connection with the given id
+pub fn remove_tag(id: &str, label: &str) -> PyResult<()> {
+    let id = parse_id(id)?;
+    connection_tags().remove_if_mut(&id, |_, tags| {
+        tags.remove(label);
+        tags.is_empty()
+    });
+    Ok(())
+}
+
+#[pyfunction]
+/// The labels attached to the web socket connection with the given id
+pub fn get_tags(id: &str) -> PyResult<HashMap<String, String>> {
+    let id = parse_id(id)?;
+    Ok(connection_tags()
+        .get(&id)
+        .map(|tags| tags.clone())
+        .unwrap_or_default())
+}
+
+#[pyfunction]
+/// Sends the text or binary message to every web socket connection whose label has the value.
+/// Returns the number of connections that the message was queued for and dropped for
+pub fn broadcast_where(py: Python, label: &str, value: &str, message: Payload) -> PyObject {
+    let delivery = py.allow_threads(|| {
+        let mut delivery = Delivery::default();
+        for tags in connection_tags().iter() {
+            if tags.get(label).map(String::as_str) != Some(value) {
+                continue;
+            }
+            if let Some(connection) = connections().get(tags.key()) {
+                delivery.record(connection.send(tags.key(), CommandRunner(message.clone())));
+            }
+        }
+        delivery
+    });
+    delivery.to_object(py)
+}
+
+#[pyfunction]
+#[pyo3(signature = (id=None))]
+/// The number of text and binary frames and bytes received and sent by the connection with the
+/// given id, or by every connection of this process when no id is given
+pub fn connection_metrics(py: Python, id: Option<&str>) -> PyResult<PyObject> {
+    let metrics = match id {
+        Some(id) => {
+            let id = parse_id(id)?;
+            let connection = connections().get(&id).ok_or_else(|| {
+                PyValueError::new_err(format!("No web socket connection with id {id}"))
+            })?;
+            let mut metrics = connection.metrics.to_json();
+            metrics["uptime"] = json!(connection.connected_at.elapsed().as_secs_f64());
+            metrics
+        }
+        None => GLOBAL_METRICS.to_json(),
+    };
+    pythonize(py, &metrics).map_err(|e| PyValueError::new_err(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(build: impl FnOnce(Python) -> PyObject) -> PyResult<ConnectResult> {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| connect_result(build(py).as_ref(py), None))
+    }
+
+    #[test]
+    fn booleans_accept_or_reject() {
+        assert!(matches!(
+            parse(|py| true.to_object(py)),
+            Ok(ConnectResult::Accept(None))
+        ));
+        assert!(matches!(
+            parse(|py| false.to_object(py)),
+            Ok(ConnectResult::Reject)
+        ));
+        assert!(matches!(
+            parse(|py| py.None()),
+            Ok(ConnectResult::Accept(None))
+        ));
+    }
+
+    #[test]
+    fn replies_are_sent_as_the_first_frame() {
+        assert!(matches!(
+            parse(|py| "welcome".to_object(py)),
+            Ok(ConnectResult::Accept(Some(WsMessage::Text(text)))) if text == "welcome"
+        ));
+        let reply = parse(|py| {
+            let dict = PyDict::new(py);
+            dict.set_item("event", "welcome").unwrap();
+            dict.into()
+        });
+        assert!(matches!(
+            reply,
+            Ok(ConnectResult::Accept(Some(WsMessage::Json(value)))) if value == json!({"event": "welcome"})
+        ));
+    }
+
+    #[test]
+    fn close_connection_closes_after_the_upgrade() {
+        let close = parse(|py| {
+            Py::new(
+                py,
+                CloseConnection::new(4001, Some("banned".to_string())).unwrap(),
+            )
+            .unwrap()
+            .into_py(py)
+        });
+        let Ok(ConnectResult::Close(reason)) = close else {
+            panic!("expected a close");
+        };
+        assert_eq!(u16::from(reason.code), 4001);
+        assert_eq!(reason.description.as_deref(), Some("banned"));
+    }
+
+    #[test]
+    fn accept_connection_keeps_its_settings() {
+        let accept = parse(|py| {
+            let headers = HashMap::from([("x-session".to_string(), "abc".to_string())]);
+            let accept = AcceptConnection::new(
+                Some("chat".to_string()),
+                Some("hello".to_object(py)),
+                Some("user-1".to_string()),
+                None,
+                Some(headers),
+            );
+            Py::new(py, accept).unwrap().into_py(py)
+        });
+        let Ok(ConnectResult::AcceptConnection {
+            subprotocol,
+            id,
+            output,
+            background,
+            headers,
+        }) = accept
+        else {
+            panic!("expected an AcceptConnection");
+        };
+        assert_eq!(subprotocol.as_deref(), Some("chat"));
+        assert_eq!(id.as_deref(), Some("user-1"));
+        assert!(matches!(output, Some(WsMessage::Text(text)) if text == "hello"));
+        assert!(background.is_none());
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers[0].0, "x-session");
+        assert_eq!(headers[0].1, "abc");
+    }
+
+    #[test]
+    fn accept_connection_cant_replace_the_upgrade_headers() {
+        let accept = parse(|py| {
+            let headers = HashMap::from([("Upgrade".to_string(), "h2c".to_string())]);
+            let accept = AcceptConnection::new(None, None, None, None, Some(headers));
+            Py::new(py, accept).unwrap().into_py(py)
+        });
+        assert!(accept.is_err());
+    }
 }